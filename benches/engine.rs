@@ -0,0 +1,71 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::{json, Value};
+use rjx::parser::parse_query;
+use rjx::query::QueryEngine;
+
+// Engine-only benchmarks: queries are parsed and inputs built up front, so only
+// QueryEngine::execute is timed (no process spawning, JSON parsing or output formatting)
+
+// Input sizes, as the number of items in the generated document
+const SIZES: &[(&str, usize)] = &[
+    ("small", 10),
+    ("medium", 1_000),
+    ("large", 50_000),
+];
+
+// Benchmark queries
+const QUERIES: &[(&str, &str)] = &[
+    ("identity", "."),
+    ("deep_property", ".config.database.primary.connection.host"),
+    ("array_iteration", ".items[] | .id"),
+    ("select", ".items[] | select(.metadata.rating > 3) | .name"),
+    // Iteration hands out the elements one at a time, so this should cost about as much as the plain index
+    ("first_iteration", "first(.items[])"),
+    ("first_index", ".items[0]"),
+];
+
+// Generate a document with the given number of items and a deeply nested config object
+fn generate_input(items: usize) -> Value {
+    let items: Vec<Value> = (0..items)
+        .map(|i| json!({
+            "id": i,
+            "name": format!("Item {}", i),
+            "tags": ["tag1", "tag2", "tag3"],
+            "metadata": {
+                "status": if i % 2 == 0 { "active" } else { "inactive" },
+                "rating": i % 5,
+            },
+        }))
+        .collect();
+
+    json!({
+        "config": {"database": {"primary": {"connection": {"host": "localhost", "port": 5432}}}},
+        "items": items,
+    })
+}
+
+fn benchmark_engine(c: &mut Criterion) {
+    let engine = QueryEngine::new();
+
+    for (size_name, size) in SIZES {
+        let input = generate_input(*size);
+
+        let mut group = c.benchmark_group(format!("engine_{}", size_name));
+        group.throughput(Throughput::Elements(*size as u64));
+        if *size >= 50_000 {
+            group.sample_size(20); // Fewer samples for large inputs to keep runtime reasonable
+        }
+
+        for (name, query) in QUERIES {
+            let expr = parse_query(query).unwrap();
+            group.bench_with_input(BenchmarkId::from_parameter(name), &expr, |b, expr| {
+                b.iter(|| engine.execute(black_box(expr), black_box(&input)).unwrap());
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, benchmark_engine);
+criterion_main!(benches);
@@ -2,3 +2,181 @@
 pub mod parser;
 pub mod query;
 pub mod output;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub use query::{QueryEngine, QueryError, QueryResult};
+pub use serde_json::Value;
+
+use output::{OutputError, OutputFormatter, OutputOptions};
+
+/// Errors from a full run: reading the input, parsing and running the query, or formatting the results
+#[derive(Debug, thiserror::Error)]
+pub enum RunError {
+    #[error(transparent)]
+    Query(#[from] QueryError),
+    
+    #[error(transparent)]
+    Output(#[from] OutputError),
+}
+
+/// Time spent in each stage of a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    pub json_parse: Duration,
+    pub query_parse: Duration,
+    pub execute: Duration,
+    pub output: Duration,
+}
+
+impl Timings {
+    /// The time spent across all four stages
+    pub fn total(&self) -> Duration {
+        self.json_parse + self.query_parse + self.execute + self.output
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Benchmark:")?;
+        writeln!(f, "  JSON parse time:   {:?}", self.json_parse)?;
+        writeln!(f, "  Query parse time:  {:?}", self.query_parse)?;
+        writeln!(f, "  Execution time:    {:?}", self.execute)?;
+        writeln!(f, "  Formatting time:   {:?}", self.output)?;
+        write!(f, "  Total time:        {:?}", self.total())
+    }
+}
+
+/// The results of a run, their formatted output and the time each stage took
+#[derive(Debug, Clone)]
+pub struct TimedRun {
+    pub results: Vec<Value>,
+    pub output: String,
+    pub timings: Timings,
+}
+
+/// Run `start` and add the time it took to `stage`
+pub fn time_stage<T>(stage: &mut Duration, start: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = start();
+    *stage += started.elapsed();
+    result
+}
+
+/// Parse a query and run it against a JSON value in one call
+///
+/// ```
+/// use serde_json::json;
+///
+/// let results = rjx::query(".users[] | .name", &json!({"users": [{"name": "Ada"}, {"name": "Grace"}]})).unwrap();
+/// assert_eq!(results, vec![json!("Ada"), json!("Grace")]);
+/// ```
+pub fn query(expr: &str, input: &Value) -> QueryResult {
+    let expr = parser::parse_query(expr)?;
+    QueryEngine::new().execute(&expr, input)
+}
+
+/// Parse a query and run it against a JSON document given as text
+///
+/// ```
+/// use serde_json::json;
+///
+/// let results = rjx::query_str(".a", r#"{"a": [1, 2]}"#).unwrap();
+/// assert_eq!(results, vec![json!([1, 2])]);
+/// ```
+pub fn query_str(expr: &str, json: &str) -> QueryResult {
+    let input: Value = serde_json::from_str(json)?;
+    query(expr, &input)
+}
+
+/// Run a query over a stream of JSON documents like the command line does, timing each stage
+///
+/// The output holds the formatted results one per line.
+///
+/// ```
+/// use rjx::output::OutputOptions;
+///
+/// let run = rjx::run_timed(".a", r#"{"a": 1} {"a": 2}"#, OutputOptions { compact: true, ..Default::default() }).unwrap();
+/// assert_eq!(run.output, "1\n2");
+/// assert_eq!(run.timings.total(), run.timings.json_parse + run.timings.query_parse + run.timings.execute + run.timings.output);
+/// ```
+pub fn run_timed(expr: &str, json: &str, options: OutputOptions) -> Result<TimedRun, RunError> {
+    let mut timings = Timings::default();
+    
+    let documents = time_stage(&mut timings.json_parse, || {
+        serde_json::Deserializer::from_str(json).into_iter().collect::<Result<Vec<Value>, _>>()
+    }).map_err(QueryError::from)?;
+    let expr = time_stage(&mut timings.query_parse, || parser::parse_query(expr)).map_err(QueryError::from)?;
+    
+    // Documents are queued as inputs, so `input` and `inputs` read the ones after the current document
+    let mut engine = QueryEngine::new();
+    engine.set_inputs(documents);
+    let results = time_stage(&mut timings.execute, || -> QueryResult {
+        let mut results = Vec::new();
+        while let Some(document) = engine.next_input() {
+            results.extend(engine.execute(&expr, &document?)?);
+        }
+        Ok(results)
+    })?;
+    
+    let formatter = OutputFormatter::new(options);
+    let output = time_stage(&mut timings.output, || formatter.format_multiple(&results))?;
+    
+    Ok(TimedRun { results, output, timings })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_query() {
+        let result = query(".a | keys", &json!({"a": {"x": 1, "y": 2}})).unwrap();
+        assert_eq!(result, vec![json!(["x", "y"])]);
+
+        assert!(matches!(query(".a |", &json!({})), Err(QueryError::Parse(_))));
+    }
+
+    #[test]
+    fn test_query_str() {
+        let result = query_str(".[1]", "[10, 20, 30]").unwrap();
+        assert_eq!(result, vec![json!(20)]);
+
+        assert!(matches!(query_str(".", "{not json"), Err(QueryError::Json(_))));
+    }
+
+    #[test]
+    fn test_lowercase_top_level_keys() {
+        // with_entries, |= and ascii_downcase compose to normalize keys, leaving nested objects as they are
+        let json = r#"{"Name": "Ada", "EMAIL": "ada@example.com", "Meta": {"Tags": ["X"]}}"#;
+        let result = query_str("with_entries(.key |= ascii_downcase)", json).unwrap();
+        assert_eq!(result, vec![json!({"name": "Ada", "email": "ada@example.com", "meta": {"Tags": ["X"]}})]);
+    }
+
+    #[test]
+    fn test_run_timed_matches_query_str() {
+        let json = r#"{"items": [{"id": 1}, {"id": 2}, {"id": 3}]}"#;
+        let run = run_timed(".items[] | .id * 2", json, OutputOptions::default()).unwrap();
+        
+        assert_eq!(run.results, query_str(".items[] | .id * 2", json).unwrap());
+        assert_eq!(run.output, "2\n4\n6");
+        
+        // Every stage ran, so each took some time
+        let timings = run.timings;
+        for stage in [timings.json_parse, timings.query_parse, timings.execute, timings.output] {
+            assert!(stage > Duration::ZERO);
+        }
+        assert_eq!(timings.total(), timings.json_parse + timings.query_parse + timings.execute + timings.output);
+    }
+
+    #[test]
+    fn test_run_timed_errors() {
+        assert!(matches!(run_timed(".", "{not json", OutputOptions::default()), Err(RunError::Query(QueryError::Json(_)))));
+        assert!(matches!(run_timed(".a |", "{}", OutputOptions::default()), Err(RunError::Query(QueryError::Parse(_)))));
+
+        let strict = OutputOptions { raw: true, strict_raw: true, ..Default::default() };
+        assert!(matches!(run_timed(".", "1", strict), Err(RunError::Output(OutputError::NotAString(_)))));
+    }
+}
@@ -1,22 +1,20 @@
-mod parser;
-mod query;
-mod output;
-
-use anyhow::{Result, Context};
-use clap::Parser;
+use anyhow::{anyhow, Result, Context};
+use clap::{ArgAction, Parser};
 use std::fs::File;
-use std::io::{self, BufReader, Read};
-use std::path::PathBuf;
-use std::time::Instant;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use parser::parse_query;
-use query::QueryEngine;
-use output::{OutputFormatter, OutputOptions};
-use serde_json::Value;
+use rjx::parser::{parse_query, Expression};
+use rjx::query::{QueryEngine, QueryError};
+use rjx::output::{format_parse_error, KeyOrder, OutputFormatter, OutputOptions};
+use rjx::{time_stage, Timings};
+use serde_json::{Map, Value};
 
 /// RJQ - A fast and lightweight JSON processor in Rust (jq alternative)
-#[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[derive(Parser, Debug, Clone)]
+#[clap(author, version, about, after_help = CONFIG_HELP)]
 struct Cli {
     /// The query to run on the JSON input
     #[clap(short, long, value_parser)]
@@ -26,6 +24,10 @@ struct Cli {
     #[clap(value_parser)]
     input: Option<PathBuf>,
 
+    /// Positional arguments for $ARGS.positional, when --args or --jsonargs is given
+    #[clap(value_name = "ARGS")]
+    positional: Vec<String>,
+
     /// Pretty print the output
     #[clap(short, long, action)]
     pretty: bool,
@@ -34,14 +36,88 @@ struct Cli {
     #[clap(short, long, action)]
     compact: bool,
 
+    /// Indent output by N spaces (0 means compact, single-line output)
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=7))]
+    indent: Option<u8>,
+
+    /// Indent output by STRING, such as three spaces or a tab (it may only contain whitespace)
+    #[clap(long, value_name = "STRING", value_parser = parse_indent_string, conflicts_with = "indent")]
+    indent_string: Option<String>,
+    
+    /// Pretty print the output, indenting with a tab per level
+    #[clap(long, action, conflicts_with_all = ["indent", "indent_string", "compact"])]
+    tab: bool,
+
+    /// Round floating point numbers in the output to N significant digits
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=17))]
+    precision: Option<u8>,
+
+    /// Sort the keys of every object in the output
+    #[clap(short = 'S', long, action)]
+    sort_keys: bool,
+
+    /// Print all results together as one JSON array instead of one value per line
+    #[clap(long, action)]
+    collect: bool,
+
+    /// Newline-delimited JSON output: one compact JSON value per line, overriding the other layout
+    /// and raw/color options so the output can be read back in
+    #[clap(long, action)]
+    ndjson_out: bool,
+
+    /// Read and write RFC 7464 JSON text sequences: records are separated by the ASCII record separator
+    /// (0x1E), malformed input records are reported and skipped, and each output value starts with one
+    #[clap(long, action)]
+    seq: bool,
+
+    /// Do not end the output with a newline (results are still separated by newlines)
+    #[clap(long, action)]
+    no_newline: bool,
+    
+    /// Separate results with STRING instead of a newline, such as ", " (the output still ends with a newline)
+    #[clap(long, value_name = "STRING", default_value = "\n", hide_default_value = true, conflicts_with_all = ["seq", "ndjson_out"])]
+    output_separator: String,
+
     /// Raw output (unwrap strings)
-    #[clap(short, long, action)]
+    #[clap(short, long, visible_alias = "raw-output", action)]
     raw: bool,
+    
+    /// With --raw, fail if a result is not a string instead of printing it as JSON
+    #[clap(long, action, requires = "raw")]
+    strict: bool,
 
     /// Colorize JSON output
     #[clap(short = 'C', long, action)]
     color: bool,
     
+    /// Never colorize the output, even with --color
+    #[clap(short = 'M', long = "monochrome-output", action)]
+    monochrome: bool,
+    
+    /// Read all input documents into a single array and run the query once on it
+    /// (with --raw-input, read the whole input as one string)
+    #[clap(short, long, action)]
+    slurp: bool,
+    
+    /// Read each line of the input as a string instead of parsing it as JSON
+    #[clap(short = 'R', long, action)]
+    raw_input: bool,
+    
+    /// Run the query once with null as its input; the inputs are then only read by `input` and `inputs`
+    #[clap(short, long, action)]
+    null_input: bool,
+    
+    /// Parse input documents only as the query reads them, rather than all before it runs. With --null-input,
+    /// `reduce inputs as $x (...)` then folds a large stream holding one document at a time, where --slurp
+    /// would hold them all in one array.
+    #[clap(long, action, conflicts_with_all = ["slurp", "raw_input", "seq", "lenient"])]
+    lazy_input: bool,
+    
+    /// Read the input as one document per line, reporting and skipping malformed lines
+    /// (by default a malformed document aborts the run)
+    #[clap(long, visible_alias = "skip-errors", action)]
+    lenient: bool,
+    
     /// Benchmark mode - show execution time
     #[clap(short, long, action)]
     benchmark: bool,
@@ -49,22 +125,336 @@ struct Cli {
     /// Debug mode (show detailed error information)
     #[clap(long, action)]
     debug: bool,
+    
+    /// Print the parsed query as a JSON syntax tree on stderr
+    #[clap(long, action)]
+    query_debug: bool,
+    
+    /// Fail instead of descending more than N levels into nested input
+    #[clap(long, value_name = "N")]
+    max_depth: Option<usize>,
+    
+    /// Bind $NAME to the string VALUE
+    #[clap(long, value_names = ["NAME", "VALUE"], num_args = 2, action = ArgAction::Append)]
+    arg: Vec<String>,
+    
+    /// Bind $NAME to the JSON value TEXT
+    #[clap(long, value_names = ["NAME", "TEXT"], num_args = 2, action = ArgAction::Append)]
+    argjson: Vec<String>,
+    
+    /// Treat all positional arguments as strings in $ARGS.positional (input is read from stdin)
+    #[clap(long, action, conflicts_with = "jsonargs")]
+    args: bool,
+    
+    /// Treat all positional arguments as JSON values in $ARGS.positional (input is read from stdin)
+    #[clap(long, action)]
+    jsonargs: bool,
+    
+    /// Bind $NAME to an array of all JSON values in FILE
+    #[clap(long, value_names = ["NAME", "FILE"], num_args = 2, action = ArgAction::Append)]
+    slurpfile: Vec<String>,
+    
+    /// Bind $NAME to the contents of FILE as a string
+    #[clap(long, value_names = ["NAME", "FILE"], num_args = 2, action = ArgAction::Append)]
+    rawfile: Vec<String>,
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// Read a file into a string
+fn read_file(path: &Path) -> Result<String> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents)
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(contents)
+}
+
+/// Output defaults from a `.rjxrc` file, which command line flags override
+#[derive(Debug, Default, PartialEq)]
+struct Config {
+    indent: Option<u8>,
+    color: Option<bool>,
+    sort_keys: Option<bool>,
+}
+
+/// The name of the config file looked for in the current directory and then in $HOME
+const CONFIG_FILE: &str = ".rjxrc";
+
+/// Description of the config file format, shown after the options in --help
+const CONFIG_HELP: &str = "Output defaults are read from .rjxrc in the current directory or $HOME: one `key = value` \
+setting per line for indent (0-7), color and sort-keys (true or false), with # comments. This is a small subset of \
+TOML; tables, quoted strings and any other keys are rejected.";
+
+/// Load the first `.rjxrc` found in the current directory or $HOME (no config file means no defaults)
+fn load_config() -> Result<Config> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let candidates = [Some(PathBuf::from(CONFIG_FILE)), home.map(|home| home.join(CONFIG_FILE))];
+    
+    match candidates.into_iter().flatten().find(|path| path.is_file()) {
+        Some(path) => read_config(&path),
+        None => Ok(Config::default()),
+    }
+}
+
+/// Read a config file: `key = value` lines setting `indent` (0-7), `color` and `sort-keys`, with `#` comments.
+/// It only has numbers and booleans, so this is a subset of TOML without tables, strings or arrays.
+fn read_config(path: &Path) -> Result<Config> {
+    let mut config = Config::default();
     
-    // Read input from file or stdin
-    let json_input = match cli.input {
+    for (number, line) in read_file(path)?.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        
+        let context = || format!("{}:{}: invalid setting '{}'", path.display(), number + 1, line);
+        let (key, value) = line.split_once('=').with_context(context)?;
+        match (key.trim(), value.trim()) {
+            ("indent", value) => {
+                let indent = value.parse::<u8>().ok().filter(|indent| *indent <= 7).with_context(context)?;
+                config.indent = Some(indent);
+            },
+            ("color", value) => config.color = Some(value.parse().ok().with_context(context)?),
+            ("sort-keys", value) => config.sort_keys = Some(value.parse().ok().with_context(context)?),
+            _ => return Err(anyhow::anyhow!(context())),
+        }
+    }
+    
+    Ok(config)
+}
+
+/// Check that an --indent-string is JSON whitespace, so that the output stays valid JSON
+fn parse_indent_string(indent: &str) -> std::result::Result<String, String> {
+    if indent.chars().all(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+        Ok(indent.to_string())
+    } else {
+        Err("the indent string may only contain spaces, tabs and line breaks".to_string())
+    }
+}
+
+/// Run a query on each of the engine's queued inputs in turn, or once on null with `null_input`, passing each
+/// document's results to `emit` as soon as they are computed. Inputs read by `input` or `inputs` are taken
+/// from the same queue, so they are not run again.
+fn stream_query(engine: &QueryEngine, expr: &Expression, null_input: bool, debug: bool, mut emit: impl FnMut(Vec<Value>) -> Result<()>) -> Result<()> {
+    let mut run = |document: Value| -> Result<()> {
+        match engine.execute(expr, &document) {
+            Ok(outputs) => emit(outputs),
+            Err(e) => {
+                eprintln!("Error executing query: {}", e);
+                if debug {
+                    eprintln!("Expression: {:?}", expr);
+                    eprintln!("Data: {}", serde_json::to_string_pretty(&document).unwrap_or_default());
+                }
+                Err(e.into())
+            }
+        }
+    };
+    
+    if null_input {
+        run(Value::Null)?;
+    } else {
+        while let Some(document) = engine.next_input() {
+            run(document.context("Failed to parse JSON input")?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run a query like `stream_query`, collecting all of the results
+fn run_query(engine: &QueryEngine, expr: &Expression, null_input: bool, debug: bool) -> Result<Vec<Value>> {
+    let mut results = Vec::new();
+    stream_query(engine, expr, null_input, debug, |outputs| {
+        results.extend(outputs);
+        Ok(())
+    })?;
+    Ok(results)
+}
+
+/// Gather all results into a single array (the inverse of --slurp)
+fn collect_results(results: Vec<Value>) -> Vec<Value> {
+    vec![Value::Array(results)]
+}
+
+/// Build the output options from the command line, falling back to the config file's defaults
+fn output_options(cli: &Cli, config: &Config) -> OutputOptions {
+    let options = OutputOptions {
+        // Tabs only matter in pretty output, so --tab implies it
+        pretty: cli.pretty || cli.tab,
+        compact: cli.compact,
+        raw: cli.raw,
+        strict_raw: cli.strict,
+        color: (cli.color || config.color == Some(true)) && !cli.monochrome,
+        indent: cli.indent.or(config.indent).map(usize::from),
+        indent_string: if cli.tab { Some("\t".to_string()) } else { cli.indent_string.clone() },
+        key_order: if cli.sort_keys || config.sort_keys == Some(true) { KeyOrder::Sorted } else { KeyOrder::AsIs },
+        precision: cli.precision.map(usize::from),
+    };
+    
+    if cli.ndjson_out {
+        OutputOptions {
+            pretty: false,
+            compact: true,
+            raw: false,
+            color: false,
+            indent: None,
+            indent_string: None,
+            ..options
+        }
+    } else {
+        options
+    }
+}
+
+/// Writes results through a buffered writer as they are produced, with `separator` (usually a newline)
+/// between them. As a JSON text sequence each result also starts with a record separator. With `newline`
+/// the last result is followed by a line break once the writer is finished.
+struct ResultWriter<'a, W: Write> {
+    out: BufWriter<W>,
+    formatter: &'a OutputFormatter,
+    seq: bool,
+    separator: &'a str,
+    newline: bool,
+    /// Whether any result has been written, so the next one is preceded by the separator
+    written: bool,
+    /// Whether the separator is the line break that ends the output, so it can follow each result at once
+    /// and every line is complete as soon as it is written
+    terminated: bool,
+}
+
+impl<'a, W: Write> ResultWriter<'a, W> {
+    fn new(out: W, formatter: &'a OutputFormatter, seq: bool, separator: &'a str, newline: bool) -> Self {
+        let terminated = newline && separator == "\n";
+        ResultWriter { out: BufWriter::new(out), formatter, seq, separator, newline, written: false, terminated }
+    }
+    
+    /// Write a batch of results, such as those of one input document, and flush them so they are seen
+    /// before the next batch is computed
+    fn write_all(&mut self, results: &[Value]) -> Result<()> {
+        for value in results {
+            if self.written && !self.terminated {
+                write!(self.out, "{}", self.separator).context("Failed to write output")?;
+            }
+            if self.seq {
+                write!(self.out, "{}", RECORD_SEPARATOR).context("Failed to write output")?;
+            }
+            self.formatter.format_into(&mut self.out, value).context("Failed to write output")?;
+            if self.terminated {
+                writeln!(self.out).context("Failed to write output")?;
+            }
+            self.written = true;
+        }
+        self.out.flush().context("Failed to write output")
+    }
+    
+    /// End the output, with a line break after the last result if there was one
+    fn finish(mut self) -> Result<()> {
+        if self.written && self.newline && !self.terminated {
+            writeln!(self.out).context("Failed to write output")?;
+        }
+        self.out.flush().context("Failed to write output")
+    }
+}
+
+/// Parse the input as a stream of whitespace-separated JSON documents, failing on the first malformed one.
+/// Like jq, empty or whitespace-only input is a stream of no documents rather than an error.
+/// In lenient mode the input is newline-delimited JSON instead, and malformed lines are reported on
+/// stderr and skipped.
+fn read_documents(input: &str, lenient: bool) -> Result<Vec<Value>> {
+    let input = strip_bom(input);
+    if !lenient {
+        return serde_json::Deserializer::from_str(input)
+            .into_iter::<Value>()
+            .collect::<Result<Vec<Value>, _>>()
+            .map_err(|e| {
+                let line = e.line().checked_sub(1).and_then(|index| input.lines().nth(index)).unwrap_or_default();
+                anyhow!(describe_malformed_line(e.line(), line, &e))
+            })
+            .context("Failed to parse JSON input (use --lenient to skip malformed lines)");
+    }
+    
+    let mut documents = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        
+        match serde_json::from_str(line) {
+            Ok(document) => documents.push(document),
+            Err(e) => eprintln!("Skipping malformed input on {}", describe_malformed_line(number + 1, line, &e)),
+        }
+    }
+    Ok(documents)
+}
+
+/// Longest part of a malformed line quoted in its error, in characters
+const SNIPPET_LENGTH: usize = 40;
+
+/// Describe a JSON error on a line of input: its 1-based number and column, the problem and the start of
+/// the line, so a bad record in a large stream can be found
+fn describe_malformed_line(number: usize, line: &str, error: &serde_json::Error) -> String {
+    describe_malformed_json(number, error.column(), line, error)
+}
+
+/// Describe a JSON error at a line and column of the whole input, which differ from the error's own
+/// position when only part of the input (such as one record of a sequence) was parsed
+fn describe_malformed_json(number: usize, column: usize, line: &str, error: &serde_json::Error) -> String {
+    use serde_json::error::Category;
+    let problem = match error.classify() {
+        Category::Syntax => "invalid JSON",
+        Category::Eof => "unexpected end of input",
+        Category::Data => "invalid value",
+        Category::Io => "read error",
+    };
+    
+    let line = line.trim_matches(|c: char| c.is_whitespace() || c == RECORD_SEPARATOR);
+    let snippet = match line.char_indices().nth(SNIPPET_LENGTH) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    };
+    format!("line {}, column {}: {} in `{}`", number, column, problem, snippet)
+}
+
+/// Open the input file or stdin to read lazily. A terminal with --null-input gives no input at all.
+fn open_input(path: Option<&Path>, null_input: bool) -> Result<Box<dyn Read + Send>> {
+    Ok(match path {
         Some(path) => {
-            let file = File::open(&path)
-                .with_context(|| format!("Failed to open file: {}", path.display()))?;
-            let mut reader = BufReader::new(file);
-            let mut contents = String::new();
-            reader.read_to_string(&mut contents)
-                .with_context(|| format!("Failed to read file: {}", path.display()))?;
-            contents
+            let file = File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+            Box::new(BufReader::new(file))
+        },
+        None if null_input && io::stdin().is_terminal() => Box::new(io::empty()),
+        None => Box::new(io::stdin()),
+    })
+}
+
+/// Give the engine its input documents. Normally they are all read and parsed up front (timed as JSON parsing).
+/// With --lazy-input, or with --null-input where the query may never read its inputs, they are instead read only
+/// as `input` or `inputs` asks for them, so `rjx -n 1` does not wait for an open stdin to close.
+fn queue_input(engine: &mut QueryEngine, cli: &Cli, json_parse: &mut Duration) -> Result<()> {
+    if cli.lazy_input || (cli.null_input && !cli.slurp && !cli.seq && !cli.lenient) {
+        // One document or line at a time
+        let reader = open_input(cli.input.as_deref(), cli.null_input)?;
+        if cli.raw_input {
+            engine.set_input_stream(line_stream(reader));
+        } else {
+            engine.set_input_stream(document_stream(reader));
         }
+    } else if cli.null_input {
+        // Slurped, sequence and lenient input need all of it, but only once the query asks for a document
+        let cli = cli.clone();
+        engine.set_input_stream(deferred_input(move || read_input(&cli)));
+    } else {
+        engine.set_inputs(time_stage(json_parse, || read_input(cli))?);
+    }
+    Ok(())
+}
+
+/// Read the whole input from the file or stdin (except from a terminal with --null-input, which may need no
+/// input) and parse it into the stream of documents the query runs on one at a time
+fn read_input(cli: &Cli) -> Result<Vec<Value>> {
+    let input = match &cli.input {
+        Some(path) => read_file(path)?,
+        None if cli.null_input && io::stdin().is_terminal() => String::new(),
         None => {
             let mut contents = String::new();
             io::stdin().read_to_string(&mut contents)
@@ -72,68 +462,720 @@ fn main() -> Result<()> {
             contents
         }
     };
+    
+    Ok(if cli.raw_input {
+        read_raw_documents(&input, cli.slurp)
+    } else {
+        let documents = if cli.seq {
+            read_sequence(&input)
+        } else {
+            read_documents(&input, cli.lenient)?
+        };
+        
+        if cli.slurp {
+            vec![Value::Array(documents)]
+        } else {
+            documents
+        }
+    })
+}
+
+/// Read and parse the whole input only when the first document is asked for
+fn deferred_input(read: impl FnOnce() -> Result<Vec<Value>> + Send + 'static) -> impl Iterator<Item = Result<Value, QueryError>> + Send {
+    std::iter::once_with(read).flat_map(|documents| -> Vec<Result<Value, QueryError>> {
+        match documents {
+            Ok(documents) => documents.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(QueryError::Input(format!("{:#}", e)))],
+        }
+    })
+}
+
+/// Read lines from a reader one at a time, each as a string (for --raw-input with --null-input)
+fn line_stream(reader: impl Read + Send + 'static) -> impl Iterator<Item = Result<Value, QueryError>> + Send {
+    BufReader::new(reader)
+        .lines()
+        .map(|line| line.map(Value::String).map_err(|e| QueryError::Input(e.to_string())))
+}
+
+/// Parse JSON documents from a reader one at a time, each as it is taken from the stream (for --lazy-input)
+fn document_stream(reader: impl Read + Send + 'static) -> impl Iterator<Item = Result<Value, QueryError>> + Send {
+    serde_json::Deserializer::from_reader(reader)
+        .into_iter::<Value>()
+        .map(|document| document.map_err(QueryError::from))
+}
+
+/// Read raw text input as strings: one per line, or with slurp the whole input (newlines and all) as one
+fn read_raw_documents(input: &str, slurp: bool) -> Vec<Value> {
+    if slurp {
+        vec![Value::String(input.to_string())]
+    } else {
+        input.lines().map(|line| Value::String(line.to_string())).collect()
+    }
+}
+
+/// The ASCII record separator that starts each record of a JSON text sequence (RFC 7464)
+const RECORD_SEPARATOR: char = '\u{1E}';
+
+/// Parse a JSON text sequence, where each record starts with a record separator. Like jq, a record that
+/// fails to parse (such as one truncated by a writer that crashed) is reported on stderr and skipped.
+fn read_sequence(input: &str) -> Vec<Value> {
+    let mut documents = Vec::new();
+    let mut start = 0;
+    for (number, record) in input.split(RECORD_SEPARATOR).enumerate() {
+        let range = start..start + record.len();
+        start = range.end + RECORD_SEPARATOR.len_utf8();
+        if record.trim().is_empty() {
+            continue;
+        }
+        
+        match read_record(number, input, range) {
+            Ok(values) => documents.extend(values),
+            Err(e) => eprintln!("Skipping {:#}", e),
+        }
+    }
+    documents
+}
+
+/// Parse one record of a JSON text sequence, the given byte range of the input, numbered from 1 by the
+/// record separators before it. Errors give their line and column in the whole input, like --lenient.
+fn read_record(number: usize, input: &str, range: Range<usize>) -> Result<Vec<Value>> {
+    // Trailing whitespace is dropped so an unfinished record ends on its last line, not the one after it
+    let record = strip_bom(&input[range.clone()]);
+    let body = record.trim_end();
+    serde_json::Deserializer::from_str(body)
+        .into_iter::<Value>()
+        .collect::<Result<Vec<Value>, _>>()
+        .map_err(|e| {
+            // The error's position counts from the start of the record's text, which is partway through a line
+            let body_start = range.end - record.len();
+            let before = &input[..body_start];
+            let line_number = before.matches('\n').count() + e.line();
+            let column = match e.line() {
+                1 => body_start - before.rfind('\n').map_or(0, |index| index + 1) + e.column(),
+                _ => e.column(),
+            };
+            let line = input.lines().nth(line_number - 1).unwrap_or_default();
+            anyhow!("malformed record {} on {}", number, describe_malformed_json(line_number, column, line, &e))
+        })
+}
+
+/// Strip a leading UTF-8 byte order mark, as written by some Windows tools, and leading whitespace
+fn strip_bom(input: &str) -> &str {
+    input.strip_prefix('\u{FEFF}').unwrap_or(input).trim_start()
+}
+
+/// Read all JSON values in a file into an array (for --slurpfile)
+fn read_slurpfile(path: &Path) -> Result<Value> {
+    let contents = read_file(path)?;
+    let values = serde_json::Deserializer::from_str(strip_bom(&contents))
+        .into_iter::<Value>()
+        .collect::<Result<Vec<Value>, _>>()
+        .with_context(|| format!("Failed to parse JSON in file: {}", path.display()))?;
+    Ok(Value::Array(values))
+}
+
+/// Read a file as a single JSON string (for --rawfile)
+fn read_rawfile(path: &Path) -> Result<Value> {
+    Ok(Value::String(read_file(path)?))
+}
 
-    // Parse the JSON input
-    let start_parse = Instant::now();
-    let json_value: Value = serde_json::from_str(&json_input)
-        .context("Failed to parse JSON input")?;
-    let parse_duration = start_parse.elapsed();
+/// Collect --arg and --argjson bindings, in the order given per flag
+fn named_args(arg: &[String], argjson: &[String]) -> Result<Map<String, Value>> {
+    let mut named = Map::new();
+    for binding in arg.chunks(2) {
+        named.insert(binding[0].clone(), Value::String(binding[1].clone()));
+    }
+    for binding in argjson.chunks(2) {
+        let value = serde_json::from_str(&binding[1])
+            .with_context(|| format!("Invalid JSON text passed to --argjson {}", binding[0]))?;
+        named.insert(binding[0].clone(), value);
+    }
+    Ok(named)
+}
+
+/// Convert positional arguments to values for $ARGS.positional, parsing them as JSON for --jsonargs
+fn positional_args(values: &[String], json: bool) -> Result<Vec<Value>> {
+    values.iter()
+        .map(|value| if json {
+            serde_json::from_str(value).with_context(|| format!("Invalid JSON text passed to --jsonargs: {}", value))
+        } else {
+            Ok(Value::String(value.clone()))
+        })
+        .collect()
+}
+
+/// Build the $ARGS object from named and positional arguments
+fn args_value(named: Map<String, Value>, positional: Vec<Value>) -> Value {
+    let mut args = Map::new();
+    args.insert("positional".to_string(), Value::Array(positional));
+    args.insert("named".to_string(), Value::Object(named));
+    Value::Object(args)
+}
+
+fn main() -> Result<()> {
+    let mut cli = Cli::parse();
+    
+    // With --args/--jsonargs every positional argument is an argument rather than the input file
+    let mut positional = std::mem::take(&mut cli.positional);
+    if cli.args || cli.jsonargs {
+        if let Some(input) = cli.input.take() {
+            positional.insert(0, input.to_string_lossy().into_owned());
+        }
+    } else if !positional.is_empty() {
+        anyhow::bail!("Unexpected argument '{}' (use --args or --jsonargs to pass positional arguments)", positional[0]);
+    }
+    
+    // Configure the engine and bind variables from --arg, --argjson, --slurpfile, --rawfile and $ARGS
+    let mut query_engine = QueryEngine::new();
+    if let Some(max_depth) = cli.max_depth {
+        query_engine.set_max_depth(max_depth);
+    }
+    let named = named_args(&cli.arg, &cli.argjson)?;
+    for (name, value) in &named {
+        query_engine.set_variable(name, value.clone());
+    }
+    query_engine.set_variable("ARGS", args_value(named, positional_args(&positional, cli.jsonargs)?));
+    for binding in cli.slurpfile.chunks(2) {
+        query_engine.set_variable(&binding[0], read_slurpfile(Path::new(&binding[1]))?);
+    }
+    for binding in cli.rawfile.chunks(2) {
+        query_engine.set_variable(&binding[0], read_rawfile(Path::new(&binding[1]))?);
+    }
+    
+    // Load the output defaults before anything is timed, so the config file's I/O is not counted as formatting
+    let output_options = output_options(&cli, &load_config()?);
+    
+    let mut timings = Timings::default();
+    queue_input(&mut query_engine, &cli, &mut timings.json_parse)?;
     
     // Parse the query
-    let start_query_parse = Instant::now();
-    let query_expr = parse_query(&cli.query)
-        .context("Failed to parse query")?;
-    let query_parse_duration = start_query_parse.elapsed();
+    let query_expr = match time_stage(&mut timings.query_parse, || parse_query(&cli.query)) {
+        Ok(expr) => expr,
+        Err(e) => {
+            eprintln!("Error parsing query: {}", format_parse_error(&cli.query, &e, cli.color && !cli.monochrome));
+            return Err(e).context("Failed to parse query");
+        }
+    };
     
-    // Execute the query
-    let start_execute = Instant::now();
-    let query_engine = QueryEngine::new();
+    if cli.query_debug {
+        eprintln!("{}", serde_json::to_string_pretty(&query_expr).context("Failed to serialize the query")?);
+    }
     
     // Debug the query expression
     if cli.debug {
         eprintln!("Query expression: {:?}", query_expr);
     }
     
-    let results = match query_engine.execute(&query_expr, &json_value) {
-        Ok(results) => results,
-        Err(e) => {
-            eprintln!("Error executing query: {}", e);
-            if cli.debug {
-                eprintln!("Expression: {:?}", query_expr);
-                eprintln!("Data: {}", serde_json::to_string_pretty(&json_value).unwrap_or_default());
-            }
-            return Err(e.into());
-        }
-    };
-    let execute_duration = start_execute.elapsed();
-    
-    // Format and output the results
-    let start_output = Instant::now();
-    let output_options = OutputOptions {
-        pretty: cli.pretty,
-        compact: cli.compact,
-        raw: cli.raw,
-        color: cli.color,
-    };
+    // Execute the query
     
+    // Print the results through one locked, buffered handle rather than locking stdout per line
     let formatter = OutputFormatter::new(output_options);
-    let output = formatter.format_multiple(&results)
-        .context("Failed to format output")?;
-    let output_duration = start_output.elapsed();
-    
-    // Print the results
-    println!("{}", output);
+    let mut writer = ResultWriter::new(io::stdout().lock(), &formatter, cli.seq, &cli.output_separator, !cli.no_newline);
+    let executed = if cli.collect {
+        time_stage(&mut timings.execute, || run_query(&query_engine, &query_expr, cli.null_input, cli.debug))
+            .and_then(|results| time_stage(&mut timings.output, || writer.write_all(&collect_results(results))))
+    } else {
+        // Each document's results are written as soon as they are computed, so the output streams and the
+        // results before a failing document are still printed. That writing happens during execution, so its
+        // time is taken back out of the execution time.
+        let executed = time_stage(&mut timings.execute, || {
+            stream_query(&query_engine, &query_expr, cli.null_input, cli.debug, |results| {
+                time_stage(&mut timings.output, || writer.write_all(&results))
+            })
+        });
+        timings.execute = timings.execute.saturating_sub(timings.output);
+        executed
+    };
+    time_stage(&mut timings.output, || writer.finish())?;
+    executed?;
     
     // Print benchmark information if requested
     if cli.benchmark {
-        eprintln!("\nBenchmark:");
-        eprintln!("  JSON parse time:   {:?}", parse_duration);
-        eprintln!("  Query parse time:  {:?}", query_parse_duration);
-        eprintln!("  Execution time:    {:?}", execute_duration);
-        eprintln!("  Formatting time:   {:?}", output_duration);
-        eprintln!("  Total time:        {:?}", 
-            parse_duration + query_parse_duration + execute_duration + output_duration);
+        eprintln!("\n{}", timings);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    /// Write all of the results at once through a `ResultWriter`
+    fn write_results<W: Write>(out: W, formatter: &OutputFormatter, results: &[Value], seq: bool, separator: &str, newline: bool) -> Result<()> {
+        let mut writer = ResultWriter::new(out, formatter, seq, separator, newline);
+        writer.write_all(results)?;
+        writer.finish()
+    }
+    
+    /// Write a temporary file for a test and return its path
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rjx_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+    
+    #[test]
+    fn test_slurpfile_binding() {
+        let path = temp_file("slurpfile.json", "{\"id\": 1}\n{\"id\": 2}\n");
+        let mut engine = QueryEngine::new();
+        engine.set_variable("records", read_slurpfile(&path).unwrap());
+        std::fs::remove_file(&path).ok();
+        
+        let expr = parse_query("$records | .[1] | .id").unwrap();
+        let result = engine.execute(&expr, &Value::Null).unwrap();
+        assert_eq!(result, vec![json!(2)]);
+    }
+    
+    #[test]
+    fn test_rawfile_binding() {
+        let path = temp_file("rawfile.txt", "line one\nline two\n");
+        let mut engine = QueryEngine::new();
+        engine.set_variable("text", read_rawfile(&path).unwrap());
+        std::fs::remove_file(&path).ok();
+        
+        let expr = parse_query("$text / \"\\n\"").unwrap();
+        let result = engine.execute(&expr, &Value::Null).unwrap();
+        assert_eq!(result, vec![json!(["line one", "line two", ""])]);
+    }
+    
+    #[test]
+    fn test_args_positional() {
+        let mut engine = QueryEngine::new();
+        let positional = positional_args(&["first".to_string(), "2".to_string()], false).unwrap();
+        engine.set_variable("ARGS", args_value(Map::new(), positional));
+        
+        let expr = parse_query("$ARGS.positional[0]").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!("first")]);
+        
+        let expr = parse_query("$ARGS.positional[1]").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!("2")]);
+    }
+    
+    #[test]
+    fn test_jsonargs_and_named() {
+        let positional = positional_args(&["{\"a\": 1}".to_string(), "2".to_string()], true).unwrap();
+        let named = named_args(&["name".to_string(), "Ada".to_string()], &["n".to_string(), "[1]".to_string()]).unwrap();
+        let args = args_value(named, positional);
+        assert_eq!(args, json!({"positional": [{"a": 1}, 2], "named": {"name": "Ada", "n": [1]}}));
+        
+        assert!(positional_args(&["not json".to_string()], true).is_err());
+        assert!(named_args(&[], &["x".to_string(), "{".to_string()]).is_err());
+    }
+    
+    #[test]
+    fn test_arg_in_select() {
+        let cli = Cli::parse_from(["rjx", "--arg", "threshold", "5", "-q", ".[] | select(.score > ($threshold | tonumber)) | .name"]);
+        let mut engine = QueryEngine::new();
+        for (name, value) in named_args(&cli.arg, &cli.argjson).unwrap() {
+            engine.set_variable(&name, value);
+        }
+        
+        // --arg binds a string, so it needs tonumber to compare as a number
+        engine.set_inputs(read_documents(r#"[{"name": "a", "score": 3}, {"name": "b", "score": 7}, {"name": "c", "score": 5.5}]"#, false).unwrap());
+        let expr = parse_query(&cli.query).unwrap();
+        assert_eq!(run_query(&engine, &expr, false, false).unwrap(), vec![json!("b"), json!("c")]);
+    }
+    
+    #[test]
+    fn test_strip_bom() {
+        let input = "\u{FEFF}  {\"a\": 1}";
+        assert!(serde_json::from_str::<Value>(input).is_err());
+        
+        let value: Value = serde_json::from_str(strip_bom(input)).unwrap();
+        assert_eq!(value, json!({"a": 1}));
+        
+        assert_eq!(strip_bom("[1]"), "[1]");
+    }
+    
+    #[test]
+    fn test_write_results() {
+        let results = vec![json!({"a": 1}), json!("text"), json!([1, 2])];
+        let formatter = OutputFormatter::new(OutputOptions { compact: true, ..OutputOptions::default() });
+        
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &results, false, "\n", true).unwrap();
+        let expected = format!("{}\n", formatter.format_multiple(&results).unwrap());
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        
+        // No results print nothing at all
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &[], false, "\n", true).unwrap();
+        assert!(out.is_empty());
+    }
+    
+    #[test]
+    fn test_output_separator() {
+        let cli = Cli::parse_from(["rjx", "-r", "--output-separator", ", ", "-q", ".[]"]);
+        let formatter = OutputFormatter::new(output_options(&cli, &Config::default()));
+        let results = [json!("a"), json!(1), json!({"b": [2]})];
+        
+        // Raw strings are joined as they are, and the output still ends with a newline
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &results, cli.seq, &cli.output_separator, !cli.no_newline).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a, 1, {\"b\":[2]}\n");
+        
+        assert_eq!(Cli::parse_from(["rjx", "-q", "."]).output_separator, "\n");
+    }
+    
+    #[test]
+    fn test_write_results_no_newline() {
+        let formatter = OutputFormatter::new(OutputOptions { raw: true, ..OutputOptions::default() });
+        let results = vec![json!("a"), json!("b")];
+        
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &results, false, "\n", true).unwrap();
+        assert_eq!(out, b"a\nb\n");
+        
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &results, false, "\n", false).unwrap();
+        assert_eq!(out, b"a\nb");
+        
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &results[..1], true, "\n", false).unwrap();
+        assert_eq!(out, b"\x1ea");
+    }
+    
+    #[test]
+    fn test_read_documents_malformed_line() {
+        let input = "{\"id\": 1}\n{\"id\": \n{\"id\": 3}\n";
+        
+        // By default the whole run fails
+        assert!(read_documents(input, false).is_err());
+        
+        // Leniently the malformed line is skipped and the rest are read
+        let documents = read_documents(input, true).unwrap();
+        assert_eq!(documents, vec![json!({"id": 1}), json!({"id": 3})]);
+        
+        let documents = read_documents("1 [2]\n{\"a\": 3}", false).unwrap();
+        assert_eq!(documents, vec![json!(1), json!([2]), json!({"a": 3})]);
+    }
+    
+    #[test]
+    fn test_malformed_line_is_located() {
+        let input = format!("{{\"id\": 1}}\n{{\"id\": 2}}\n{{\"id\": 3, \"note\": \"{}\" oops}}\n{{\"id\": 4}}\n", "x".repeat(60));
+        
+        let error = format!("{:#}", read_documents(&input, false).unwrap_err());
+        assert!(error.contains("line 3, column"), "{}", error);
+        assert!(error.contains("in `{\"id\": 3, \"note\": \"xxxxxxxxxxxxxxxxxxxxx...`"), "{}", error);
+        
+        // Leniently the rest of the stream is still read
+        let documents = read_documents(&input, true).unwrap();
+        assert_eq!(documents, vec![json!({"id": 1}), json!({"id": 2}), json!({"id": 4})]);
+        
+        let line = "{\"id\": ";
+        let error = serde_json::from_str::<Value>(line).unwrap_err();
+        assert_eq!(describe_malformed_line(7, line, &error), "line 7, column 7: unexpected end of input in `{\"id\":`");
+        
+        let line = "{\"id\": oops}";
+        let error = serde_json::from_str::<Value>(line).unwrap_err();
+        assert_eq!(describe_malformed_line(3, line, &error), "line 3, column 8: invalid JSON in `{\"id\": oops}`");
+    }
+    
+    #[test]
+    fn test_document_stream_identity() {
+        let formatter = OutputFormatter::new(OutputOptions { compact: true, ..OutputOptions::default() });
+        let expr = parse_query(".").unwrap();
+        let engine = QueryEngine::new();
+        
+        let mut results = Vec::new();
+        for document in read_documents("{} {\"a\": 1}\n[2]", false).unwrap() {
+            results.extend(engine.execute(&expr, &document).unwrap());
+        }
+        
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &results, false, "\n", true).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "{}\n{\"a\":1}\n[2]\n");
+    }
+    
+    #[test]
+    fn test_exponent_input_prints_like_jq() {
+        let formatter = OutputFormatter::new(OutputOptions { compact: true, ..OutputOptions::default() });
+        let documents = read_documents("1e2 [2.5E1, 1e-2] {\"n\": -3e0}", false).unwrap();
+        
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &documents, false, "\n", true).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "100\n[25,0.01]\n{\"n\":-3}\n");
+    }
+    
+    #[test]
+    fn test_ndjson_out() {
+        let cli = Cli::parse_from(["rjx", "-q", ".[]", "--ndjson-out", "--pretty", "--raw", "-C"]);
+        let formatter = OutputFormatter::new(output_options(&cli, &Config::default()));
+        
+        let expr = parse_query(&cli.query).unwrap();
+        let results = QueryEngine::new().execute(&expr, &json!([{"id": 1, "tags": ["a"]}, "text", null])).unwrap();
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &results, false, "\n", true).unwrap();
+        
+        let output = String::from_utf8(out).unwrap();
+        assert_eq!(output, "{\"id\":1,\"tags\":[\"a\"]}\n\"text\"\nnull\n");
+        assert_eq!(read_documents(&output, true).unwrap(), results);
+    }
+    
+    #[test]
+    fn test_monochrome_overrides_color() {
+        let cli = Cli::parse_from(["rjx", "-q", ".", "-C", "-M"]);
+        let options = output_options(&cli, &Config::default());
+        assert!(!options.color);
+        
+        let output = OutputFormatter::new(options).format(&json!({"a": [1, "two", null]})).unwrap();
+        assert!(!output.contains('\u{1b}'), "{:?}", output);
+        
+        assert!(output_options(&Cli::parse_from(["rjx", "-q", ".", "-C"]), &Config::default()).color);
+        assert!(!output_options(&Cli::parse_from(["rjx", "-q", ".", "--monochrome-output"]), &Config::default()).color);
+    }
+    
+    #[test]
+    fn test_config_defaults() {
+        let path = temp_file("rjxrc", "# output defaults\nindent = 4\nsort-keys = true  # always\n\n");
+        let config = read_config(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(config, Config { indent: Some(4), color: None, sort_keys: Some(true) });
+        
+        let options = output_options(&Cli::parse_from(["rjx", "-q", "."]), &config);
+        assert_eq!((options.indent, options.key_order), (Some(4), KeyOrder::Sorted));
+        let output = OutputFormatter::new(options).format(&json!({"b": [1], "a": 2})).unwrap();
+        assert_eq!(output, "{\n    \"a\": 2,\n    \"b\": [\n        1\n    ]\n}");
+        
+        // Flags override the config file
+        let options = output_options(&Cli::parse_from(["rjx", "-q", ".", "--indent", "2"]), &config);
+        assert_eq!(options.indent, Some(2));
+    }
+    
+    #[test]
+    fn test_config_invalid() {
+        // Only the TOML subset of bare `key = value` lines is read
+        for contents in ["indent = 9", "indent = four", "colour = true", "color", "[output]\nindent = 2", "color = \"true\""] {
+            let path = temp_file("rjxrc_invalid", contents);
+            let result = read_config(&path);
+            std::fs::remove_file(&path).ok();
+            assert!(result.is_err(), "{}", contents);
+        }
+    }
+    
+    #[test]
+    fn test_indent_string() {
+        let cli = Cli::parse_from(["rjx", "-q", ".", "--indent-string", "   "]);
+        let output = OutputFormatter::new(output_options(&cli, &Config::default())).format(&json!({"a": 1})).unwrap();
+        assert_eq!(output, "{\n   \"a\": 1\n}");
+        
+        assert!(Cli::try_parse_from(["rjx", "-q", ".", "--indent-string", ". "]).is_err());
+        assert!(Cli::try_parse_from(["rjx", "-q", ".", "--indent-string", " ", "--indent", "2"]).is_err());
+    }
+    
+    #[test]
+    fn test_tab_implies_pretty() {
+        let cli = Cli::parse_from(["rjx", "-q", ".", "--tab"]);
+        let options = output_options(&cli, &Config::default());
+        assert!(options.pretty);
+        
+        let output = OutputFormatter::new(options).format(&json!({"a": [1]})).unwrap();
+        assert_eq!(output, "{\n\t\"a\": [\n\t\t1\n\t]\n}");
+        
+        assert!(Cli::try_parse_from(["rjx", "-q", ".", "--tab", "--indent", "2"]).is_err());
+        assert!(Cli::try_parse_from(["rjx", "-q", ".", "--tab", "-c"]).is_err());
+    }
+    
+    #[test]
+    fn test_strict_raw() {
+        let formatter = OutputFormatter::new(output_options(&Cli::parse_from(["rjx", "-q", ".", "-r", "--strict"]), &Config::default()));
+        let mut out = Vec::new();
+        let error = write_results(&mut out, &formatter, &[json!("a"), json!([1])], false, "\n", true).unwrap_err();
+        assert!(format!("{:#}", error).contains("a result is array"), "{:#}", error);
+        
+        assert!(Cli::try_parse_from(["rjx", "-q", ".", "--strict"]).is_err());
+        assert!(Cli::parse_from(["rjx", "-q", ".", "--raw-output", "--strict"]).strict);
+    }
+    
+    #[test]
+    fn test_seq_input() {
+        let input = "\u{1E}{\"id\": 1}\n\u{1E}{\"id\": \n\u{1E}{\"id\": 3}\n";
+        let documents = read_sequence(input);
+        assert_eq!(documents, vec![json!({"id": 1}), json!({"id": 3})]);
+        
+        let expr = parse_query(".id").unwrap();
+        let engine = QueryEngine::new();
+        let ids: Vec<Value> = documents.iter().flat_map(|doc| engine.execute(&expr, doc).unwrap()).collect();
+        assert_eq!(ids, vec![json!(1), json!(3)]);
+    }
+    
+    #[test]
+    fn test_seq_record_error() {
+        let input = "\u{1E}{\"id\": 1}\n\u{1E}{\"id\": \n";
+        let error = format!("{:#}", read_record(2, input, 12..input.len()).unwrap_err());
+        
+        // Records are not lines, and --lenient does not apply to them
+        assert!(error.starts_with("malformed record 2 on "), "{}", error);
+        assert!(!error.contains("--lenient"), "{}", error);
+        
+        // Like --lenient, the position is in the whole input rather than within the record
+        assert_eq!(error, "malformed record 2 on line 2, column 7: unexpected end of input in `{\"id\":`");
+        let input = "\u{1E}1\n\u{1E}[2,\n\n  oops]\n\u{1E}3 \u{1E}{\"a\": x}\n";
+        let error = format!("{:#}", read_record(2, input, 4..17).unwrap_err());
+        assert_eq!(error, "malformed record 2 on line 4, column 3: invalid JSON in `oops]`");
+        let error = format!("{:#}", read_record(4, input, 21..input.len()).unwrap_err());
+        assert_eq!(error, "malformed record 4 on line 5, column 11: invalid JSON in `3 \u{1E}{\"a\": x}`");
+    }
+    
+    #[test]
+    fn test_seq_output() {
+        let formatter = OutputFormatter::new(OutputOptions { compact: true, ..OutputOptions::default() });
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &[json!(1), json!([2])], true, "\n", true).unwrap();
+        
+        let output = String::from_utf8(out).unwrap();
+        assert_eq!(output, "\u{1E}1\n\u{1E}[2]\n");
+        assert_eq!(read_sequence(&output), vec![json!(1), json!([2])]);
+    }
+    
+    #[test]
+    fn test_collect_results() {
+        let expr = parse_query(".[] | .id").unwrap();
+        let results = QueryEngine::new().execute(&expr, &json!([{"id": 1}, {"id": 2}, {"id": 3}])).unwrap();
+        
+        let formatter = OutputFormatter::new(OutputOptions { compact: true, ..OutputOptions::default() });
+        let mut out = Vec::new();
+        write_results(&mut out, &formatter, &collect_results(results), false, "\n", true).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[1,2,3]\n");
+        
+        // No results still make one (empty) document
+        assert_eq!(collect_results(Vec::new()), vec![json!([])]);
+    }
+    
+    #[test]
+    fn test_empty_input() {
+        for input in ["", "   \n\t\n", "\u{FEFF}", "\u{FEFF}\n"] {
+            assert!(read_documents(input, false).unwrap().is_empty(), "{:?}", input);
+            assert!(read_documents(input, true).unwrap().is_empty(), "{:?}", input);
+            assert!(read_sequence(input).is_empty(), "{:?}", input);
+        }
+    }
+    
+    #[test]
+    fn test_raw_input_with_inputs() {
+        let path = temp_file("lines.txt", "one\ntwo\nthree\n");
+        let cli = Cli::parse_from(["rjx", "-R", "-n", "-q", "[inputs] | length"]);
+        let input = read_file(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        
+        let mut engine = QueryEngine::new();
+        engine.set_inputs(read_raw_documents(&input, cli.slurp));
+        let expr = parse_query(&cli.query).unwrap();
+        assert_eq!(run_query(&engine, &expr, cli.null_input, false).unwrap(), vec![json!(3)]);
+    }
+    
+    #[test]
+    fn test_null_input_with_inputs() {
+        let input = "1 2\n3\n4";
+        
+        // The main input is null, but every document is still available to `inputs`
+        for (query, expected) in [
+            ("[inputs] | length", json!(4)),
+            ("reduce inputs as $x (0; . + $x)", json!(10)),
+            ("[., input]", json!([null, 1])),
+        ] {
+            let cli = Cli::parse_from(["rjx", "-n", "-q", query]);
+            let expr = parse_query(&cli.query).unwrap();
+            
+            let mut engine = QueryEngine::new();
+            engine.set_inputs(read_documents(input, false).unwrap());
+            assert_eq!(run_query(&engine, &expr, cli.null_input, false).unwrap(), vec![expected.clone()], "{}", query);
+            
+            engine.set_input_stream(document_stream(io::Cursor::new(input.as_bytes().to_vec())));
+            assert_eq!(run_query(&engine, &expr, cli.null_input, false).unwrap(), vec![expected], "{} (lazy)", query);
+        }
+    }
+    
+    #[test]
+    fn test_results_stream_before_an_error() {
+        let formatter = OutputFormatter::new(OutputOptions { compact: true, ..OutputOptions::default() });
+        let mut engine = QueryEngine::new();
+        engine.set_inputs(read_documents("1 2 \"three\" 4", false).unwrap());
+        let expr = parse_query(". - 1").unwrap();
+        
+        // Each document's results are written before the next document runs, so those before the failing
+        // document are already out when it fails
+        let mut out = Vec::new();
+        let mut batches = Vec::new();
+        let mut writer = ResultWriter::new(&mut out, &formatter, false, "\n", true);
+        let result = stream_query(&engine, &expr, false, false, |results| {
+            batches.push(results.clone());
+            writer.write_all(&results)
+        });
+        
+        // With the default separator each line is already complete before the writer is finished
+        assert!(result.is_err());
+        assert_eq!(batches, vec![vec![json!(0)], vec![json!(1)]]);
+        writer.out.flush().unwrap();
+        assert_eq!(String::from_utf8(writer.out.get_ref().to_vec()).unwrap(), "0\n1\n");
+        writer.finish().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "0\n1\n");
+    }
+    
+    #[test]
+    fn test_null_input_reads_input_only_when_asked() {
+        let path = temp_file("null_input_malformed.json", "1 {oops");
+        let mut timings = Timings::default();
+        
+        // The malformed document is only reached if the query reads that far
+        for flags in [&["-n"][..], &["-n", "--seq"], &["-n", "--slurp"]] {
+            let cli = Cli::parse_from(["rjx", "-q", "1"].iter().chain(flags).copied().chain([path.to_str().unwrap()]));
+            let mut engine = QueryEngine::new();
+            queue_input(&mut engine, &cli, &mut timings.json_parse).unwrap();
+            assert_eq!(run_query(&engine, &parse_query("1").unwrap(), true, false).unwrap(), vec![json!(1)], "{:?}", flags);
+        }
+        
+        let cli = Cli::parse_from(["rjx", "-n", "-q", "[inputs]", path.to_str().unwrap()]);
+        let mut engine = QueryEngine::new();
+        queue_input(&mut engine, &cli, &mut timings.json_parse).unwrap();
+        assert!(run_query(&engine, &parse_query("[inputs]").unwrap(), true, false).is_err());
+        
+        // Without --null-input every document is parsed before the query runs
+        let cli = Cli::parse_from(["rjx", "-q", ".", path.to_str().unwrap()]);
+        assert!(queue_input(&mut QueryEngine::new(), &cli, &mut timings.json_parse).is_err());
+        std::fs::remove_file(&path).ok();
+        
+        // An endless stream of lines is read only as far as the query asks
+        let mut engine = QueryEngine::new();
+        engine.set_input_stream(line_stream(io::repeat(b'\n')));
+        assert_eq!(run_query(&engine, &parse_query("[limit(2; inputs)]").unwrap(), true, false).unwrap(), vec![json!(["", ""])]);
+    }
+    
+    #[test]
+    fn test_input_consumes_documents() {
+        let mut engine = QueryEngine::new();
+        
+        // Without --null-input, a document read by `input` is not run again
+        engine.set_inputs(read_documents("1 2 3 4 5", false).unwrap());
+        let expr = parse_query("[., input]").unwrap();
+        let error = run_query(&engine, &expr, false, false).unwrap_err();
+        assert!(error.to_string().contains("no more inputs"), "{}", error);
+        
+        engine.set_inputs(read_documents("1 2 3 4", false).unwrap());
+        assert_eq!(run_query(&engine, &expr, false, false).unwrap(), vec![json!([1, 2]), json!([3, 4])]);
+        
+        engine.set_inputs(read_documents("1 2 3", false).unwrap());
+        let expr = parse_query("[., input, [inputs]]").unwrap();
+        assert_eq!(run_query(&engine, &expr, true, false).unwrap(), vec![json!([null, 1, [2, 3]])]);
+    }
+    
+    #[test]
+    fn test_raw_input() {
+        let path = temp_file("raw_input.txt", "alpha\nbeta\ngamma\n");
+        let contents = read_file(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        
+        let lines = read_raw_documents(&contents, false);
+        assert_eq!(lines, vec![json!("alpha"), json!("beta"), json!("gamma")]);
+        
+        // With --slurp the whole file is one string, which the query can split itself
+        let documents = read_raw_documents(&contents, true);
+        assert_eq!(documents, vec![json!("alpha\nbeta\ngamma\n")]);
+        
+        let expr = parse_query(r#"split("\n") | map(length)"#).unwrap();
+        let result = QueryEngine::new().execute(&expr, &documents[0]).unwrap();
+        assert_eq!(result, vec![json!([5, 4, 5, 0])]);
+    }
+}
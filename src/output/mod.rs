@@ -2,8 +2,13 @@
 //!
 //! This module handles formatting and displaying JSON results
 
+use crate::parser::ParseError;
+use crate::query::type_name;
 use colored::Colorize;
-use serde_json::{Value, to_string_pretty, to_string};
+use serde::Serialize;
+use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter};
+use serde_json::{Serializer, Value};
+use std::io::{self, Write};
 use thiserror::Error;
 
 /// Error type for output formatting failures
@@ -14,10 +19,13 @@ pub enum OutputError {
     
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
+    
+    #[error("strict raw output expects strings, but a result is {0}")]
+    NotAString(&'static str),
 }
 
 /// Output format options
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct OutputOptions {
     /// Pretty print the output with indentation
     pub pretty: bool,
@@ -28,19 +36,45 @@ pub struct OutputOptions {
     /// Raw output (unwrap strings)
     pub raw: bool,
     
+    /// With raw output, fail on a result that is not a string instead of printing it as JSON
+    pub strict_raw: bool,
+    
     /// Colorize JSON output
     pub color: bool,
+    
+    /// Number of spaces to indent by (0 means compact, single-line output)
+    pub indent: Option<usize>,
+    
+    /// Indent each level by this string instead of spaces (it must be JSON whitespace for the output to be JSON)
+    pub indent_string: Option<String>,
+    
+    /// The order to print the keys of objects in
+    pub key_order: KeyOrder,
+    
+    /// Round floating point numbers to this many significant digits (integers are left alone)
+    pub precision: Option<usize>,
 }
 
-impl Default for OutputOptions {
-    fn default() -> Self {
-        OutputOptions {
-            pretty: false,
-            compact: false,
-            raw: false,
-            color: false,
-        }
-    }
+/// The order object keys are printed in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyOrder {
+    /// The order the object holds its keys in. That is insertion order only when serde_json is built with its
+    /// `preserve_order` feature; otherwise objects keep their keys sorted, so this falls back to `Sorted`
+    /// (see [`preserves_key_order`]).
+    #[default]
+    AsIs,
+    
+    /// Sort the keys of every object, including objects nested in arrays
+    Sorted,
+}
+
+/// Whether objects keep their keys in insertion order, which depends on serde_json's `preserve_order` feature.
+/// Without it [`KeyOrder::AsIs`] prints keys sorted, just like [`KeyOrder::Sorted`].
+pub fn preserves_key_order() -> bool {
+    let mut obj = serde_json::Map::new();
+    obj.insert("b".to_string(), Value::Null);
+    obj.insert("a".to_string(), Value::Null);
+    obj.keys().next().is_some_and(|key| key == "b")
 }
 
 /// Formatter for JSON output
@@ -56,27 +90,65 @@ impl OutputFormatter {
     
     /// Format a JSON value as a string
     pub fn format(&self, value: &Value) -> Result<String, OutputError> {
-        // Handle raw output (unwrap strings)
+        let mut buffer = Vec::new();
+        self.format_into(&mut buffer, value)?;
+        Ok(String::from_utf8(buffer).expect("formatted output is valid UTF-8"))
+    }
+    
+    /// Format a JSON value straight into a writer, without building an intermediate string
+    /// (except for colorized output, which is colored as text)
+    pub fn format_into<W: Write>(&self, mut writer: W, value: &Value) -> Result<(), OutputError> {
+        // Handle raw output (unwrap strings); colors only ever apply to JSON text
         if self.options.raw {
             if let Value::String(s) = value {
-                return Ok(s.clone());
+                writer.write_all(s.as_bytes())?;
+                return Ok(());
+            }
+            if self.options.strict_raw {
+                return Err(OutputError::NotAString(type_name(value)));
             }
         }
         
-        // Format the JSON value
-        let json_str = if self.options.compact {
-            to_string(value)?
-        } else if self.options.pretty {
-            to_string_pretty(value)?
+        // Sort object keys at every level if requested
+        let sorted;
+        let value = if self.options.key_order == KeyOrder::Sorted {
+            sorted = sort_keys(value);
+            &sorted
         } else {
-            to_string(value)?
+            value
+        };
+        
+        // Round floats to the requested number of significant digits
+        let rounded;
+        let value = match self.options.precision {
+            Some(digits) => {
+                rounded = round_floats(value, digits);
+                &rounded
+            },
+            None => value,
         };
         
         // Colorize the output if requested
         if self.options.color {
-            Ok(self.colorize_json(&json_str))
-        } else {
-            Ok(json_str)
+            let mut buffer = Vec::new();
+            self.write_json(&mut buffer, value)?;
+            let json_str = String::from_utf8(buffer).expect("serde_json produces valid UTF-8");
+            writer.write_all(self.colorize_json(&json_str).as_bytes())?;
+            return Ok(());
+        }
+        
+        self.write_json(writer, value)
+    }
+    
+    /// Serialize a JSON value with the configured layout
+    fn write_json<W: Write>(&self, writer: W, value: &Value) -> Result<(), OutputError> {
+        match (self.options.indent_string.as_deref(), self.options.indent) {
+            _ if self.options.compact => write_with(writer, value, CompactFormatter),
+            (Some(indent), _) => write_with(writer, value, PrettyFormatter::with_indent(indent.as_bytes())),
+            (None, Some(0)) => write_with(writer, value, CompactFormatter),
+            (None, Some(indent)) => write_indented(writer, value, indent),
+            (None, None) if self.options.pretty => write_with(writer, value, PrettyFormatter::new()),
+            (None, None) => write_with(writer, value, CompactFormatter),
         }
     }
     
@@ -148,6 +220,132 @@ impl OutputFormatter {
     }
 }
 
+/// Render a query parse error, showing the query line with a caret under the failing position
+pub fn format_parse_error(query: &str, error: &ParseError, color: bool) -> String {
+    let message = error.to_string();
+    let column = match error.column() {
+        Some(column) => column,
+        None => return message,
+    };
+    
+    // Find the line of a multi-line query that the error is on
+    let mut line_start = 0;
+    let mut line = "";
+    for (i, text) in query.split('\n').enumerate() {
+        let length = text.chars().count();
+        line = text;
+        if column <= line_start + length || i == query.matches('\n').count() {
+            break;
+        }
+        line_start += length + 1;
+    }
+    
+    let caret = format!("{}^", " ".repeat(column - line_start));
+    if color {
+        format!("{}\n    {}\n    {}", message.red(), line, caret.red().bold())
+    } else {
+        format!("{}\n    {}\n    {}", message, line, caret)
+    }
+}
+
+/// Recursively sort the keys of all objects in a JSON value, descending into arrays
+fn sort_keys(value: &Value) -> Value {
+    match value {
+        Value::Object(obj) => {
+            let mut entries: Vec<(&String, &Value)> = obj.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            Value::Object(entries.into_iter().map(|(k, v)| (k.clone(), sort_keys(v))).collect())
+        },
+        Value::Array(arr) => Value::Array(arr.iter().map(sort_keys).collect()),
+        _ => value.clone(),
+    }
+}
+
+/// Recursively round every non-integer number in a JSON value to the given number of significant digits
+fn round_floats(value: &Value, digits: usize) -> Value {
+    match value {
+        Value::Number(n) if n.is_f64() => {
+            let f = n.as_f64().unwrap_or_default();
+            // Formatting in scientific notation rounds to exactly `digits` significant digits
+            let rounded: f64 = format!("{:.*e}", digits.max(1) - 1, f).parse().unwrap_or(f);
+            Value::from(rounded)
+        },
+        Value::Object(obj) => Value::Object(obj.iter().map(|(k, v)| (k.clone(), round_floats(v, digits))).collect()),
+        Value::Array(arr) => Value::Array(arr.iter().map(|v| round_floats(v, digits)).collect()),
+        _ => value.clone(),
+    }
+}
+
+/// Pretty print a JSON value indenting each level by the given number of spaces
+fn write_indented<W: Write>(writer: W, value: &Value, indent: usize) -> Result<(), OutputError> {
+    let indent = " ".repeat(indent);
+    write_with(writer, value, PrettyFormatter::with_indent(indent.as_bytes()))
+}
+
+/// Serialize a JSON value with the given layout, printing integral floats like jq
+fn write_with<W: Write, F: Formatter>(writer: W, value: &Value, formatter: F) -> Result<(), OutputError> {
+    let mut serializer = Serializer::with_formatter(writer, IntegralFloats(formatter));
+    value.serialize(&mut serializer)?;
+    Ok(())
+}
+
+/// A serde_json formatter that lays out JSON like the one it wraps, but writes a float with no fractional
+/// part that fits in an i64 the way jq does, as an integer (`2.0` is printed as `2`). Only the output changes,
+/// so values keep their float type inside queries.
+struct IntegralFloats<F>(F);
+
+impl<F: Formatter> Formatter for IntegralFloats<F> {
+    fn write_f64<W: ?Sized + Write>(&mut self, writer: &mut W, value: f64) -> io::Result<()> {
+        if value == 0.0 && value.is_sign_negative() {
+            writer.write_all(b"-0")
+        } else if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+            self.0.write_i64(writer, value as i64)
+        } else {
+            self.0.write_f64(writer, value)
+        }
+    }
+    
+    fn begin_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_array(writer)
+    }
+    
+    fn end_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_array(writer)
+    }
+    
+    fn begin_array_value<W: ?Sized + Write>(&mut self, writer: &mut W, first: bool) -> io::Result<()> {
+        self.0.begin_array_value(writer, first)
+    }
+    
+    fn end_array_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_array_value(writer)
+    }
+    
+    fn begin_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_object(writer)
+    }
+    
+    fn end_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_object(writer)
+    }
+    
+    fn begin_object_key<W: ?Sized + Write>(&mut self, writer: &mut W, first: bool) -> io::Result<()> {
+        self.0.begin_object_key(writer, first)
+    }
+    
+    fn end_object_key<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_object_key(writer)
+    }
+    
+    fn begin_object_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_object_value(writer)
+    }
+    
+    fn end_object_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_object_value(writer)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -163,7 +361,7 @@ mod tests {
         let value = json!({"name": "John", "age": 30});
         
         let result = formatter.format(&value).unwrap();
-        assert_eq!(result, r#"{"name":"John","age":30}"#);
+        assert_eq!(result, r#"{"age":30,"name":"John"}"#);
     }
     
     #[test]
@@ -192,4 +390,197 @@ mod tests {
         let result = formatter.format(&value).unwrap();
         assert_eq!(result, "Hello, world!");
     }
+    
+    #[test]
+    fn test_format_strict_raw() {
+        // Without strict raw output, non-strings are printed as JSON
+        let formatter = OutputFormatter::new(OutputOptions { raw: true, compact: true, ..Default::default() });
+        assert_eq!(formatter.format(&json!({"a": [1]})).unwrap(), r#"{"a":[1]}"#);
+        
+        let formatter = OutputFormatter::new(OutputOptions { raw: true, strict_raw: true, ..Default::default() });
+        assert_eq!(formatter.format(&json!("text")).unwrap(), "text");
+        
+        let error = formatter.format(&json!({"a": [1]})).unwrap_err();
+        assert!(matches!(error, OutputError::NotAString("object")));
+        assert_eq!(error.to_string(), "strict raw output expects strings, but a result is object");
+        assert!(matches!(formatter.format(&json!(null)), Err(OutputError::NotAString("null"))));
+    }
+    
+    #[test]
+    fn test_format_indent() {
+        let value = json!({"a": [1]});
+        let format_with_indent = |indent| {
+            let options = OutputOptions {
+                indent: Some(indent),
+                ..Default::default()
+            };
+            OutputFormatter::new(options).format(&value).unwrap()
+        };
+        
+        assert_eq!(format_with_indent(0), r#"{"a":[1]}"#);
+        assert_eq!(format_with_indent(2), "{\n  \"a\": [\n    1\n  ]\n}");
+        assert_eq!(format_with_indent(4), "{\n    \"a\": [\n        1\n    ]\n}");
+    }
+    
+    #[test]
+    fn test_format_indent_string() {
+        let options = OutputOptions { indent_string: Some("   ".to_string()), ..Default::default() };
+        let result = OutputFormatter::new(options).format(&json!({"a": [1]})).unwrap();
+        assert_eq!(result, "{\n   \"a\": [\n      1\n   ]\n}");
+        
+        let options = OutputOptions { indent_string: Some("\t".to_string()), ..Default::default() };
+        assert_eq!(OutputFormatter::new(options).format(&json!([1])).unwrap(), "[\n\t1\n]");
+        
+        // Compact output still wins
+        let options = OutputOptions { indent_string: Some("  ".to_string()), compact: true, ..Default::default() };
+        assert_eq!(OutputFormatter::new(options).format(&json!([1])).unwrap(), "[1]");
+    }
+    
+    #[test]
+    fn test_format_indent_zero_overrides_pretty() {
+        let options = OutputOptions {
+            pretty: true,
+            indent: Some(0),
+            ..Default::default()
+        };
+        let formatter = OutputFormatter::new(options);
+        
+        let result = formatter.format(&json!({"a": 1})).unwrap();
+        assert_eq!(result, r#"{"a":1}"#);
+    }
+    
+    #[test]
+    fn test_format_key_order() {
+        let mut obj = serde_json::Map::new();
+        obj.insert("z".to_string(), json!(1));
+        obj.insert("a".to_string(), json!({"y": 2, "b": 3}));
+        let value = Value::Object(obj);
+        
+        let sorted = OutputFormatter::new(OutputOptions { key_order: KeyOrder::Sorted, ..Default::default() });
+        assert_eq!(sorted.format(&value).unwrap(), r#"{"a":{"b":3,"y":2},"z":1}"#);
+        
+        // As-is keeps insertion order only where objects do; otherwise it is the same as sorted
+        let as_is = OutputFormatter::new(OutputOptions { key_order: KeyOrder::AsIs, ..Default::default() });
+        if preserves_key_order() {
+            assert!(as_is.format(&value).unwrap().starts_with(r#"{"z":1"#));
+        } else {
+            assert_eq!(as_is.format(&value).unwrap(), sorted.format(&value).unwrap());
+        }
+    }
+    
+    #[test]
+    fn test_format_sort_keys_in_arrays() {
+        let options = OutputOptions {
+            pretty: true,
+            key_order: KeyOrder::Sorted,
+            ..Default::default()
+        };
+        let formatter = OutputFormatter::new(options);
+        let value: Value = serde_json::from_str(r#"[{"b":1,"a":2,"c":{"z":0,"y":[{"e":1,"d":2}]}}]"#).unwrap();
+        
+        let result = formatter.format(&value).unwrap();
+        let a = result.find("\"a\"").unwrap();
+        let b = result.find("\"b\"").unwrap();
+        let d = result.find("\"d\"").unwrap();
+        let e = result.find("\"e\"").unwrap();
+        let y = result.find("\"y\"").unwrap();
+        let z = result.find("\"z\"").unwrap();
+        assert!(a < b && y < z && d < e);
+        assert!(result.starts_with("[\n  {\n    \"a\": 2"));
+    }
+    
+    #[test]
+    fn test_format_precision() {
+        let options = OutputOptions {
+            precision: Some(3),
+            ..Default::default()
+        };
+        let formatter = OutputFormatter::new(options);
+        
+        assert_eq!(formatter.format(&json!(0.30000000000000004)).unwrap(), "0.3");
+        assert_eq!(formatter.format(&json!(1.23456789)).unwrap(), "1.23");
+        assert_eq!(formatter.format(&json!({"a": [0.000123456]})).unwrap(), r#"{"a":[0.000123]}"#);
+        
+        // Integers keep all of their digits
+        assert_eq!(formatter.format(&json!(123456789)).unwrap(), "123456789");
+    }
+    
+    #[test]
+    fn test_format_parse_error_caret() {
+        let query = ".a | | .b";
+        let error = crate::parser::parse_query(query).unwrap_err();
+        let rendered = format_parse_error(query, &error, false);
+        
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], error.to_string());
+        assert_eq!(lines[1], "    .a | | .b");
+        assert_eq!(lines[2].find('^'), Some(4 + error.column().unwrap()));
+        assert_eq!(&lines[1][lines[2].find('^').unwrap()..], "| .b");
+    }
+    
+    #[test]
+    fn test_format_parse_error_multiline() {
+        let query = ".a\n| .b +";
+        let error = crate::parser::parse_query(query).unwrap_err();
+        let rendered = format_parse_error(query, &error, false);
+        assert!(rendered.ends_with("\n    | .b +\n          ^"));
+    }
+    
+    #[test]
+    fn test_format_integral_floats() {
+        let formatter = OutputFormatter::new(OutputOptions { compact: true, ..Default::default() });
+        
+        // Like jq, floats with no fractional part print as integers
+        assert_eq!(formatter.format(&json!(2.0)).unwrap(), "2");
+        assert_eq!(formatter.format(&json!(-3.0)).unwrap(), "-3");
+        assert_eq!(formatter.format(&json!(2.5)).unwrap(), "2.5");
+        assert_eq!(formatter.format(&json!(9007199254740992.0)).unwrap(), "9007199254740992");
+        assert_eq!(formatter.format(&json!(-0.0)).unwrap(), "-0");
+        
+        // Beyond the range of an integer they keep serde_json's float form
+        assert_eq!(formatter.format(&json!(1e20)).unwrap(), "1e+20");
+        
+        let formatter = OutputFormatter::new(OutputOptions { indent: Some(2), ..Default::default() });
+        assert_eq!(formatter.format(&json!({"a": [1.0, 1.5]})).unwrap(), "{\n  \"a\": [\n    1,\n    1.5\n  ]\n}");
+    }
+    
+    #[test]
+    fn test_format_into_writes_each_layout() {
+        let value = json!({"b": [1, 2.5, "x"], "a": {"nested": null}, "s": "text"});
+        let cases = [
+            (OutputOptions { compact: true, ..Default::default() }, r#"{"a":{"nested":null},"b":[1,2.5,"x"],"s":"text"}"#),
+            (OutputOptions { pretty: true, ..Default::default() },
+             "{\n  \"a\": {\n    \"nested\": null\n  },\n  \"b\": [\n    1,\n    2.5,\n    \"x\"\n  ],\n  \"s\": \"text\"\n}"),
+            (OutputOptions { indent: Some(3), key_order: KeyOrder::Sorted, ..Default::default() },
+             "{\n   \"a\": {\n      \"nested\": null\n   },\n   \"b\": [\n      1,\n      2.5,\n      \"x\"\n   ],\n   \"s\": \"text\"\n}"),
+            (OutputOptions { precision: Some(1), ..Default::default() }, r#"{"a":{"nested":null},"b":[1,2,"x"],"s":"text"}"#),
+        ];
+        
+        for (options, expected) in cases {
+            let mut buffer = Vec::new();
+            OutputFormatter::new(options).format_into(&mut buffer, &value).unwrap();
+            assert_eq!(String::from_utf8(buffer).unwrap(), expected);
+        }
+        
+        // Colors depend on the terminal unless forced on
+        colored::control::set_override(true);
+        let mut buffer = Vec::new();
+        OutputFormatter::new(OutputOptions { color: true, ..Default::default() }).format_into(&mut buffer, &json!([1, null])).unwrap();
+        colored::control::unset_override();
+        assert_eq!(String::from_utf8(buffer).unwrap(),
+                   "\u{1b}[33m[\u{1b}[0m\u{1b}[34m1\u{1b}[0m\u{1b}[36m,\u{1b}[0m\u{1b}[35mn\u{1b}[0mull\u{1b}[33m]\u{1b}[0m");
+        
+        let formatter = OutputFormatter::new(OutputOptions { raw: true, ..Default::default() });
+        let mut buffer = Vec::new();
+        formatter.format_into(&mut buffer, &json!("line\nbreak")).unwrap();
+        assert_eq!(buffer, b"line\nbreak");
+    }
+    
+    #[test]
+    fn test_color_only_applies_to_json_text() {
+        // Raw strings are not JSON, so --color leaves them without escape codes
+        let formatter = OutputFormatter::new(OutputOptions { raw: true, color: true, ..Default::default() });
+        assert_eq!(formatter.format(&json!("plain")).unwrap(), "plain");
+    }
 }
@@ -3,6 +3,8 @@
 //! This module handles parsing of query expressions similar to jq syntax
 //! but with a focus on performance and simplicity.
 
+use serde::Serialize;
+use serde_json::Value;
 use thiserror::Error;
 use std::fmt;
 
@@ -20,6 +22,31 @@ pub enum ParseError {
     
     #[error("invalid filter: {0}")]
     InvalidFilter(String),
+    
+    #[error("{error}")]
+    Located {
+        error: Box<ParseError>,
+        /// Character position in the query where the error was found
+        column: usize,
+    },
+}
+
+impl ParseError {
+    /// Attach the query position an error was found at
+    fn at(self, column: usize) -> Self {
+        match self {
+            ParseError::Located { .. } => self,
+            error => ParseError::Located { error: Box::new(error), column },
+        }
+    }
+    
+    /// The character position in the query where the error was found, if known
+    pub fn column(&self) -> Option<usize> {
+        match self {
+            ParseError::Located { column, .. } => Some(*column),
+            _ => None,
+        }
+    }
 }
 
 /// Token types for the query language lexer
@@ -34,9 +61,29 @@ pub enum Token {
     LeftBrace,         // {
     RightBrace,        // }
     Colon,             // :
+    Semicolon,         // ;
+    LeftParen,         // (
+    RightParen,        // )
     Question,          // ?
+    Plus,              // +
+    Minus,             // -
+    Star,              // *
+    Slash,             // /
+    SlashSlash,        // //
+    Percent,           // %
+    Equal,             // ==
+    NotEqual,          // !=
+    Less,              // <
+    LessEqual,         // <=
+    Greater,           // >
+    GreaterEqual,      // >=
+    Assign,            // =
+    UpdateAssign,      // |=
     Identifier(String),
+    Variable(String),  // $name
+    Format(String),    // @name
     StringLiteral(String),
+    StringTemplate(Vec<TemplatePart>), // "text \(expr) text"
     NumberLiteral(f64),
     BoolLiteral(bool),
     Null,
@@ -54,9 +101,44 @@ impl fmt::Display for Token {
             Token::LeftBrace => write!(f, "{{"),
             Token::RightBrace => write!(f, "}}"),
             Token::Colon => write!(f, ":"),
+            Token::Semicolon => write!(f, ";"),
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
             Token::Question => write!(f, "?"),
+            Token::Plus => write!(f, "+"),
+            Token::Minus => write!(f, "-"),
+            Token::Star => write!(f, "*"),
+            Token::Slash => write!(f, "/"),
+            Token::SlashSlash => write!(f, "//"),
+            Token::Percent => write!(f, "%"),
+            Token::Equal => write!(f, "=="),
+            Token::NotEqual => write!(f, "!="),
+            Token::Assign => write!(f, "="),
+            Token::UpdateAssign => write!(f, "|="),
+            Token::Less => write!(f, "<"),
+            Token::LessEqual => write!(f, "<="),
+            Token::Greater => write!(f, ">"),
+            Token::GreaterEqual => write!(f, ">="),
             Token::Identifier(s) => write!(f, "{}", s),
+            Token::Variable(s) => write!(f, "${}", s),
+            Token::Format(s) => write!(f, "@{}", s),
             Token::StringLiteral(s) => write!(f, "\"{}\"", s),
+            Token::StringTemplate(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        TemplatePart::Text(s) => write!(f, "{}", s)?,
+                        TemplatePart::Interpolation(tokens, _) => {
+                            write!(f, "\\(")?;
+                            for (token, _) in tokens {
+                                write!(f, "{}", token)?;
+                            }
+                            write!(f, ")")?;
+                        },
+                    }
+                }
+                write!(f, "\"")
+            },
             Token::NumberLiteral(n) => write!(f, "{}", n),
             Token::BoolLiteral(b) => write!(f, "{}", b),
             Token::Null => write!(f, "null"),
@@ -64,10 +146,20 @@ impl fmt::Display for Token {
     }
 }
 
+/// A piece of a string literal containing interpolations
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart {
+    Text(String),
+    /// The tokens of an interpolated expression, and the position of its closing parenthesis
+    Interpolation(Vec<(Token, usize)>, usize),
+}
+
 /// Lexer for tokenizing query strings
 pub struct Lexer {
     input: Vec<char>,
     position: usize,
+    /// Current nesting depth of string interpolations being scanned
+    depth: usize,
 }
 
 impl Lexer {
@@ -76,6 +168,7 @@ impl Lexer {
         Lexer {
             input: input.chars().collect(),
             position: 0,
+            depth: 0,
         }
     }
     
@@ -105,9 +198,29 @@ impl Lexer {
     
     /// Tokenize the input string into a vector of tokens
     pub fn tokenize(&mut self) -> Result<Vec<Token>, ParseError> {
+        Ok(self.tokenize_spanned()?.into_iter().map(|(token, _)| token).collect())
+    }
+    
+    /// Tokenize the input string, pairing each token with the character position it starts at
+    pub fn tokenize_spanned(&mut self) -> Result<Vec<(Token, usize)>, ParseError> {
+        self.scan(false).map_err(|e| e.at(self.position))
+    }
+    
+    /// Scan tokens and their start positions until the end of the input, or when `closing`
+    /// until (and past) the parenthesis that closes an interpolation
+    fn scan(&mut self, closing: bool) -> Result<Vec<(Token, usize)>, ParseError> {
         let mut tokens = Vec::new();
+        let mut columns = Vec::new();
+        let mut depth = 0;
         
         while let Some(c) = self.current_char() {
+            let column = self.position;
+            
+            if closing && c == ')' && depth == 0 {
+                self.advance();
+                return Ok(tokens.into_iter().zip(columns).collect());
+            }
+            
             match c {
                 '.' => {
                     self.advance();
@@ -120,7 +233,12 @@ impl Lexer {
                 },
                 '|' => {
                     self.advance();
-                    tokens.push(Token::Pipe);
+                    if self.current_char() == Some('=') {
+                        self.advance();
+                        tokens.push(Token::UpdateAssign);
+                    } else {
+                        tokens.push(Token::Pipe);
+                    }
                 },
                 ',' => {
                     self.advance();
@@ -146,14 +264,62 @@ impl Lexer {
                     self.advance();
                     tokens.push(Token::Colon);
                 },
+                ';' => {
+                    self.advance();
+                    tokens.push(Token::Semicolon);
+                },
+                '(' => {
+                    self.advance();
+                    depth += 1;
+                    tokens.push(Token::LeftParen);
+                },
+                ')' => {
+                    self.advance();
+                    depth -= 1;
+                    tokens.push(Token::RightParen);
+                },
                 '?' => {
                     self.advance();
                     tokens.push(Token::Question);
                 },
+                '+' => {
+                    self.advance();
+                    tokens.push(Token::Plus);
+                },
+                '-' => {
+                    self.advance();
+                    tokens.push(Token::Minus);
+                },
+                '*' => {
+                    self.advance();
+                    tokens.push(Token::Star);
+                },
+                '/' => {
+                    self.advance();
+                    if self.current_char() == Some('/') {
+                        self.advance();
+                        tokens.push(Token::SlashSlash);
+                    } else {
+                        tokens.push(Token::Slash);
+                    }
+                },
+                '%' => {
+                    self.advance();
+                    tokens.push(Token::Percent);
+                },
+                '=' | '!' | '<' | '>' => {
+                    tokens.push(self.read_comparison()?);
+                },
+                '$' => {
+                    tokens.push(self.read_variable()?);
+                },
+                '@' => {
+                    tokens.push(self.read_format()?);
+                },
                 '"' => {
                     tokens.push(self.read_string()?);
                 },
-                c if c.is_ascii_digit() || c == '-' => {
+                c if c.is_ascii_digit() => {
                     tokens.push(self.read_number()?);
                 },
                 c if c.is_alphabetic() || c == '_' => {
@@ -166,21 +332,34 @@ impl Lexer {
                     return Err(ParseError::Syntax(format!("unexpected character: {}", c)));
                 }
             }
+            
+            columns.resize(tokens.len(), column);
         }
         
-        Ok(tokens)
+        if closing {
+            return Err(ParseError::Syntax("unterminated string interpolation".to_string()));
+        }
+        
+        Ok(tokens.into_iter().zip(columns).collect())
     }
     
     /// Read a string literal
     fn read_string(&mut self) -> Result<Token, ParseError> {
         self.advance(); // Skip opening quote
         let mut value = String::new();
+        let mut parts = Vec::new();
         
         while let Some(c) = self.current_char() {
             match c {
                 '"' => {
                     self.advance(); // Skip closing quote
-                    return Ok(Token::StringLiteral(value));
+                    if parts.is_empty() {
+                        return Ok(Token::StringLiteral(value));
+                    }
+                    if !value.is_empty() {
+                        parts.push(TemplatePart::Text(value));
+                    }
+                    return Ok(Token::StringTemplate(parts));
                 },
                 '\\' => {
                     self.advance();
@@ -190,7 +369,33 @@ impl Lexer {
                         Some('n') => value.push('\n'),
                         Some('r') => value.push('\r'),
                         Some('t') => value.push('\t'),
-                        Some(c) => value.push(c),
+                        Some('/') => value.push('/'),
+                        Some('b') => value.push('\u{8}'),
+                        Some('f') => value.push('\u{c}'),
+                        Some('u') => {
+                            self.advance();
+                            value.push(self.read_unicode_escape()?);
+                            continue;
+                        },
+                        Some('(') => {
+                            // Interpolation: tokenize the expression up to its closing parenthesis
+                            self.advance();
+                            if !value.is_empty() {
+                                parts.push(TemplatePart::Text(std::mem::take(&mut value)));
+                            }
+                            // Each interpolation is scanned recursively, so deep nesting fails cleanly
+                            // instead of overflowing the stack
+                            if self.depth >= MAX_NESTING_DEPTH {
+                                return Err(ParseError::Syntax("nesting too deep".to_string()));
+                            }
+                            self.depth += 1;
+                            let tokens = self.scan(true);
+                            self.depth -= 1;
+                            let tokens = tokens?;
+                            parts.push(TemplatePart::Interpolation(tokens, self.position - 1));
+                            continue;
+                        },
+                        Some(c) => return Err(ParseError::Syntax(format!("invalid escape \\{} in string", c))),
                         None => return Err(ParseError::UnexpectedEof),
                     }
                     self.advance();
@@ -205,15 +410,45 @@ impl Lexer {
         Err(ParseError::UnexpectedEof)
     }
     
-    /// Read a number literal
-    fn read_number(&mut self) -> Result<Token, ParseError> {
-        let mut value = String::new();
+    /// Read the hex digits of a `\uXXXX` escape (after the `\u`), combining a UTF-16 surrogate pair
+    /// written as two escapes (`\ud83d\ude00`) into one character
+    fn read_unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.read_hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| ParseError::Syntax(format!("invalid unicode escape \\u{:04x}", high)));
+        }
+        
+        // A high surrogate must be followed by an escaped low surrogate
+        if self.current_char() != Some('\\') || self.input.get(self.position + 1) != Some(&'u') {
+            return Err(ParseError::Syntax(format!("unpaired surrogate \\u{:04x} in string", high)));
+        }
+        self.position += 2;
+        let low = self.read_hex4()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err(ParseError::Syntax(format!("unpaired surrogate \\u{:04x} in string", high)));
+        }
         
-        // Handle negative numbers
-        if self.current_char() == Some('-') {
-            value.push('-');
+        let code = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+        char::from_u32(code).ok_or_else(|| ParseError::Syntax("invalid unicode escape".to_string()))
+    }
+    
+    /// Read exactly four hex digits
+    fn read_hex4(&mut self) -> Result<u32, ParseError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = match self.current_char() {
+                Some(c) => c.to_digit(16).ok_or_else(|| ParseError::Syntax(format!("invalid hex digit '{}' in unicode escape", c)))?,
+                None => return Err(ParseError::UnexpectedEof),
+            };
+            code = code * 16 + digit;
             self.advance();
         }
+        Ok(code)
+    }
+    
+    /// Read a number literal
+    fn read_number(&mut self) -> Result<Token, ParseError> {
+        let mut value = String::new();
         
         // Read integer part
         while let Some(c) = self.current_char() {
@@ -247,12 +482,78 @@ impl Lexer {
         }
         
         // Parse the number
+        // A literal too large for a float would otherwise become infinity, and then null
         match value.parse::<f64>() {
-            Ok(n) => Ok(Token::NumberLiteral(n)),
+            Ok(n) if n.is_finite() => Ok(Token::NumberLiteral(n)),
+            Ok(_) => Err(ParseError::Syntax(format!("number literal {} is too large", value))),
             Err(_) => Err(ParseError::Syntax("invalid number format".to_string())),
         }
     }
     
+    /// Read a comparison operator (==, !=, <, <=, >, >=) or the assignment operator (=)
+    fn read_comparison(&mut self) -> Result<Token, ParseError> {
+        let first = self.current_char();
+        self.advance();
+        let followed_by_equals = self.current_char() == Some('=');
+        if followed_by_equals {
+            self.advance();
+        }
+        
+        match (first, followed_by_equals) {
+            (Some('='), true) => Ok(Token::Equal),
+            (Some('='), false) => Ok(Token::Assign),
+            (Some('!'), true) => Ok(Token::NotEqual),
+            (Some('<'), true) => Ok(Token::LessEqual),
+            (Some('<'), false) => Ok(Token::Less),
+            (Some('>'), true) => Ok(Token::GreaterEqual),
+            (Some('>'), false) => Ok(Token::Greater),
+            (Some(c), _) => Err(ParseError::Syntax(format!("unexpected character: {}", c))),
+            (None, _) => Err(ParseError::UnexpectedEof),
+        }
+    }
+    
+    /// Read a variable reference ($name)
+    fn read_variable(&mut self) -> Result<Token, ParseError> {
+        self.advance(); // Skip the dollar sign
+        let mut name = String::new();
+        
+        while let Some(c) = self.current_char() {
+            if c.is_alphanumeric() || c == '_' {
+                name.push(c);
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        
+        if name.is_empty() {
+            return Err(ParseError::Syntax("expected variable name after '$'".to_string()));
+        }
+        
+        Ok(Token::Variable(name))
+    }
+    
+    /// Read a format name (@name)
+    fn read_format(&mut self) -> Result<Token, ParseError> {
+        self.advance(); // Skip the at sign
+        let mut name = String::new();
+        
+        while let Some(c) = self.current_char() {
+            if c.is_alphanumeric() || c == '_' {
+                name.push(c);
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        
+        if name.is_empty() {
+            return Err(ParseError::Syntax("expected format name after '@'".to_string()));
+        }
+        
+        Ok(Token::Format(name))
+    }
+    
     /// Read an identifier or keyword
     fn read_identifier(&mut self) -> Result<Token, ParseError> {
         let mut value = String::new();
@@ -277,28 +578,192 @@ impl Lexer {
 }
 
 /// Represents a parsed query expression
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Expression {
     Identity,                          // .
+    Empty,                             // empty
     RecursiveDescent,                  // ..
+    RecurseDepth(Box<Expression>),     // recurse_depth(n)
+    ToStream,                          // tostream
+    Test(Box<Expression>, Box<Expression>), // test(regex), test(regex; flags)
+    Builtins,                          // builtins
+    Env,                               // env
+    Input,                             // input
+    Inputs,                            // inputs
+    Match(Box<Expression>, Box<Expression>), // match(regex), match(regex; flags)
+    Sub(Box<Expression>, Box<Expression>, Box<Expression>), // sub(regex; replacement; flags)
+    Type,                              // type
+    Walk(Box<Expression>),             // walk(f)
+    FromStream(Box<Expression>),       // fromstream(events)
     Property(String),                  // .property_name or ."property name"
     Index(i64),                        // .[0]
     Slice(Option<i64>, Option<i64>),   // .[1:3]
     Array(Vec<Expression>),            // [expr1, expr2, ...]
-    Object(Vec<(String, Expression)>), // {key1: expr1, key2: expr2, ...}
+    Object(Vec<(Expression, Expression)>), // {key1: expr1, (key_expr): expr2, ...}
     Pipe(Box<Expression>, Box<Expression>), // expr1 | expr2
-    Filter(Box<Expression>),           // .[] | select(...)
     ArrayIteration,                    // .[]
-    Select(Box<Expression>, String, Box<Expression>), // select(.field == "value")
     Map(Box<Expression>),              // map(expr)
     Keys,                              // keys
+    ToEntries,                         // to_entries
+    FromEntries,                       // from_entries
+    AsciiDowncase,                     // ascii_downcase
+    AsciiUpcase,                       // ascii_upcase
+    ToNumber,                          // tonumber
+    ToString,                          // tostring
     Length,                            // length
+    Utf8ByteLength,                    // utf8bytelength
+    Abs,                               // abs
+    GetPath(Box<Expression>),          // getpath(["a", 0, "b"])
+    SetPath(Box<Expression>, Box<Expression>), // setpath(["a", 0, "b"]; value)
+    Delete(Box<Expression>),           // del(.a, .items[].tmp)
+    Sort,                              // sort
+    SortBy(Box<Expression>),           // sort_by(.a), sort_by(.a, .b)
+    MinBy(Box<Expression>),            // min_by(.a)
+    MaxBy(Box<Expression>),            // max_by(.a)
+    Literal(Value),                    // "string", 42, true, false, null
+    Variable(String),                  // $name
+    StringInterp(Vec<StringPart>),     // "Hello \(.name)"
+    Format(String),                    // @csv, @tsv, @base64, ...
+    Join(Box<Expression>),             // join(separator)
+    Split(Box<Expression>),            // split(separator)
+    First(Box<Expression>),            // first(expr)
+    Last(Box<Expression>),             // last(expr)
+    Range(Box<Expression>, Box<Expression>), // range(upto) or range(from; upto)
+    While(Box<Expression>, Box<Expression>), // while(cond; update)
+    Until(Box<Expression>, Box<Expression>), // until(cond; update)
+    Repeat(Box<Expression>),           // repeat(f)
+    Limit(Box<Expression>, Box<Expression>), // limit(n; f)
+    If(Box<Expression>, Box<Expression>, Box<Expression>), // if cond then expr1 else expr2 end
+    Binding(Box<Expression>, Pattern, Box<Expression>), // expr as $name | body, expr as [$a, $b] | body
+    BindingAlternatives(Box<Expression>, Vec<Pattern>, Box<Expression>), // expr as {a: $x} ?// [$x] | body
+    Reduce(Box<Expression>, Pattern, Box<Expression>, Box<Expression>), // reduce .[] as $x (0; . + $x)
+    Foreach(Box<Expression>, Pattern, Box<Expression>, Box<Expression>, Option<Box<Expression>>), // foreach .[] as $x (0; . + $x; [$x, .])
+    FunctionDef(Box<FunctionDef>, Box<Expression>), // def name(params): body; expr
+    FunctionCall(String, Vec<Expression>), // name(arg1; arg2), a user-defined function
+    Arithmetic(Box<Expression>, String, Box<Expression>), // expr1 + expr2, expr1 * expr2, ...
+    Comparison(Box<Expression>, String, Box<Expression>), // expr1 == expr2, expr1 < expr2, ...
+    Comma(Box<Expression>, Box<Expression>), // expr1, expr2
+    Alternative(Box<Expression>, Box<Expression>), // expr1 // expr2
+    And(Box<Expression>, Box<Expression>), // expr1 and expr2
+    Or(Box<Expression>, Box<Expression>), // expr1 or expr2
+    Negate(Box<Expression>),           // -expr
+    Assign(Box<Expression>, Box<Expression>), // path = value
+    Update(Box<Expression>, Box<Expression>), // path |= f
+}
+
+/// A destructuring pattern binding variables from a value
+#[derive(Debug, Clone, Serialize)]
+pub enum Pattern {
+    Variable(String),                  // $name
+    Array(Vec<Pattern>),               // [$a, $b]
+    Object(Vec<(String, Pattern)>),    // {key: $v}, {$name}
+}
+
+/// A piece of an interpolated string: literal text or an expression whose outputs are inserted
+#[derive(Debug, Clone, Serialize)]
+pub enum StringPart {
+    Literal(String),
+    Expr(Expression),
 }
 
+/// A user-defined function: `def name(params): body;`
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionDef {
+    pub name: String,
+    /// Parameter names; filter parameters are bare, value parameters keep their leading `$`
+    pub params: Vec<String>,
+    pub body: Expression,
+}
+
+/// Words with a meaning of their own in queries, which are only field names when written as `.word`
+pub const KEYWORDS: &[&str] = &["as", "def", "if", "then", "elif", "else", "end", "and", "or", "reduce", "foreach"];
+
+/// A builtin function: its name, its arity and how to build its expression, taking each argument in turn
+pub type Builtin = (&'static str, usize, fn(&mut dyn FnMut() -> Box<Expression>) -> Expression);
+
+/// Every builtin function, which is both how calls are parsed and what the `builtins` query lists
+pub const BUILTINS: &[Builtin] = &[
+    ("empty", 0, |_| Expression::Empty),
+    ("keys", 0, |_| Expression::Keys),
+    ("type", 0, |_| Expression::Type),
+    ("to_entries", 0, |_| Expression::ToEntries),
+    ("from_entries", 0, |_| Expression::FromEntries),
+    // with_entries(f) is `to_entries | map(f) | from_entries`
+    ("with_entries", 1, |arg| Expression::Pipe(
+        Box::new(Expression::ToEntries),
+        Box::new(Expression::Pipe(Box::new(Expression::Map(arg())), Box::new(Expression::FromEntries))),
+    )),
+    ("ascii_downcase", 0, |_| Expression::AsciiDowncase),
+    ("ascii_upcase", 0, |_| Expression::AsciiUpcase),
+    ("tonumber", 0, |_| Expression::ToNumber),
+    ("tostring", 0, |_| Expression::ToString),
+    ("length", 0, |_| Expression::Length),
+    ("utf8bytelength", 0, |_| Expression::Utf8ByteLength),
+    ("abs", 0, |_| Expression::Abs),
+    ("getpath", 1, |arg| Expression::GetPath(arg())),
+    ("setpath", 2, |arg| Expression::SetPath(arg(), arg())),
+    ("del", 1, |arg| Expression::Delete(arg())),
+    ("sort", 0, |_| Expression::Sort),
+    ("sort_by", 1, |arg| Expression::SortBy(arg())),
+    ("min_by", 1, |arg| Expression::MinBy(arg())),
+    ("max_by", 1, |arg| Expression::MaxBy(arg())),
+    ("min", 0, |_| Expression::MinBy(Box::new(Expression::Identity))),
+    ("max", 0, |_| Expression::MaxBy(Box::new(Expression::Identity))),
+    ("map", 1, |arg| Expression::Map(arg())),
+    ("walk", 1, |arg| Expression::Walk(arg())),
+    // select(f) is `if f then . else empty end`, so it filters lazily as values stream through
+    ("select", 1, |arg| Expression::If(arg(), Box::new(Expression::Identity), Box::new(Expression::Empty))),
+    ("join", 1, |arg| Expression::Join(arg())),
+    ("split", 1, |arg| Expression::Split(arg())),
+    ("first", 0, |_| Expression::Index(0)),
+    ("last", 0, |_| Expression::Index(-1)),
+    ("first", 1, |arg| Expression::First(arg())),
+    ("last", 1, |arg| Expression::Last(arg())),
+    ("range", 1, |arg| Expression::Range(Box::new(Expression::Literal(Value::from(0))), arg())),
+    ("range", 2, |arg| Expression::Range(arg(), arg())),
+    ("while", 2, |arg| Expression::While(arg(), arg())),
+    ("until", 2, |arg| Expression::Until(arg(), arg())),
+    ("repeat", 1, |arg| Expression::Repeat(arg())),
+    ("recurse", 0, |_| Expression::RecursiveDescent),
+    // recurse(f) emits its input and then recurses on each output of f, which is what repeat(f) does
+    ("recurse", 1, |arg| Expression::Repeat(arg())),
+    ("recurse_depth", 1, |arg| Expression::RecurseDepth(arg())),
+    ("tostream", 0, |_| Expression::ToStream),
+    ("fromstream", 1, |arg| Expression::FromStream(arg())),
+    ("test", 1, |arg| Expression::Test(arg(), Box::new(Expression::Literal(Value::Null)))),
+    ("test", 2, |arg| Expression::Test(arg(), arg())),
+    ("match", 1, |arg| Expression::Match(arg(), Box::new(Expression::Literal(Value::Null)))),
+    ("match", 2, |arg| Expression::Match(arg(), arg())),
+    ("sub", 2, |arg| Expression::Sub(arg(), arg(), Box::new(Expression::Literal(Value::Null)))),
+    ("sub", 3, |arg| Expression::Sub(arg(), arg(), arg())),
+    // gsub is sub with the `g` flag added, replacing every match rather than only the first
+    ("gsub", 2, |arg| Expression::Sub(arg(), arg(), Box::new(Expression::Literal(Value::from("g"))))),
+    ("gsub", 3, |arg| Expression::Sub(arg(), arg(), Box::new(Expression::Arithmetic(
+        arg(), "+".to_string(), Box::new(Expression::Literal(Value::from("g"))),
+    )))),
+    ("limit", 2, |arg| Expression::Limit(arg(), arg())),
+    ("input", 0, |_| Expression::Input),
+    ("inputs", 0, |_| Expression::Inputs),
+    ("builtins", 0, |_| Expression::Builtins),
+    ("env", 0, |_| Expression::Env),
+];
+
+/// Names of the supported `@format` filters
+pub const FORMATS: &[&str] = &["text", "json", "csv", "tsv", "html", "base64", "uri"];
+
+/// Maximum nesting of brackets, braces and calls a query may have before it is rejected
+pub const MAX_NESTING_DEPTH: usize = 128;
+
 /// Parser for query expressions
 pub struct Parser {
     tokens: Vec<Token>,
     position: usize,
+    /// Current nesting depth of expressions being parsed
+    depth: usize,
+    /// Query positions of the tokens (and of the end of the query), used to locate errors
+    columns: Vec<usize>,
+    /// Names and arities of the user-defined functions and filter parameters in scope
+    functions: Vec<(String, usize)>,
 }
 
 impl Parser {
@@ -307,12 +772,35 @@ impl Parser {
         Parser {
             tokens,
             position: 0,
+            depth: 0,
+            columns: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+    
+    /// Create a parser from tokens paired with their query positions, so errors report where they occurred
+    pub fn with_columns(tokens: Vec<(Token, usize)>, end: usize) -> Self {
+        let (tokens, mut columns): (Vec<Token>, Vec<usize>) = tokens.into_iter().unzip();
+        columns.push(end);
+        
+        Parser {
+            columns,
+            ..Parser::new(tokens)
         }
     }
     
-    /// Parse the tokens into an expression
+    /// Parse the tokens into an expression, which must use all of them: tokens left over after a
+    /// complete expression (such as the `.b` in `.a .b`) are an error rather than being ignored
     pub fn parse(&mut self) -> Result<Expression, ParseError> {
-        self.parse_expression()
+        let expr = self.parse_expression().and_then(|expr| match self.current_token() {
+            None => Ok(expr),
+            Some(token) => Err(ParseError::UnexpectedToken(format!("'{}' after the end of the expression", token))),
+        });
+        
+        expr.map_err(|e| match self.columns.get(self.position).or(self.columns.last()) {
+            Some(&column) => e.at(column),
+            None => e,
+        })
     }
     
     /// Get the current token or None if at end of tokens
@@ -329,551 +817,743 @@ impl Parser {
         self.position += 1;
     }
     
+    /// The field name of a `.name` or `."name"` access starting at the current token. A keyword after
+    /// a separate dot (`. as $x`) is not a field, but one written right after it (`.end`) is.
+    fn field_after_dot(&self) -> Option<String> {
+        if self.current_token() != Some(&Token::Dot) {
+            return None;
+        }
+        
+        match self.tokens.get(self.position + 1) {
+            Some(Token::StringLiteral(name)) => Some(name.clone()),
+            Some(Token::Identifier(name)) => {
+                let adjacent = match (self.columns.get(self.position), self.columns.get(self.position + 1)) {
+                    (Some(dot), Some(field)) => *field == dot + 1,
+                    _ => false,
+                };
+                
+                if KEYWORDS.contains(&name.as_str()) && !adjacent {
+                    None
+                } else {
+                    Some(name.clone())
+                }
+            },
+            _ => None,
+        }
+    }
+    
+    /// Check whether the current token is the given keyword
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.current_token(), Some(Token::Identifier(name)) if name == keyword)
+    }
+    
     /// Parse an expression
     fn parse_expression(&mut self) -> Result<Expression, ParseError> {
-        // Start with a simple expression
+        self.parse_binary(PIPE_PRECEDENCE)
+    }
+    
+    /// Run a parse step one level deeper, failing cleanly instead of overflowing the stack on deeply nested
+    /// queries. Every recursion in the parser passes through a term, a binding, a definition, an `elif` or a
+    /// pattern, and each of those is parsed through here.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return Err(ParseError::Syntax("nesting too deep".to_string()));
+        }
+        
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+    
+    /// Parse a chain of binary operators by precedence climbing, taking only operators that bind at least
+    /// as tightly as `min_precedence`. All operators group to the left, so long chains are parsed in a loop.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expression, ParseError> {
+        // A definition covers the whole rest of the expression
+        if self.at_keyword("def") {
+            return self.nested(Self::parse_definition);
+        }
+        
         let mut expr = self.parse_simple_expression()?;
         
-        // Check for pipe operator
-        while let Some(Token::Pipe) = self.current_token() {
+        while let Some((precedence, op)) = self.current_token().and_then(binary_operator) {
+            if precedence < min_precedence {
+                break;
+            }
             self.advance();
-            let right = self.parse_simple_expression()?;
-            expr = Expression::Pipe(Box::new(expr), Box::new(right));
+            
+            let right = self.parse_binary(precedence + 1)?;
+            expr = match op {
+                "|" => Expression::Pipe(Box::new(expr), Box::new(right)),
+                "," => Expression::Comma(Box::new(expr), Box::new(right)),
+                "//" => Expression::Alternative(Box::new(expr), Box::new(right)),
+                "=" => Expression::Assign(Box::new(expr), Box::new(right)),
+                "|=" => Expression::Update(Box::new(expr), Box::new(right)),
+                "or" => Expression::Or(Box::new(expr), Box::new(right)),
+                "and" => Expression::And(Box::new(expr), Box::new(right)),
+                "==" | "!=" | "<" | "<=" | ">" | ">=" => Expression::Comparison(Box::new(expr), op.to_string(), Box::new(right)),
+                _ => Expression::Arithmetic(Box::new(expr), op.to_string(), Box::new(right)),
+            };
         }
         
         Ok(expr)
     }
     
-    /// Parse a simple expression (without pipes)
-    fn parse_simple_expression(&mut self) -> Result<Expression, ParseError> {
-        match self.current_token() {
-            Some(Token::Dot) => {
-                self.advance();
-                
-                // Check if it's just the identity operator
-                if self.current_token().is_none() || 
-                   matches!(self.current_token(), Some(Token::Pipe) | Some(Token::Comma) | Some(Token::RightBracket) | Some(Token::RightBrace)) {
-                    return Ok(Expression::Identity);
+    /// Parse a complete expression from a separate run of tokens (such as an interpolation),
+    /// seeing the same functions as the surrounding query
+    fn parse_nested(&self, tokens: Vec<(Token, usize)>, end: usize) -> Result<Expression, ParseError> {
+        let mut parser = Parser::with_columns(tokens, end);
+        parser.depth = self.depth;
+        parser.functions = self.functions.clone();
+        
+        parser.parse()
+    }
+    
+    /// Parse a function definition `def name(params): body;` and the expression it is visible in
+    fn parse_definition(&mut self) -> Result<Expression, ParseError> {
+        self.advance();
+        
+        let name = match self.current_token() {
+            Some(Token::Identifier(name)) => name.clone(),
+            _ => return Err(ParseError::Syntax("expected function name after def".to_string())),
+        };
+        self.advance();
+        
+        // Parse parameters, separated by semicolons: def name(f; $x)
+        let mut params = Vec::new();
+        if let Some(Token::LeftParen) = self.current_token() {
+            self.advance();
+            
+            loop {
+                match self.current_token() {
+                    Some(Token::Identifier(param)) => params.push(param.clone()),
+                    Some(Token::Variable(param)) => params.push(format!("${}", param)),
+                    _ => return Err(ParseError::Syntax(format!("expected parameter name in definition of {}", name))),
                 }
+                self.advance();
                 
-                // Check for property access
                 match self.current_token() {
-                    Some(Token::Identifier(name)) => {
-                        let name = name.clone();
-                        self.advance();
-                        
-                        // Check for nested property access (.address.city)
-                        let mut expr = Expression::Property(name);
-                        while let Some(Token::Dot) = self.current_token() {
-                            self.advance();
-                            match self.current_token() {
-                                Some(Token::Identifier(nested_name)) => {
-                                    let nested_name = nested_name.clone();
-                                    self.advance();
-                                    expr = Expression::Pipe(
-                                        Box::new(expr),
-                                        Box::new(Expression::Property(nested_name))
-                                    );
-                                },
-                                Some(Token::StringLiteral(nested_name)) => {
-                                    let nested_name = nested_name.clone();
-                                    self.advance();
-                                    expr = Expression::Pipe(
-                                        Box::new(expr),
-                                        Box::new(Expression::Property(nested_name))
-                                    );
-                                },
-                                _ => break,
-                            }
-                        }
-                        
-                        Ok(expr)
-                    },
-                    Some(Token::StringLiteral(name)) => {
-                        let name = name.clone();
-                        self.advance();
-                        
-                        // Check for nested property access (."address"."city")
-                        let mut expr = Expression::Property(name);
-                        while let Some(Token::Dot) = self.current_token() {
-                            self.advance();
-                            match self.current_token() {
-                                Some(Token::Identifier(nested_name)) => {
-                                    let nested_name = nested_name.clone();
-                                    self.advance();
-                                    expr = Expression::Pipe(
-                                        Box::new(expr),
-                                        Box::new(Expression::Property(nested_name))
-                                    );
-                                },
-                                Some(Token::StringLiteral(nested_name)) => {
-                                    let nested_name = nested_name.clone();
-                                    self.advance();
-                                    expr = Expression::Pipe(
-                                        Box::new(expr),
-                                        Box::new(Expression::Property(nested_name))
-                                    );
-                                },
-                                _ => break,
-                            }
-                        }
-                        
-                        Ok(expr)
-                    },
-                    Some(Token::LeftBracket) => {
+                    Some(Token::Semicolon) => self.advance(),
+                    Some(Token::RightParen) => {
                         self.advance();
-                        
-                        // Parse array index or slice
-                        match self.current_token() {
-                            // Handle array iteration .[]
-                            Some(Token::RightBracket) => {
-                                self.advance();
-                                Ok(Expression::ArrayIteration)
-                            },
-                            Some(Token::NumberLiteral(n)) => {
-                                let index = *n as i64;
-                                self.advance();
-                                
-                                if let Some(Token::Colon) = self.current_token() {
-                                    self.advance();
-                                    
-                                    // Parse end of slice
-                                    let end = match self.current_token() {
-                                        Some(Token::NumberLiteral(n)) => {
-                                            let end = *n as i64;
-                                            self.advance();
-                                            Some(end)
-                                        },
-                                        _ => None,
-                                    };
-                                    
-                                    self.expect_token(&Token::RightBracket)?;
-                                    
-                                    // Check for nested property access (.[0].name)
-                                    let mut expr = Expression::Slice(Some(index), end);
-                                    if let Some(Token::Dot) = self.current_token() {
-                                        self.advance();
-                                        if let Some(Token::Identifier(nested_name)) = self.current_token() {
-                                            let nested_name = nested_name.clone();
-                                            self.advance();
-                                            expr = Expression::Pipe(
-                                                Box::new(expr),
-                                                Box::new(Expression::Property(nested_name))
-                                            );
-                                        }
-                                    }
-                                    
-                                    Ok(expr)
-                                } else {
-                                    self.expect_token(&Token::RightBracket)?;
-                                    
-                                    // Check for nested property access (.[0].name)
-                                    let mut expr = Expression::Index(index);
-                                    while let Some(Token::Dot) = self.current_token() {
-                                        self.advance();
-                                        match self.current_token() {
-                                            Some(Token::Identifier(nested_name)) => {
-                                                let nested_name = nested_name.clone();
-                                                self.advance();
-                                                expr = Expression::Pipe(
-                                                    Box::new(expr),
-                                                    Box::new(Expression::Property(nested_name))
-                                                );
-                                            },
-                                            Some(Token::StringLiteral(nested_name)) => {
-                                                let nested_name = nested_name.clone();
-                                                self.advance();
-                                                expr = Expression::Pipe(
-                                                    Box::new(expr),
-                                                    Box::new(Expression::Property(nested_name))
-                                                );
-                                            },
-                                            _ => break,
-                                        }
-                                    }
-                                    
-                                    Ok(expr)
-                                }
-                            },
-                            Some(Token::Colon) => {
-                                self.advance();
-                                
-                                // Parse end of slice
-                                let end = match self.current_token() {
-                                    Some(Token::NumberLiteral(n)) => {
-                                        let end = *n as i64;
-                                        self.advance();
-                                        Some(end)
-                                    },
-                                    _ => None,
-                                };
-                                
-                                self.expect_token(&Token::RightBracket)?;
-                                Ok(Expression::Slice(None, end))
-                            },
-                            _ => {
-                                Err(ParseError::Syntax("expected number, colon, or closing bracket in array access".to_string()))
-                            }
-                        }
+                        break;
                     },
-                    _ => {
-                        Err(ParseError::Syntax("expected property name or array access after dot".to_string()))
-                    }
+                    _ => return Err(ParseError::Syntax(format!("expected ';' or ')' in parameters of {}", name))),
                 }
+            }
+        }
+        self.expect_token(&Token::Colon)?;
+        
+        // The body sees the function itself (for recursion) and its parameters as zero-arity filters
+        let scope = self.functions.len();
+        self.functions.push((name.clone(), params.len()));
+        for param in &params {
+            self.functions.push((param.trim_start_matches('$').to_string(), 0));
+        }
+        let body = self.parse_expression()?;
+        self.functions.truncate(scope + 1);
+        self.expect_token(&Token::Semicolon)?;
+        
+        let rest = self.parse_expression()?;
+        self.functions.truncate(scope);
+        
+        Ok(Expression::FunctionDef(Box::new(FunctionDef { name, params, body }), Box::new(rest)))
+    }
+    
+    /// Parse a conditional `if cond then expr (elif cond then expr)* (else expr)? end`, after the `if`
+    fn parse_conditional(&mut self) -> Result<Expression, ParseError> {
+        let cond = self.parse_expression()?;
+        if !self.at_keyword("then") {
+            return Err(ParseError::Syntax("expected then after if condition".to_string()));
+        }
+        self.advance();
+        let then_branch = self.parse_expression()?;
+        
+        let else_branch = if self.at_keyword("elif") {
+            self.advance();
+            return Ok(Expression::If(Box::new(cond), Box::new(then_branch), Box::new(self.nested(Self::parse_conditional)?)));
+        } else if self.at_keyword("else") {
+            self.advance();
+            self.parse_expression()?
+        } else {
+            // Without an else branch the input passes through unchanged
+            Expression::Identity
+        };
+        
+        if !self.at_keyword("end") {
+            return Err(ParseError::Syntax("expected end after if expression".to_string()));
+        }
+        self.advance();
+        
+        Ok(Expression::If(Box::new(cond), Box::new(then_branch), Box::new(else_branch)))
+    }
+    
+    /// Parse `reduce source as $x (init; update)` or `foreach source as $x (init; update; extract)`, after
+    /// the keyword. The source is a single term, such as `.[]` or `inputs`.
+    fn parse_fold(&mut self, keyword: &str) -> Result<Expression, ParseError> {
+        let source = self.nested(Self::parse_term)?;
+        let source = self.parse_postfix(source)?;
+        if !self.at_keyword("as") {
+            return Err(ParseError::Syntax(format!("expected as after the source of {}", keyword)));
+        }
+        self.advance();
+        let pattern = self.nested(Self::parse_pattern)?;
+        
+        self.expect_token(&Token::LeftParen)?;
+        let init = self.parse_expression()?;
+        self.expect_token(&Token::Semicolon)?;
+        let update = self.parse_expression()?;
+        let extract = match self.current_token() {
+            Some(Token::Semicolon) if keyword == "foreach" => {
+                self.advance();
+                Some(Box::new(self.parse_expression()?))
             },
-            Some(Token::DotDot) => {
+            _ => None,
+        };
+        self.expect_token(&Token::RightParen)?;
+        
+        let (source, init, update) = (Box::new(source), Box::new(init), Box::new(update));
+        Ok(match extract {
+            _ if keyword == "reduce" => Expression::Reduce(source, pattern, init, update),
+            extract => Expression::Foreach(source, pattern, init, update, extract),
+        })
+    }
+    
+    /// Parse a number literal with an optional leading minus sign
+    fn parse_signed_number(&mut self) -> Result<f64, ParseError> {
+        let negative = if let Some(Token::Minus) = self.current_token() {
+            self.advance();
+            true
+        } else {
+            false
+        };
+        
+        match self.current_token() {
+            Some(Token::NumberLiteral(n)) => {
+                let n = *n;
                 self.advance();
-                Ok(Expression::RecursiveDescent)
+                Ok(if negative { -n } else { n })
+            },
+            Some(token) => Err(ParseError::UnexpectedToken(format!("expected number, got {:?}", token))),
+            None => Err(ParseError::UnexpectedEof),
+        }
+    }
+    
+    /// Parse a simple expression (without pipes or operators): a term followed by any postfix accesses,
+    /// optionally bound to variables for the rest of the pipeline with `term as $name | body`
+    fn parse_simple_expression(&mut self) -> Result<Expression, ParseError> {
+        let term = self.nested(Self::parse_term)?;
+        let term = self.parse_postfix(term)?;
+        
+        if self.at_keyword("as") {
+            self.nested(|parser| parser.parse_binding(term))
+        } else {
+            Ok(term)
+        }
+    }
+    
+    /// Parse `as pattern | body` after the term whose outputs are bound, where the pattern may be a chain
+    /// of alternatives to try in turn: `as {a: $x} ?// [$x] | body`
+    fn parse_binding(&mut self, term: Expression) -> Result<Expression, ParseError> {
+        self.advance();
+        
+        let mut patterns = vec![self.nested(Self::parse_pattern)?];
+        while self.current_token() == Some(&Token::Question) && self.tokens.get(self.position + 1) == Some(&Token::SlashSlash) {
+            self.advance();
+            self.advance();
+            patterns.push(self.nested(Self::parse_pattern)?);
+        }
+        self.expect_token(&Token::Pipe)?;
+        let body = self.parse_expression()?;
+        
+        if patterns.len() == 1 {
+            Ok(Expression::Binding(Box::new(term), patterns.remove(0), Box::new(body)))
+        } else {
+            Ok(Expression::BindingAlternatives(Box::new(term), patterns, Box::new(body)))
+        }
+    }
+    
+    /// Parse a destructuring pattern: `$name`, `[$a, $b]` or `{key: $v, $name, "str": [$x]}`
+    fn parse_pattern(&mut self) -> Result<Pattern, ParseError> {
+        match self.current_token() {
+            Some(Token::Variable(name)) => {
+                let name = name.clone();
+                self.advance();
+                Ok(Pattern::Variable(name))
             },
             Some(Token::LeftBracket) => {
                 self.advance();
                 let mut elements = Vec::new();
                 
-                // Parse array elements
-                if let Some(Token::RightBracket) = self.current_token() {
-                    self.advance();
-                    return Ok(Expression::Array(elements));
-                }
-                
                 loop {
-                    let element = self.parse_expression()?;
-                    elements.push(element);
+                    elements.push(self.nested(Self::parse_pattern)?);
                     
                     match self.current_token() {
-                        Some(Token::Comma) => {
-                            self.advance();
-                        },
+                        Some(Token::Comma) => self.advance(),
                         Some(Token::RightBracket) => {
                             self.advance();
                             break;
                         },
-                        _ => {
-                            return Err(ParseError::Syntax("expected comma or closing bracket in array".to_string()));
-                        }
+                        _ => return Err(ParseError::Syntax("expected comma or closing bracket in array pattern".to_string())),
                     }
                 }
                 
-                Ok(Expression::Array(elements))
+                Ok(Pattern::Array(elements))
             },
             Some(Token::LeftBrace) => {
                 self.advance();
-                let mut properties = Vec::new();
-                
-                // Parse object properties
-                if let Some(Token::RightBrace) = self.current_token() {
-                    self.advance();
-                    return Ok(Expression::Object(properties));
-                }
+                let mut entries = Vec::new();
                 
                 loop {
-                    // Parse property key
-                    let key = match self.current_token() {
-                        Some(Token::Identifier(name)) => {
+                    match self.current_token() {
+                        // {$name} binds .name to $name; {$name: pattern} also destructures it
+                        Some(Token::Variable(name)) => {
                             let name = name.clone();
                             self.advance();
-                            name
+                            entries.push((name.clone(), Pattern::Variable(name.clone())));
+                            
+                            if let Some(Token::Colon) = self.current_token() {
+                                self.advance();
+                                entries.push((name, self.nested(Self::parse_pattern)?));
+                            }
                         },
-                        Some(Token::StringLiteral(name)) => {
-                            let name = name.clone();
+                        Some(Token::Identifier(key)) | Some(Token::StringLiteral(key)) => {
+                            let key = key.clone();
                             self.advance();
-                            name
+                            self.expect_token(&Token::Colon)?;
+                            entries.push((key, self.nested(Self::parse_pattern)?));
                         },
-                        _ => {
-                            return Err(ParseError::Syntax("expected property name in object".to_string()));
-                        }
-                    };
-                    
-                    // Expect colon
-                    self.expect_token(&Token::Colon)?;
-                    
-                    // Parse property value
-                    let value = self.parse_expression()?;
-                    properties.push((key, value));
+                        _ => return Err(ParseError::Syntax("expected key or variable in object pattern".to_string())),
+                    }
                     
                     match self.current_token() {
-                        Some(Token::Comma) => {
-                            self.advance();
-                        },
+                        Some(Token::Comma) => self.advance(),
                         Some(Token::RightBrace) => {
                             self.advance();
                             break;
                         },
-                        _ => {
-                            return Err(ParseError::Syntax("expected comma or closing brace in object".to_string()));
-                        }
+                        _ => return Err(ParseError::Syntax("expected comma or closing brace in object pattern".to_string())),
                     }
                 }
                 
-                Ok(Expression::Object(properties))
+                Ok(Pattern::Object(entries))
             },
-            _ => {
-                Err(ParseError::Syntax("unexpected token".to_string()))
-            }
+            _ => Err(ParseError::Syntax("expected variable, array or object pattern after as".to_string())),
         }
     }
     
-    /// Expect a specific token and advance if found
-    fn expect_token(&mut self, expected: &Token) -> Result<(), ParseError> {
+    /// Parse a single term such as `.name`, a literal, a function call or a constructor
+    fn parse_term(&mut self) -> Result<Expression, ParseError> {
         match self.current_token() {
-            Some(token) if token == expected => {
-                self.advance();
-                Ok(())
+            Some(Token::Dot) => {
+                // Check for property or array access, otherwise it's just the identity operator
+                if let Some(name) = self.field_after_dot() {
+                    self.advance();
+                    self.advance();
+                    return Ok(Expression::Property(name));
+                }
+                self.advance();
+                
+                match self.current_token() {
+                    Some(Token::LeftBracket) => self.parse_bracket_access(),
+                    _ => Ok(Expression::Identity),
+                }
             },
-            Some(token) => {
-                Err(ParseError::UnexpectedToken(format!("expected {:?}, got {:?}", expected, token)))
+            Some(Token::DotDot) => {
+                self.advance();
+                Ok(Expression::RecursiveDescent)
             },
-            None => {
-                Err(ParseError::UnexpectedEof)
-            }
-        }
-    }
-}
-
-/// Find the position of the matching closing parenthesis
-fn find_matching_paren(s: &str) -> Option<usize> {
-    let chars: Vec<char> = s.chars().collect();
-    let mut depth = 0;
-    
-    for (i, &c) in chars.iter().enumerate() {
-        match c {
-            '(' => depth += 1,
-            ')' => {
-                depth -= 1;
-                if depth == 0 {
-                    return Some(i);
+            Some(Token::StringLiteral(s)) => {
+                let s = s.clone();
+                self.advance();
+                Ok(Expression::Literal(Value::String(s)))
+            },
+            Some(Token::StringTemplate(parts)) => {
+                let parts = parts.clone();
+                self.advance();
+                self.parse_string_template(parts)
+            },
+            Some(Token::Minus) if !matches!(self.tokens.get(self.position + 1), Some(Token::NumberLiteral(_))) => {
+                // Unary minus binds tighter than any binary operator: -.a + 1 is (-.a) + 1
+                self.advance();
+                let term = self.nested(Self::parse_term)?;
+                Ok(Expression::Negate(Box::new(self.parse_postfix(term)?)))
+            },
+            Some(Token::NumberLiteral(_)) | Some(Token::Minus) => {
+                let n = self.parse_signed_number()?;
+                Ok(Expression::Literal(number_to_value(n)))
+            },
+            Some(Token::BoolLiteral(b)) => {
+                let b = *b;
+                self.advance();
+                Ok(Expression::Literal(Value::Bool(b)))
+            },
+            Some(Token::Null) => {
+                self.advance();
+                Ok(Expression::Literal(Value::Null))
+            },
+            Some(Token::Variable(name)) => {
+                let name = name.clone();
+                self.advance();
+                Ok(Expression::Variable(name))
+            },
+            Some(Token::Format(name)) => {
+                let name = name.clone();
+                if !FORMATS.contains(&name.as_str()) {
+                    return Err(ParseError::Syntax(format!("{} is not a valid format", name)));
                 }
+                self.advance();
+                self.parse_format(name)
             },
-            _ => {}
+            Some(Token::Identifier(name)) => {
+                let name = name.clone();
+                self.advance();
+                self.parse_call(name)
+            },
+            Some(Token::LeftParen) => {
+                // A parenthesized expression groups a whole pipeline into a single term
+                self.advance();
+                let expr = self.parse_expression()?;
+                self.expect_token(&Token::RightParen)?;
+                Ok(expr)
+            },
+            Some(Token::LeftBracket) => self.parse_array_constructor(),
+            Some(Token::LeftBrace) => self.parse_object_constructor(),
+            _ => {
+                Err(ParseError::Syntax("unexpected token".to_string()))
+            }
         }
     }
     
-    None
-}
-
-/// Parse a query string into an expression
-pub fn parse_query(query: &str) -> Result<Expression, ParseError> {
-    // Handle string literals in quotes
-    if query.starts_with('"') && query.ends_with('"') && query.len() >= 2 {
-        let content = &query[1..query.len()-1];
-        return Ok(Expression::Property(content.to_string()));
+    /// Parse the parts of a string template into literal text and interpolated expressions
+    fn parse_string_template(&mut self, parts: Vec<TemplatePart>) -> Result<Expression, ParseError> {
+        let parts = parts.into_iter()
+            .map(|part| match part {
+                TemplatePart::Text(text) => Ok(StringPart::Literal(text)),
+                TemplatePart::Interpolation(tokens, end) => Ok(StringPart::Expr(self.parse_nested(tokens, end)?)),
+            })
+            .collect::<Result<Vec<_>, ParseError>>()?;
+        Ok(Expression::StringInterp(parts))
     }
     
-    // Special case for array iteration like '.resources[]'
-    if query.ends_with("[]") {
-        let base_part = &query[0..query.len()-2];
-        if !base_part.is_empty() {
-            let base_expr = parse_query(base_part)?;
-            return Ok(Expression::Pipe(
-                Box::new(base_expr),
-                Box::new(Expression::ArrayIteration)
-            ));
+    /// Parse the rest of a format such as `@csv`, which may be applied to a following string template
+    fn parse_format(&mut self, name: String) -> Result<Expression, ParseError> {
+        // A format followed by a string applies to each interpolated value: @uri "q=\(.q)"
+        match self.parse_term_if_string()? {
+            Some(Expression::StringInterp(parts)) => {
+                let parts = parts.into_iter()
+                    .map(|part| match part {
+                        StringPart::Expr(expr) => StringPart::Expr(Expression::Pipe(Box::new(expr), Box::new(Expression::Format(name.clone())))),
+                        literal => literal,
+                    })
+                    .collect();
+                Ok(Expression::StringInterp(parts))
+            },
+            Some(literal) => Ok(literal),
+            None => Ok(Expression::Format(name)),
         }
     }
     
-    // Special case for map operation like '.resources[] | map(.type)'
-    if query.contains(" | map(") && query.contains(")") {
-        if let Some(pipe_pos) = query.find(" | map(") {
-            let left_part = &query[0..pipe_pos];
-            let map_part = &query[pipe_pos + 7..];
+    /// Parse the rest of a keyword expression or of a function call with arguments separated by semicolons
+    fn parse_call(&mut self, name: String) -> Result<Expression, ParseError> {
+        if name == "if" {
+            return self.parse_conditional();
+        }
+        if name == "reduce" || name == "foreach" {
+            return self.parse_fold(&name);
+        }
+        
+        // Parse call arguments, separated by semicolons: name(arg1; arg2)
+        let mut args = Vec::new();
+        if let Some(Token::LeftParen) = self.current_token() {
+            self.advance();
             
-            if map_part.ends_with(')') {
-                let map_expr_str = &map_part[0..map_part.len()-1];
-                
-                // Parse the left part of the pipe
-                let outer_expr = parse_query(left_part)?;
-                
-                // Parse the map expression
-                let map_expr = parse_query(map_expr_str)?;
+            loop {
+                args.push(self.parse_expression()?);
                 
-                return Ok(Expression::Pipe(
-                    Box::new(outer_expr),
-                    Box::new(Expression::Map(Box::new(map_expr)))
-                ));
+                match self.current_token() {
+                    Some(Token::Semicolon) => {
+                        self.advance();
+                    },
+                    Some(Token::RightParen) => {
+                        self.advance();
+                        break;
+                    },
+                    _ => {
+                        return Err(ParseError::Syntax(format!("expected ';' or ')' in arguments to {}", name)));
+                    }
+                }
             }
         }
+        
+        // User-defined functions shadow builtins with the same name and arity
+        if self.functions.iter().any(|(defined, arity)| *defined == name && *arity == args.len()) {
+            Ok(Expression::FunctionCall(name, args))
+        } else {
+            self.parse_function(&name, args)
+        }
     }
     
-    // Special case for keys operation like '.resources | keys'
-    if query.contains(" | keys") {
-        if let Some(pipe_pos) = query.find(" | keys") {
-            let left_part = &query[0..pipe_pos];
+    /// Parse an array constructor `[expr]`, which collects every output of `expr` (such as `[.a, .b]`)
+    fn parse_array_constructor(&mut self) -> Result<Expression, ParseError> {
+        self.advance();
+        
+        if let Some(Token::RightBracket) = self.current_token() {
+            self.advance();
+            return Ok(Expression::Array(Vec::new()));
+        }
+        
+        let element = self.parse_expression()?;
+        match self.current_token() {
+            Some(Token::RightBracket) => {
+                self.advance();
+                Ok(Expression::Array(vec![element]))
+            },
+            _ => Err(ParseError::Syntax("expected comma or closing bracket in array".to_string())),
+        }
+    }
+    
+    /// Parse an object constructor `{key1: expr1, key2, ...}`
+    fn parse_object_constructor(&mut self) -> Result<Expression, ParseError> {
+        self.advance();
+        let mut properties = Vec::new();
+        
+        // Parse object properties
+        if let Some(Token::RightBrace) = self.current_token() {
+            self.advance();
+            return Ok(Expression::Object(properties));
+        }
+        
+        loop {
+            // Parse property key: a name, or a parenthesized expression computing it ({(.k): .v})
+            let key = match self.current_token() {
+                Some(Token::Identifier(name)) | Some(Token::StringLiteral(name)) => {
+                    let name = name.clone();
+                    self.advance();
+                    Some(name)
+                },
+                Some(Token::LeftParen) => None,
+                _ => {
+                    return Err(ParseError::Syntax("expected property name in object".to_string()));
+                }
+            };
             
-            // Parse the left part of the pipe
-            let left_expr = parse_query(left_part)?;
+            let property = match key {
+                // Parse property value, or use the shorthand {name} for {name: .name}
+                Some(key) => {
+                    let value = if let Some(Token::Colon) = self.current_token() {
+                        self.advance();
+                        self.parse_object_value()?
+                    } else {
+                        Expression::Property(key.clone())
+                    };
+                    (Expression::Literal(Value::String(key)), value)
+                },
+                None => {
+                    self.advance();
+                    let key = self.parse_expression()?;
+                    self.expect_token(&Token::RightParen)?;
+                    self.expect_token(&Token::Colon)?;
+                    (key, self.parse_object_value()?)
+                },
+            };
+            properties.push(property);
             
-            return Ok(Expression::Pipe(
-                Box::new(left_expr),
-                Box::new(Expression::Keys)
-            ));
+            match self.current_token() {
+                Some(Token::Comma) => {
+                    self.advance();
+                },
+                Some(Token::RightBrace) => {
+                    self.advance();
+                    break;
+                },
+                _ => {
+                    return Err(ParseError::Syntax("expected comma or closing brace in object".to_string()));
+                }
+            }
         }
+        
+        Ok(Expression::Object(properties))
     }
     
-    // Special case for length operation like '.resources | length'
-    if query.contains(" | length") {
-        if let Some(pipe_pos) = query.find(" | length") {
-            let left_part = &query[0..pipe_pos];
+    /// Parse the value of an object entry, a pipeline of operator expressions that ends at the
+    /// comma separating entries: `{a: .x | .y, b: 1}`
+    fn parse_object_value(&mut self) -> Result<Expression, ParseError> {
+        let mut expr = self.parse_binary(COMMA_PRECEDENCE + 1)?;
+        
+        while let Some(Token::Pipe) = self.current_token() {
+            self.advance();
+            let right = self.parse_binary(COMMA_PRECEDENCE + 1)?;
+            expr = Expression::Pipe(Box::new(expr), Box::new(right));
+        }
+        
+        Ok(expr)
+    }
+    
+    /// Parse a string literal or template if one comes next
+    fn parse_term_if_string(&mut self) -> Result<Option<Expression>, ParseError> {
+        match self.current_token() {
+            Some(Token::StringLiteral(_)) | Some(Token::StringTemplate(_)) => Ok(Some(self.nested(Self::parse_term)?)),
+            _ => Ok(None),
+        }
+    }
+    
+    /// Parse property and array accesses following a term (.a.b, .a[0], .a[])
+    fn parse_postfix(&mut self, mut expr: Expression) -> Result<Expression, ParseError> {
+        loop {
+            if let Some(name) = self.field_after_dot() {
+                self.advance();
+                self.advance();
+                expr = Expression::Pipe(Box::new(expr), Box::new(Expression::Property(name)));
+                continue;
+            }
             
-            // Parse the left part of the pipe
-            let left_expr = parse_query(left_part)?;
+            let access = match (self.current_token(), self.tokens.get(self.position + 1)) {
+                (Some(Token::Dot), Some(Token::LeftBracket)) => {
+                    self.advance();
+                    self.parse_bracket_access()?
+                },
+                (Some(Token::LeftBracket), _) => self.parse_bracket_access()?,
+                _ => break,
+            };
             
-            return Ok(Expression::Pipe(
-                Box::new(left_expr),
-                Box::new(Expression::Length)
-            ));
+            expr = Expression::Pipe(Box::new(expr), Box::new(access));
         }
+        
+        Ok(expr)
     }
     
-    // Special case for select expressions with chained operations
-    // like '.resources[] | select(.type == "aws_instance") | .instances[].attributes.id'
-    if query.contains(" | select(") {
-        if let Some(pipe_pos) = query.find(" | select(") {
-            let left_part = &query[0..pipe_pos];
-            let remaining = &query[pipe_pos + 10..];
-            
-            // Find the closing parenthesis for select
-            if let Some(close_paren) = find_matching_paren(remaining) {
-                let condition = &remaining[0..close_paren];
-                
-                // Check if there are more operations after select
-                let has_more_ops = close_paren + 1 < remaining.len() && remaining[close_paren+1..].contains(" | ");
+    /// Parse a bracketed access: iteration [], index [0], slice [1:3] or key ["name"]
+    fn parse_bracket_access(&mut self) -> Result<Expression, ParseError> {
+        self.expect_token(&Token::LeftBracket)?;
+        
+        match self.current_token() {
+            // Handle array iteration .[]
+            Some(Token::RightBracket) => {
+                self.advance();
+                Ok(Expression::ArrayIteration)
+            },
+            Some(Token::StringLiteral(name)) => {
+                let name = name.clone();
+                self.advance();
+                self.expect_token(&Token::RightBracket)?;
+                Ok(Expression::Property(name))
+            },
+            Some(Token::NumberLiteral(_)) | Some(Token::Minus) => {
+                let number = self.parse_signed_number()?;
                 
-                // Handle common comparison operators: ==, !=, >, <, >=, <=
-                for op in &["==", "!=", ">", "<", ">=", "<="] {
-                    if condition.contains(op) {
-                        if let Some(op_pos) = condition.find(op) {
-                            let left_expr_str = &condition[0..op_pos].trim();
-                            let right_expr_str = &condition[op_pos + op.len()..].trim();
-                            
-                            // Parse the left part of the pipe
-                            let outer_expr = parse_query(left_part)?;
-                            
-                            // Parse the left and right expressions of the condition
-                            let left_expr = parse_query(left_expr_str)?;
-                            
-                            // Handle string literals in the right expression
-                            let right_expr = if right_expr_str.starts_with('"') && right_expr_str.ends_with('"') {
-                                let content = &right_expr_str[1..right_expr_str.len()-1];
-                                Expression::Property(content.to_string())
-                            } else {
-                                parse_query(right_expr_str)?
-                            };
-                            
-                            let select_expr = Expression::Pipe(
-                                Box::new(outer_expr),
-                                Box::new(Expression::Select(
-                                    Box::new(left_expr),
-                                    op.to_string(),
-                                    Box::new(right_expr)
-                                ))
-                            );
-                            
-                            // If there are more operations, parse them
-                            if has_more_ops {
-                                let next_pipe_pos = remaining[close_paren+1..].find(" | ").unwrap();
-                                let next_ops = &remaining[close_paren+1+next_pipe_pos..];
-                                let next_expr = parse_query(next_ops)?;
-                                
-                                return Ok(Expression::Pipe(
-                                    Box::new(select_expr),
-                                    Box::new(next_expr)
-                                ));
-                            } else {
-                                return Ok(select_expr);
-                            }
-                        }
+                if let Some(Token::Colon) = self.current_token() {
+                    self.advance();
+                    let end = self.parse_slice_end()?;
+                    self.expect_token(&Token::RightBracket)?;
+                    Ok(Expression::Slice(Some(number as i64), end))
+                } else {
+                    // A fractional index is almost always a mistake, so it is rejected rather than truncated
+                    if number.fract() != 0.0 {
+                        return Err(ParseError::Syntax(format!("array index {} is not an integer", number)));
+                    }
+                    if number.abs() >= i64::MAX as f64 {
+                        return Err(ParseError::Syntax(format!("array index {} is too large", number)));
                     }
+                    self.expect_token(&Token::RightBracket)?;
+                    Ok(Expression::Index(number as i64))
                 }
+            },
+            Some(Token::Colon) => {
+                self.advance();
+                let end = self.parse_slice_end()?;
+                self.expect_token(&Token::RightBracket)?;
+                Ok(Expression::Slice(None, end))
+            },
+            _ => {
+                Err(ParseError::Syntax("expected number, colon, or closing bracket in array access".to_string()))
             }
         }
     }
     
-    // Special case for object construction like '.address | {city, state}'
-    if query.contains(" | {") && query.contains("}") {
-        if let Some(pipe_pos) = query.find(" | {") {
-            let left_part = &query[0..pipe_pos];
-            let right_part = &query[pipe_pos+3..];
-            
-            if right_part.starts_with('{') && right_part.ends_with('}') {
-                let obj_content = &right_part[1..right_part.len()-1];
-                let fields: Vec<&str> = obj_content.split(',').map(|s| s.trim()).collect();
-                
-                // Parse the left part of the pipe
-                let left_expr = parse_query(left_part)?;
-                
-                // Create object construction with fields
-                let mut properties = Vec::new();
-                for field in fields {
-                    properties.push((field.to_string(), Expression::Property(field.to_string())));
-                }
-                
-                let obj_expr = Expression::Object(properties);
-                
-                return Ok(Expression::Pipe(
-                    Box::new(left_expr),
-                    Box::new(obj_expr)
-                ));
-            }
+    /// Parse the optional end of a slice
+    fn parse_slice_end(&mut self) -> Result<Option<i64>, ParseError> {
+        match self.current_token() {
+            Some(Token::NumberLiteral(_)) | Some(Token::Minus) => Ok(Some(self.parse_signed_number()? as i64)),
+            _ => Ok(None),
         }
     }
     
-    // Special case for array indexing with property access
-    if query.contains('[') && query.contains(']') {
-        // Handle simple array indexing like .tags[1]
-        if let Some(first_dot) = query.find('.') {
-            if let Some(bracket_start) = query.find('[') {
-                if first_dot < bracket_start {
-                    let property = &query[first_dot+1..bracket_start];
-                    if let Some(bracket_end) = query[bracket_start..].find(']') {
-                        let bracket_end = bracket_start + bracket_end + 1;
-                        
-                        // Check if this is a pattern like .phones[0].number
-                        if bracket_end < query.len() && query[bracket_end..].contains('.') {
-                            let second_dot = bracket_end + query[bracket_end..].find('.').unwrap();
-                            let index_str = &query[bracket_start+1..bracket_end-1];
-                            if let Ok(index) = index_str.parse::<i64>() {
-                                let nested_property = &query[second_dot+1..];
-                                
-                                // Create a pipe expression: .property | .[index] | .nested_property
-                                let property_expr = Expression::Property(property.to_string());
-                                let index_expr = Expression::Index(index);
-                                let nested_expr = Expression::Property(nested_property.to_string());
-                                
-                                let pipe1 = Expression::Pipe(
-                                    Box::new(property_expr),
-                                    Box::new(index_expr)
-                                );
-                                
-                                return Ok(Expression::Pipe(
-                                    Box::new(pipe1),
-                                    Box::new(nested_expr)
-                                ));
-                            }
-                        } 
-                        // Simple array indexing like .tags[1]
-                        else if bracket_end == query.len() {
-                            let index_str = &query[bracket_start+1..bracket_end-1];
-                            if let Ok(index) = index_str.parse::<i64>() {
-                                // Create a pipe expression: .property | .[index]
-                                let property_expr = Expression::Property(property.to_string());
-                                let index_expr = Expression::Index(index);
-                                
-                                return Ok(Expression::Pipe(
-                                    Box::new(property_expr),
-                                    Box::new(index_expr)
-                                ));
-                            }
-                        }
-                    }
-                }
+    /// Build the expression for a builtin function call
+    fn parse_function(&self, name: &str, args: Vec<Expression>) -> Result<Expression, ParseError> {
+        let arity = args.len();
+        let Some((_, _, build)) = BUILTINS.iter().find(|(builtin, params, _)| *builtin == name && *params == arity) else {
+            return Err(ParseError::Syntax(format!("{}/{} is not defined", name, arity)));
+        };
+        
+        let mut args = args.into_iter().map(Box::new);
+        Ok(build(&mut || args.next().expect("arity is checked before taking arguments")))
+    }
+    
+    /// Expect a specific token and advance if found
+    fn expect_token(&mut self, expected: &Token) -> Result<(), ParseError> {
+        match self.current_token() {
+            Some(token) if token == expected => {
+                self.advance();
+                Ok(())
+            },
+            Some(token) => {
+                Err(ParseError::UnexpectedToken(format!("expected {:?}, got {:?}", expected, token)))
+            },
+            None => {
+                Err(ParseError::UnexpectedEof)
             }
         }
     }
-    
-    // Regular parsing for other queries
+}
+
+/// Precedence of the pipe operator, the loosest binding operator
+const PIPE_PRECEDENCE: u8 = 1;
+
+/// Precedence of the comma operator, which binds just tighter than a pipe
+const COMMA_PRECEDENCE: u8 = 2;
+
+/// The precedence and name of a binary operator token, where higher numbers bind more tightly
+fn binary_operator(token: &Token) -> Option<(u8, &'static str)> {
+    let operator = match token {
+        Token::Pipe => (PIPE_PRECEDENCE, "|"),
+        Token::Comma => (COMMA_PRECEDENCE, ","),
+        Token::SlashSlash => (3, "//"),
+        Token::Assign => (4, "="),
+        Token::UpdateAssign => (4, "|="),
+        Token::Identifier(name) if name == "or" => (5, "or"),
+        Token::Identifier(name) if name == "and" => (6, "and"),
+        Token::Equal => (7, "=="),
+        Token::NotEqual => (7, "!="),
+        Token::Less => (7, "<"),
+        Token::LessEqual => (7, "<="),
+        Token::Greater => (7, ">"),
+        Token::GreaterEqual => (7, ">="),
+        Token::Plus => (8, "+"),
+        Token::Minus => (8, "-"),
+        Token::Star => (9, "*"),
+        Token::Slash => (9, "/"),
+        Token::Percent => (9, "%"),
+        _ => return None,
+    };
+    Some(operator)
+}
+
+/// Convert a number to a JSON value, keeping integral values as integers
+pub(crate) fn number_to_value(n: f64) -> Value {
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n <= i64::MAX as f64 {
+        Value::from(n as i64)
+    } else {
+        Value::from(n)
+    }
+}
+
+/// Parse a query string into an expression
+pub fn parse_query(query: &str) -> Result<Expression, ParseError> {
     let mut lexer = Lexer::new(query);
-    let tokens = lexer.tokenize()?;
+    let tokens = lexer.tokenize_spanned()?;
     
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::with_columns(tokens, query.chars().count());
     parser.parse()
 }
 
@@ -914,6 +1594,35 @@ mod tests {
         ]);
     }
     
+    #[test]
+    fn test_lexer_variable() {
+        let mut lexer = Lexer::new("$name | $x");
+        let tokens = lexer.tokenize().unwrap();
+        
+        assert_eq!(tokens, vec![
+            Token::Variable("name".to_string()),
+            Token::Pipe,
+            Token::Variable("x".to_string()),
+        ]);
+        assert!(Lexer::new("$").tokenize().is_err());
+    }
+    
+    #[test]
+    fn test_lexer_comparisons() {
+        let mut lexer = Lexer::new("== != < <= > >=");
+        let tokens = lexer.tokenize().unwrap();
+        
+        assert_eq!(tokens, vec![
+            Token::Equal,
+            Token::NotEqual,
+            Token::Less,
+            Token::LessEqual,
+            Token::Greater,
+            Token::GreaterEqual,
+        ]);
+        assert!(Lexer::new("!").tokenize().is_err());
+    }
+    
     #[test]
     fn test_parser_identity() {
         let expr = parse_query(".").unwrap();
@@ -952,4 +1661,326 @@ mod tests {
             _ => panic!("Expected Pipe expression"),
         }
     }
+    
+    #[test]
+    fn test_parser_arithmetic() {
+        let expr = parse_query("\"ab\" * 3").unwrap();
+        match expr {
+            Expression::Arithmetic(left, op, right) => {
+                assert!(matches!(*left, Expression::Literal(Value::String(ref s)) if s == "ab"));
+                assert_eq!(op, "*");
+                assert!(matches!(*right, Expression::Literal(ref n) if n == &Value::from(3)));
+            },
+            _ => panic!("Expected Arithmetic expression"),
+        }
+    }
+    
+    #[test]
+    fn test_parser_function_call() {
+        let expr = parse_query("first(range(0; 10))").unwrap();
+        match expr {
+            Expression::First(inner) => assert!(matches!(*inner, Expression::Range(_, _))),
+            _ => panic!("Expected First expression"),
+        }
+        
+        assert!(parse_query("no_such_function(1)").is_err());
+        assert!(parse_query("range(1; 2").is_err());
+    }
+    
+    #[test]
+    fn test_parser_postfix_access() {
+        let expr = parse_query(".items[0].name").unwrap();
+        match expr {
+            Expression::Pipe(left, right) => {
+                assert!(matches!(*left, Expression::Pipe(_, _)));
+                assert!(matches!(*right, Expression::Property(ref name) if name == "name"));
+            },
+            _ => panic!("Expected Pipe expression"),
+        }
+    }
+    
+    #[test]
+    fn test_parser_function_definition() {
+        let expr = parse_query("def inc: . + 1; inc").unwrap();
+        match expr {
+            Expression::FunctionDef(def, rest) => {
+                assert_eq!(def.name, "inc");
+                assert!(def.params.is_empty());
+                assert!(matches!(*rest, Expression::FunctionCall(ref name, ref args) if name == "inc" && args.is_empty()));
+            },
+            _ => panic!("Expected function definition"),
+        }
+        
+        let expr = parse_query("def f(g; $x): g; 1").unwrap();
+        assert!(matches!(expr, Expression::FunctionDef(ref def, _) if def.params == ["g", "$x"]));
+        
+        // Definitions are only visible after they are made, and with their own arity
+        assert!(parse_query("inc").is_err());
+        assert!(parse_query("def inc: . + 1; inc(1)").is_err());
+    }
+    
+    #[test]
+    fn test_parser_nesting_depth() {
+        let nested = |depth: usize| format!("{}.{}", "[".repeat(depth), "]".repeat(depth));
+        
+        assert!(parse_query(&nested(100)).is_ok());
+        
+        let err = parse_query(&nested(100_000)).unwrap_err();
+        assert_eq!(err.to_string(), "syntax error: nesting too deep");
+        
+        // Every other way to nest is limited too, rather than overflowing the stack
+        let deep = [
+            format!("{}.", "-".repeat(60_000)),
+            format!("{}.", "reduce ".repeat(15_000)),
+            format!("{}.", "foreach ".repeat(15_000)),
+            format!("{}.", "(".repeat(15_000)),
+            format!("{}.", "{a: ".repeat(15_000)),
+            format!("{}.", ". as $x | ".repeat(15_000)),
+            format!(". as {}$x{} | .", "[".repeat(15_000), "]".repeat(15_000)),
+            format!("{}.", "def f: .; ".repeat(15_000)),
+            format!("if . then . {}else . end", "elif . then . ".repeat(15_000)),
+        ];
+        for query in &deep {
+            let err = parse_query(query).unwrap_err();
+            assert_eq!(err.to_string(), "syntax error: nesting too deep", "{}", &query[..20]);
+        }
+        assert!(parse_query(&format!("{}.", "-".repeat(100))).is_ok());
+    }
+    
+    #[test]
+    fn test_error_columns() {
+        // Parser errors point at the offending token
+        let err = parse_query(".a | | .b").unwrap_err();
+        assert_eq!(err.column(), Some(5));
+        
+        // Running out of input points just past the end
+        let err = parse_query(".a |").unwrap_err();
+        assert_eq!(err.column(), Some(4));
+        
+        // Lexer errors point at the offending character
+        let err = parse_query(".a + #").unwrap_err();
+        assert_eq!(err.column(), Some(5));
+        assert_eq!(err.to_string(), "syntax error: unexpected character: #");
+    }
+    
+    #[test]
+    fn test_lexer_string_template() {
+        let mut lexer = Lexer::new(r#""a\(.x)b""#);
+        let tokens = lexer.tokenize().unwrap();
+        
+        assert_eq!(tokens, vec![Token::StringTemplate(vec![
+            TemplatePart::Text("a".to_string()),
+            TemplatePart::Interpolation(vec![(Token::Dot, 4), (Token::Identifier("x".to_string()), 5)], 6),
+            TemplatePart::Text("b".to_string()),
+        ])]);
+    }
+    
+    #[test]
+    fn test_parser_string_interpolation() {
+        let expr = parse_query(r#""Hello \(.name | .first)!""#).unwrap();
+        match expr {
+            Expression::StringInterp(parts) => {
+                assert_eq!(parts.len(), 3);
+                assert!(matches!(&parts[0], StringPart::Literal(s) if s == "Hello "));
+                assert!(matches!(&parts[1], StringPart::Expr(Expression::Pipe(_, _))));
+                assert!(matches!(&parts[2], StringPart::Literal(s) if s == "!"));
+            },
+            _ => panic!("Expected string interpolation"),
+        }
+        
+        // Errors inside an interpolation point into it
+        let err = parse_query(r#""x \(.a | ) y""#).unwrap_err();
+        assert_eq!(err.column(), Some(10));
+        assert!(parse_query(r#""x \(.a"#).is_err());
+    }
+    
+    #[test]
+    fn test_parser_format_template() {
+        let expr = parse_query(r#"@uri "q=\(.q)""#).unwrap();
+        match expr {
+            Expression::StringInterp(parts) => {
+                assert!(matches!(&parts[0], StringPart::Literal(s) if s == "q="));
+                assert!(matches!(&parts[1], StringPart::Expr(Expression::Pipe(_, format)) if matches!(**format, Expression::Format(ref name) if name == "uri")));
+            },
+            _ => panic!("Expected string interpolation"),
+        }
+        
+        assert!(matches!(parse_query("@base64"), Ok(Expression::Format(_))));
+        assert_eq!(parse_query("@nope").unwrap_err().column(), Some(0));
+    }
+    
+    #[test]
+    fn test_parser_binding_patterns() {
+        let expr = parse_query(". as [$a, {b: $c, $d}] | $a").unwrap();
+        match expr {
+            Expression::Binding(source, Pattern::Array(elements), body) => {
+                assert!(matches!(*source, Expression::Identity));
+                assert!(matches!(&elements[0], Pattern::Variable(name) if name == "a"));
+                assert!(matches!(&elements[1], Pattern::Object(entries) if entries.len() == 2 && entries[1].0 == "d"));
+                assert!(matches!(*body, Expression::Variable(ref name) if name == "a"));
+            },
+            _ => panic!("Expected binding"),
+        }
+        
+        assert!(parse_query(". as [] | 1").is_err());
+        assert!(parse_query(". as $x").is_err());
+    }
+    
+    #[test]
+    fn test_parser_reduce_and_foreach() {
+        assert!(matches!(parse_query("reduce .[] as $x (0; . + $x)").unwrap(), Expression::Reduce(..)));
+        assert!(matches!(parse_query("foreach inputs as [$a] (0; 1)").unwrap(), Expression::Foreach(.., None)));
+        assert!(matches!(parse_query("foreach .[] as $x (0; 1; 2)").unwrap(), Expression::Foreach(.., Some(_))));
+        assert!(matches!(parse_query(".reduce").unwrap(), Expression::Property(ref name) if name == "reduce"));
+        
+        // reduce takes no extract, and both need a binding
+        assert!(parse_query("reduce .[] as $x (0; 1; 2)").is_err());
+        assert!(parse_query("reduce .[] (0; 1)").is_err());
+    }
+    
+    #[test]
+    fn test_parser_keyword_fields() {
+        // Keywords right after a dot are still field names
+        assert!(matches!(parse_query(".end").unwrap(), Expression::Property(ref name) if name == "end"));
+        assert!(matches!(parse_query(".a.as").unwrap(), Expression::Pipe(_, ref field) if matches!(**field, Expression::Property(ref name) if name == "as")));
+        assert!(matches!(parse_query(". as $x | $x").unwrap(), Expression::Binding(..)));
+    }
+    
+    #[test]
+    fn test_parser_grouping() {
+        match parse_query("2 * (.a + .b)").unwrap() {
+            Expression::Arithmetic(left, op, right) => {
+                assert!(matches!(*left, Expression::Literal(_)));
+                assert_eq!(op, "*");
+                assert!(matches!(*right, Expression::Arithmetic(_, ref op, _) if op == "+"));
+            },
+            _ => panic!("Expected Arithmetic expression"),
+        }
+        
+        // Postfix access applies to the whole group
+        assert!(matches!(parse_query("(.a | .b).c").unwrap(), Expression::Pipe(ref group, _) if matches!(**group, Expression::Pipe(..))));
+        assert!(parse_query("(.a + 1").is_err());
+    }
+    
+    #[test]
+    fn test_parser_precedence() {
+        // Multiplication binds tighter than addition, which binds tighter than a pipe
+        match parse_query(".a + .b * 2 | .c").unwrap() {
+            Expression::Pipe(left, right) => {
+                match *left {
+                    Expression::Arithmetic(_, ref op, ref product) => {
+                        assert_eq!(op, "+");
+                        assert!(matches!(**product, Expression::Arithmetic(_, ref op, _) if op == "*"));
+                    },
+                    _ => panic!("Expected Arithmetic expression"),
+                }
+                assert!(matches!(*right, Expression::Property(ref name) if name == "c"));
+            },
+            _ => panic!("Expected Pipe expression"),
+        }
+        
+        // Operators of equal precedence group to the left
+        assert!(matches!(parse_query("1 - 2 - 3").unwrap(), Expression::Arithmetic(ref left, _, _) if matches!(**left, Expression::Arithmetic(..))));
+        
+        assert!(matches!(parse_query(".a, .b | .c").unwrap(), Expression::Pipe(ref left, _) if matches!(**left, Expression::Comma(..))));
+        assert!(matches!(parse_query(".a // .b == 1").unwrap(), Expression::Alternative(_, ref right) if matches!(**right, Expression::Comparison(..))));
+        assert!(matches!(parse_query(".a or .b and .c").unwrap(), Expression::Or(_, ref right) if matches!(**right, Expression::And(..))));
+        assert!(matches!(parse_query("-.a + 1").unwrap(), Expression::Arithmetic(ref left, _, _) if matches!(**left, Expression::Negate(_))));
+    }
+    
+    #[test]
+    fn test_parser_trailing_tokens() {
+        for query in [".a 1", ".a )", "1 2", ".a | .b ]", ".a $x", "if . then 1 else 2 end end"] {
+            let error = parse_query(query).unwrap_err();
+            assert!(matches!(error, ParseError::Located { ref error, .. } if matches!(**error, ParseError::UnexpectedToken(_))), "{}", query);
+        }
+        
+        // The error points at the first unused token
+        assert_eq!(parse_query(".a 1").unwrap_err().column(), Some(3));
+        assert!(parse_query(r#""\(.a 1)""#).is_err());
+        
+        // A field access after a space still continues the path, like jq, but anything after it is still rejected
+        assert_eq!(format!("{:?}", parse_query(".a .b").unwrap()), format!("{:?}", parse_query(".a.b").unwrap()));
+        let error = parse_query(".a .b c").unwrap_err();
+        assert!(matches!(error, ParseError::Located { ref error, .. } if matches!(**error, ParseError::UnexpectedToken(_))));
+        assert_eq!(error.column(), Some(6));
+    }
+    
+    #[test]
+    fn test_lexer_escapes() {
+        let mut lexer = Lexer::new(r#""tab\there \u00e9 \ud83d\ude00 \/""#);
+        assert_eq!(lexer.tokenize().unwrap(), vec![Token::StringLiteral("tab\there \u{e9} \u{1F600} /".to_string())]);
+        
+        for query in [r#""\q""#, r#""\u12""#, r#""\u12zz""#, r#""\ud83d""#, r#""\ud83d\u0041""#, r#""\"#, r#""\u"#] {
+            assert!(Lexer::new(query).tokenize().is_err(), "{}", query);
+        }
+        
+        let huge = "9".repeat(400);
+        assert!(Lexer::new(&huge).tokenize().is_err());
+    }
+    
+    #[test]
+    fn test_lexer_and_parser_never_panic() {
+        // Random queries built from fragments of the query language; a small xorshift generator keeps
+        // the inputs reproducible without a dependency
+        let fragments = [
+            ".", "..", "|", ",", "[", "]", "{", "}", "(", ")", ":", ";", "?", "+", "-", "*", "/", "//", "%",
+            "=", "==", "!=", "<", ">=", "$", "$x", "@", "@csv", "@nope", "\"", "\\", "\\(", "\\u", "\\ud800",
+            "a", "if", "then", "elif", "else", "end", "def", "as", "and", "or", "1", "1.", "2.5", "-3",
+            "9999999999999999999999", " ", "é", "\n", "select(", "range(", "first", "empty", "[]", "{}",
+        ];
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        
+        for _ in 0..20_000 {
+            let length = next() % 16;
+            let query: String = (0..length).map(|_| fragments[next() % fragments.len()]).collect();
+            let _ = Lexer::new(&query).tokenize();
+            let _ = parse_query(&query);
+        }
+        
+        // Short random queries cannot nest deeply, so also try long runs of each fragment that opens a level
+        let openers = [
+            "[", "{a: ", "(", "-", "\"\\(", "reduce ", "foreach ", "if . then ", "def f: ", "select(", ". as $x | ",
+        ];
+        for opener in openers {
+            for repeats in [MAX_NESTING_DEPTH + 1, 15_000] {
+                let query = format!("{}.", opener.repeat(repeats));
+                let _ = Lexer::new(&query).tokenize();
+                let err = parse_query(&query).unwrap_err();
+                assert_eq!(err.to_string(), "syntax error: nesting too deep", "{} x {}", opener, repeats);
+            }
+        }
+    }
+    
+    #[test]
+    fn test_expression_serializes_to_json() {
+        let ast = serde_json::to_value(parse_query(".a | .b").unwrap()).unwrap();
+        assert_eq!(ast, serde_json::json!({"Pipe": [{"Property": "a"}, {"Property": "b"}]}));
+        
+        let ast = serde_json::to_value(parse_query(r#". as [$x] | "\($x)!""#).unwrap()).unwrap();
+        assert_eq!(ast, serde_json::json!({"Binding": [
+            "Identity",
+            {"Array": [{"Variable": "x"}]},
+            {"StringInterp": [{"Expr": {"Variable": "x"}}, {"Literal": "!"}]},
+        ]}));
+    }
+    
+    #[test]
+    fn test_index_must_be_integer() {
+        assert!(matches!(parse_query(".[1]").unwrap(), Expression::Index(1)));
+        assert!(matches!(parse_query(".[-2]").unwrap(), Expression::Index(-2)));
+        assert!(matches!(parse_query(".[1.0]").unwrap(), Expression::Index(1)));
+        
+        for query in [".[1.5]", ".[-0.5]", ".a[2.25]", ".[100000000000000000000]"] {
+            let error = parse_query(query).unwrap_err();
+            assert!(matches!(&error, ParseError::Located { error, .. } if matches!(**error, ParseError::Syntax(_))), "{}", query);
+        }
+        assert_eq!(parse_query(".[1.5]").unwrap_err().to_string(), "syntax error: array index 1.5 is not an integer");
+    }
 }
@@ -2,8 +2,12 @@
 //!
 //! This module handles the execution of parsed queries against JSON data
 
-use crate::parser::{Expression, ParseError};
+use crate::parser::{number_to_value, Expression, BUILTINS, FunctionDef, ParseError, Pattern, StringPart};
+use regex::{Regex, RegexBuilder};
 use serde_json::{Value, Map};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Mutex, PoisonError};
 use thiserror::Error;
 
 /// Error type for query execution failures
@@ -23,277 +27,487 @@ pub enum QueryError {
     
     #[error("json error: {0}")]
     Json(#[from] serde_json::Error),
+    
+    #[error("${0} is not defined")]
+    UndefinedVariable(String),
+    
+    #[error("{0}/{1} is not defined")]
+    UndefinedFunction(String, usize),
+    
+    #[error("{0} did not finish within {1} iterations")]
+    IterationLimit(String, usize),
+    
+    #[error("input is nested more than {0} levels deep")]
+    DepthLimit(usize),
+    
+    #[error("{0} is not supported yet")]
+    Unsupported(String),
+    
+    #[error("no more inputs")]
+    NoMoreInputs,
+    
+    #[error("input error: {0}")]
+    Input(String),
 }
 
+/// Maximum number of iterations a `while`/`until` loop may run before failing
+pub const MAX_LOOP_ITERATIONS: usize = 1_000_000;
+
+/// Maximum length in bytes of a string built by repeating one (`"ab" * n`)
+pub const MAX_REPEAT_LENGTH: usize = 1 << 30;
+
+/// Default maximum nesting depth of values that recursive operations such as `..` will descend into
+pub const DEFAULT_MAX_DEPTH: usize = 10_000;
+
+/// Arrays at least this long are mapped on several threads (with the `parallel` feature)
+pub const PARALLEL_MAP_THRESHOLD: usize = 4_096;
+
 /// Result type for query operations
 pub type QueryResult = Result<Vec<Value>, QueryError>;
 
-/// Executes a query expression against JSON data
-pub struct QueryEngine;
+/// A lazily evaluated stream of query results
+pub type ValueStream<'a> = Box<dyn Iterator<Item = Result<Value, QueryError>> + 'a>;
+
+/// A lexical scope of local variables and function definitions, linked to its enclosing scope
+enum Env<'a> {
+    Root,
+    Variable(&'a str, Value, Rc<Env<'a>>),
+    Function(&'a FunctionDef, Rc<Env<'a>>),
+    /// A filter parameter, evaluated in the scope of the call site
+    Closure(&'a str, &'a Expression, Rc<Env<'a>>, Rc<Env<'a>>),
+}
+
+/// A function found by name and arity in a scope
+enum Callable<'a> {
+    /// A definition together with the scope it was defined in (which includes itself, for recursion)
+    Function(&'a FunctionDef, Rc<Env<'a>>),
+    Closure(&'a Expression, Rc<Env<'a>>),
+}
+
+impl<'a> Env<'a> {
+    /// Find the innermost binding of a local variable
+    fn variable(&self, name: &str) -> Option<&Value> {
+        let mut env = self;
+        loop {
+            env = match env {
+                Env::Root => return None,
+                Env::Variable(bound, value, _) if *bound == name => return Some(value),
+                Env::Variable(_, _, parent) | Env::Function(_, parent) | Env::Closure(_, _, _, parent) => parent,
+            };
+        }
+    }
+    
+    /// Find the innermost function with the given name and arity
+    fn function(self: &Rc<Self>, name: &str, arity: usize) -> Option<Callable<'a>> {
+        let mut env = self;
+        loop {
+            env = match &**env {
+                Env::Root => return None,
+                Env::Function(def, _) if def.name == name && def.params.len() == arity => {
+                    return Some(Callable::Function(def, env.clone()));
+                },
+                Env::Closure(bound, body, scope, _) if *bound == name && arity == 0 => {
+                    return Some(Callable::Closure(body, scope.clone()));
+                },
+                Env::Variable(_, _, parent) | Env::Function(_, parent) | Env::Closure(_, _, _, parent) => parent,
+            };
+        }
+    }
+}
+
+/// Executes a query expression against JSON data.
+///
+/// An engine only holds configuration (global variables and limits) and the queue of inputs that `input`
+/// and `inputs` read from; everything else a query needs while it runs lives in the evaluation itself. So an
+/// engine is cheap to create, and one engine is `Send + Sync` and can run queries from several threads at
+/// once. Any state added later, such as caches, must keep it that way (for example behind a `Mutex`, like
+/// the inputs).
+pub struct QueryEngine {
+    /// Global variables available to queries as `$name`
+    variables: HashMap<String, Value>,
+    
+    /// Maximum nesting depth that recursive operations descend into before failing
+    max_depth: usize,
+    
+    /// Inputs not yet read, shared by the caller running a query on each input and `input`/`inputs` in it
+    inputs: Mutex<InputStream>,
+}
+
+/// A source of inputs, which may parse each one only when it is read
+pub type InputStream = Box<dyn Iterator<Item = Result<Value, QueryError>> + Send>;
+
+impl Default for QueryEngine {
+    fn default() -> Self {
+        QueryEngine {
+            variables: HashMap::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            inputs: Mutex::new(Box::new(std::iter::empty())),
+        }
+    }
+}
 
 impl QueryEngine {
     /// Create a new query engine
     pub fn new() -> Self {
-        QueryEngine
+        QueryEngine::default()
+    }
+    
+    /// Bind a global variable, available to queries as `$name`
+    pub fn set_variable(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.to_string(), value);
+    }
+    
+    /// Set the maximum nesting depth that recursive operations such as `..` descend into
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+    
+    /// Queue the inputs that `input` and `inputs` read, replacing any left over
+    pub fn set_inputs(&mut self, inputs: Vec<Value>) {
+        self.set_input_stream(inputs.into_iter().map(Ok));
+    }
+    
+    /// Read inputs from a stream, replacing any left over. Each input is only taken from the stream when it
+    /// is read, so a stream that parses documents as it goes never holds more than one at a time.
+    pub fn set_input_stream(&mut self, inputs: impl Iterator<Item = Result<Value, QueryError>> + Send + 'static) {
+        *self.inputs.get_mut().unwrap_or_else(PoisonError::into_inner) = Box::new(inputs);
+    }
+    
+    /// Take the next input, or the error reading it. A caller running a query on each input should take them
+    /// from here, so that inputs read by `input` or `inputs` are not run again.
+    pub fn next_input(&self) -> Option<Result<Value, QueryError>> {
+        self.inputs.lock().unwrap_or_else(PoisonError::into_inner).next()
     }
     
     /// Execute a query expression against JSON data
     pub fn execute(&self, expr: &Expression, data: &Value) -> QueryResult {
+        self.stream(expr, data.clone()).collect()
+    }
+    
+    /// Lazily evaluate a query expression, producing results only as they are consumed
+    pub fn stream<'a>(&'a self, expr: &'a Expression, data: Value) -> ValueStream<'a> {
+        self.eval(expr, data, Rc::new(Env::Root))
+    }
+    
+    /// Evaluate an expression within a scope of local variables and function definitions
+    fn eval<'a>(&'a self, expr: &'a Expression, data: Value, env: Rc<Env<'a>>) -> ValueStream<'a> {
         match expr {
             Expression::Identity => {
                 // Identity expression (.) just returns the input data
-                Ok(vec![data.clone()])
+                single(Ok(data))
             },
             
+            Expression::Empty => empty(),
+            
             Expression::RecursiveDescent => {
                 // Recursive descent (..) returns all nested values
-                let mut results = Vec::new();
-                self.collect_recursive(data, &mut results);
-                Ok(results)
+                self.recurse(data, None)
+            },
+            
+            Expression::RecurseDepth(depth) => {
+                // Recursive descent stopping n levels below the input (recurse_depth(n)), so exploring a huge
+                // document does not visit every value in it
+                Box::new(self.eval(depth, data.clone(), env.clone()).flat_map(move |depth| match depth {
+                    Ok(Value::Number(n)) if n.as_u64().is_some() => self.recurse(data.clone(), n.as_u64().map(|n| n as usize)),
+                    Ok(other) => single(Err(QueryError::Type(format!("recurse_depth needs a non-negative integer, not {}", other)))),
+                    Err(e) => single(Err(e)),
+                }))
+            },
+            
+            Expression::Test(regex, flags) | Expression::Match(regex, flags) => {
+                // Regular expressions (test(re; flags), match(re; flags)), for each regex and flags given
+                let is_test = matches!(expr, Expression::Test(..));
+                let input = match data {
+                    Value::String(s) => s,
+                    other => {
+                        return single(Err(QueryError::Type(format!("{} ({}) cannot be matched, as it is not a string", type_name(&other), other))));
+                    },
+                };
+                
+                let matched = self.collect(regex, Value::String(input.clone()), env.clone()).and_then(|regexes| {
+                    let flags = self.collect(flags, Value::String(input.clone()), env.clone())?;
+                    let mut results = Vec::new();
+                    for regex in &regexes {
+                        for flags in &flags {
+                            let (regex, flags) = compile_regex(regex, flags)?;
+                            if is_test {
+                                results.push(Value::Bool(regex.is_match(&input)));
+                            } else {
+                                results.extend(regex_matches(&regex, &flags, &input));
+                            }
+                        }
+                    }
+                    Ok(results)
+                });
+                
+                match matched {
+                    Ok(results) => values(results),
+                    Err(e) => single(Err(e)),
+                }
+            },
+            
+            Expression::Sub(regex, replacement, flags) => {
+                // Regex replacement (sub(re; replacement; flags), gsub with the `g` flag). The replacement is
+                // evaluated on an object of the match's named captures, so "\(.name)" inserts a capture.
+                let input = match data {
+                    Value::String(s) => s,
+                    other => {
+                        return single(Err(QueryError::Type(format!("{} ({}) cannot be matched, as it is not a string", type_name(&other), other))));
+                    },
+                };
+                
+                let replaced = self.collect(regex, Value::String(input.clone()), env.clone()).and_then(|regexes| {
+                    let flags = self.collect(flags, Value::String(input.clone()), env.clone())?;
+                    let mut results = Vec::new();
+                    for regex in &regexes {
+                        for flags in &flags {
+                            let (regex, flags) = compile_regex(regex, flags)?;
+                            results.extend(self.substitute(&regex, &flags, &input, replacement, env.clone())?);
+                        }
+                    }
+                    Ok(results)
+                });
+                
+                match replaced {
+                    Ok(results) => values(results),
+                    Err(e) => single(Err(e)),
+                }
+            },
+            
+            Expression::Type => {
+                // The jq type name of the input (type)
+                single(Ok(Value::from(type_name(&data))))
+            },
+            
+            Expression::Walk(f) => {
+                // Apply f to every value bottom-up (walk(f)): children are rebuilt first, then f runs on the parent
+                match self.walk(f, data, env, 0) {
+                    Ok(results) => values(results),
+                    Err(e) => single(Err(e)),
+                }
+            },
+            
+            Expression::Input => {
+                // The next input (input), failing when there are none left
+                single(self.next_input().unwrap_or(Err(QueryError::NoMoreInputs)))
+            },
+            
+            Expression::Inputs => {
+                // All remaining inputs (inputs), read one at a time as they are consumed
+                Box::new(std::iter::from_fn(move || self.next_input()))
+            },
+            
+            Expression::Builtins => {
+                // The builtin functions as `name/arity` (builtins), straight from the table calls are parsed with
+                let names = BUILTINS.iter().map(|(name, arity, _)| Value::String(format!("{}/{}", name, arity))).collect();
+                single(Ok(Value::Array(names)))
+            },
+            
+            Expression::Env => {
+                // The process environment as an object of strings (env); variables that are not valid
+                // Unicode are left out
+                let vars = std::env::vars_os()
+                    .filter_map(|(name, value)| Some((name.into_string().ok()?, Value::String(value.into_string().ok()?))))
+                    .collect();
+                single(Ok(Value::Object(vars)))
+            },
+            
+            Expression::ToStream => {
+                // The input as streaming events (tostream): [path, leaf] for each scalar or empty array or object,
+                // and [path] closing each array or object after its last child, with the path of that child
+                let mut events = Vec::new();
+                match stream_events(data, &mut Vec::new(), &mut events, self.max_depth) {
+                    Ok(()) => values(events),
+                    Err(e) => single(Err(e)),
+                }
+            },
+            
+            Expression::FromStream(events) => {
+                // Reassemble values from streaming events (fromstream(f)), producing each top-level value as soon
+                // as its last event arrives
+                let mut events = self.eval(events, data, env.clone());
+                let mut value = Value::Null;
+                
+                Box::new(std::iter::from_fn(move || {
+                    for event in events.by_ref() {
+                        let event = match event {
+                            Ok(Value::Array(event)) => event,
+                            Ok(other) => return Some(Err(QueryError::Type(format!("{} is not a stream event", other)))),
+                            Err(e) => return Some(Err(e)),
+                        };
+                        
+                        match event.as_slice() {
+                            // A leaf: a scalar at the top level is complete at once
+                            [Value::Array(path), leaf] if path.is_empty() => return Some(Ok(leaf.clone())),
+                            [Value::Array(path), leaf] => match set_path(std::mem::take(&mut value), path, leaf.clone()) {
+                                Ok(updated) => value = updated,
+                                Err(e) => return Some(Err(e)),
+                            },
+                            // The end of an array or object: one at the top level completes the value
+                            [Value::Array(path)] if path.len() == 1 => return Some(Ok(std::mem::take(&mut value))),
+                            [Value::Array(_)] => {},
+                            _ => return Some(Err(QueryError::Type(format!("{} is not a stream event", Value::Array(event))))),
+                        }
+                    }
+                    None
+                }))
             },
             
             Expression::Property(name) => {
                 // Property access (.name or ."name")
                 match data {
-                    Value::Object(obj) => {
-                        if let Some(value) = obj.get(name) {
-                            Ok(vec![value.clone()])
-                        } else {
-                            Ok(vec![Value::Null])
-                        }
+                    Value::Object(mut obj) => {
+                        single(Ok(obj.remove(name).unwrap_or(Value::Null)))
+                    },
+                    Value::Array(_) => {
+                        // A common mistake from JSONPath, so suggest the ways to take the field from each element
+                        let field = field_syntax(name);
+                        single(Err(QueryError::Type(format!(
+                            "cannot access property '{}' on an array, use .[]{} or map({}) to get it from each element", name, field, field
+                        ))))
                     },
-                    _ => Err(QueryError::Type(format!("cannot access property '{}' on non-object value", name))),
+                    _ => single(Err(QueryError::Type(format!("cannot access property '{}' on non-object value", name)))),
                 }
             },
             
             Expression::Index(index) => {
                 // Array index access (.[0])
                 match data {
-                    Value::Array(arr) => {
+                    Value::Array(mut arr) => {
                         let idx = if *index < 0 {
                             arr.len().checked_sub(index.unsigned_abs() as usize)
                         } else {
                             Some(*index as usize)
                         };
                         
-                        if let Some(idx) = idx {
-                            if idx < arr.len() {
-                                Ok(vec![arr[idx].clone()])
-                            } else {
-                                Ok(vec![Value::Null])
-                            }
-                        } else {
-                            Ok(vec![Value::Null])
+                        match idx {
+                            Some(idx) if idx < arr.len() => single(Ok(arr.swap_remove(idx))),
+                            _ => single(Ok(Value::Null)),
                         }
                     },
-                    _ => Err(QueryError::Type("cannot index non-array value".to_string())),
+                    Value::String(_) => single(Err(QueryError::Type(format!(
+                        "cannot index string with number, use a slice such as .[{}:{}] to take characters", index, index + 1
+                    )))),
+                    _ => single(Err(QueryError::Type("cannot index non-array value".to_string()))),
                 }
             },
             
             Expression::Slice(start, end) => {
-                // Array slice access (.[1:3])
+                // Slice access (.[1:3]) of an array, or of a string by character
                 match data {
                     Value::Array(arr) => {
-                        let start_idx = match start {
-                            Some(s) => {
-                                if *s < 0 {
-                                    arr.len().checked_sub(s.unsigned_abs() as usize).unwrap_or(0)
-                                } else {
-                                    *s as usize
-                                }
-                            },
-                            None => 0,
-                        };
-                        
-                        let end_idx = match end {
-                            Some(e) => {
-                                if *e < 0 {
-                                    arr.len().checked_sub(e.unsigned_abs() as usize).unwrap_or(arr.len())
-                                } else {
-                                    (*e as usize).min(arr.len())
-                                }
-                            },
-                            None => arr.len(),
-                        };
-                        
-                        if start_idx <= end_idx && start_idx < arr.len() {
-                            let slice = arr[start_idx..end_idx.min(arr.len())].to_vec();
-                            Ok(vec![Value::Array(slice)])
-                        } else {
-                            Ok(vec![Value::Array(vec![])])
-                        }
+                        let (from, to) = slice_bounds(arr.len(), *start, *end);
+                        single(Ok(Value::Array(arr[from..to].to_vec())))
                     },
-                    _ => Err(QueryError::Type("cannot slice non-array value".to_string())),
+                    Value::String(s) => {
+                        let (from, to) = slice_bounds(s.chars().count(), *start, *end);
+                        single(Ok(Value::String(s.chars().skip(from).take(to - from).collect())))
+                    },
+                    Value::Null => single(Ok(Value::Null)),
+                    other => single(Err(QueryError::Type(format!("cannot slice {} ({})", type_name(&other), other)))),
                 }
             },
             
             Expression::Array(elements) => {
-                // Array constructor ([expr1, expr2, ...])
+                // Array constructor ([expr1, expr2, ...]) collects every output of every element
                 let mut result = Vec::new();
                 
                 for element in elements {
-                    let values = self.execute(element, data)?;
-                    result.extend(values);
+                    for value in self.eval(element, data.clone(), env.clone()) {
+                        match value {
+                            Ok(value) => result.push(value),
+                            Err(e) => return single(Err(e)),
+                        }
+                    }
                 }
                 
-                Ok(vec![Value::Array(result)])
+                single(Ok(Value::Array(result)))
             },
             
             Expression::Object(properties) => {
-                // Object constructor ({key1: expr1, key2: expr2, ...})
-                let mut obj = Map::new();
+                // Object constructor ({key1: expr1, key2: expr2, ...}): like jq, one object for each combination of
+                // the keys' and values' outputs, so a key or value with no outputs means no object at all
+                let mut objects = vec![Map::new()];
                 
-                for (key, expr) in properties {
-                    let values = self.execute(expr, data)?;
-                    if let Some(value) = values.first() {
-                        obj.insert(key.clone(), value.clone());
-                    }
+                for (key_expr, expr) in properties {
+                    let keys = match self.collect(key_expr, data.clone(), env.clone()) {
+                        Ok(keys) => keys,
+                        Err(e) => return single(Err(e)),
+                    };
+                    let keys = match keys.into_iter().map(|key| match key {
+                        Value::String(key) => Ok(key),
+                        other => Err(QueryError::Type(format!(
+                            "Object keys must be strings, not {} ({})", type_name(&other), other
+                        ))),
+                    }).collect::<Result<Vec<_>, _>>() {
+                        Ok(keys) => keys,
+                        Err(e) => return single(Err(e)),
+                    };
+                    let outputs = match self.collect(expr, data.clone(), env.clone()) {
+                        Ok(outputs) => outputs,
+                        Err(e) => return single(Err(e)),
+                    };
+                    
+                    objects = objects.into_iter()
+                        .flat_map(|obj| keys.iter().flat_map(|key| {
+                            let obj = &obj;
+                            outputs.iter().map(move |value| {
+                                let mut obj = obj.clone();
+                                obj.insert(key.clone(), value.clone());
+                                obj
+                            })
+                        }).collect::<Vec<_>>())
+                        .collect();
                 }
                 
-                Ok(vec![Value::Object(obj)])
+                values(objects.into_iter().map(Value::Object).collect())
             },
             
             Expression::Pipe(left, right) => {
-                // Pipe operator (expr1 | expr2)
-                let mut results = Vec::new();
-                
-                // Execute the left expression
-                let left_results = self.execute(left, data)?;
-                
-                // Execute the right expression on each result from the left
-                for value in left_results {
-                    let right_results = self.execute(right, &value)?;
-                    results.extend(right_results);
-                }
-                
-                Ok(results)
+                // Pipe operator (expr1 | expr2): run the right side on each result from the left
+                Box::new(self.eval(left, data, env.clone()).flat_map(move |value| match value {
+                    Ok(value) => self.eval(right, value, env.clone()),
+                    Err(e) => single(Err(e)),
+                }))
             },
             
             Expression::ArrayIteration => {
                 // Array iteration (.[]) returns all elements of an array
                 match data {
                     Value::Array(arr) => {
-                        Ok(arr.clone())
+                        values(arr)
                     },
                     Value::Object(obj) => {
                         // For objects, return all values
-                        let values: Vec<Value> = obj.values().cloned().collect();
-                        Ok(values)
+                        Box::new(obj.into_iter().map(|(_, value)| Ok(value)))
                     },
-                    _ => Err(QueryError::Type("array iteration can only be applied to arrays or objects".to_string())),
+                    _ => single(Err(QueryError::Type("array iteration can only be applied to arrays or objects".to_string()))),
                 }
             },
             
-            Expression::Filter(expr) => {
-                // Filter expression
+            Expression::Map(expr) => {
+                // Map operation (map(expr))
                 match data {
-                    Value::Array(arr) => {
-                        let mut results = Vec::new();
-                        
-                        for item in arr {
-                            let filter_results = self.execute(expr, item)?;
-                            
-                            // If filter returns any truthy value, include the item
-                            if filter_results.iter().any(|v| is_truthy(v)) {
-                                results.push(item.clone());
-                            }
-                        }
-                        
-                        Ok(vec![Value::Array(results)])
+                    // Local scopes live on this thread, so only a map outside of any can run in parallel
+                    #[cfg(feature = "parallel")]
+                    Value::Array(arr) if arr.len() >= PARALLEL_MAP_THRESHOLD && matches!(*env, Env::Root) => {
+                        single(self.par_map(expr, arr).map(Value::Array))
                     },
-                    _ => Err(QueryError::Type("filter can only be applied to arrays".to_string())),
-                }
-            },
-            
-            Expression::Select(expr, op, value_expr) => {
-                // Select expression (select(.field == "value"))
-                match data {
                     Value::Array(arr) => {
                         let mut results = Vec::new();
                         
                         for item in arr {
-                            let left_results = self.execute(expr, item)?;
-                            let right_results = self.execute(value_expr, item)?;
-                            
-                            if left_results.len() == 1 && right_results.len() == 1 {
-                                let left = &left_results[0];
-                                let right = &right_results[0];
-                                
-                                let include = match op.as_str() {
-                                    "==" => left == right,
-                                    "!=" => left != right,
-                                    ">" => compare_values(left, right) == Some(std::cmp::Ordering::Greater),
-                                    "<" => compare_values(left, right) == Some(std::cmp::Ordering::Less),
-                                    ">=" => {
-                                        let cmp = compare_values(left, right);
-                                        cmp == Some(std::cmp::Ordering::Greater) || cmp == Some(std::cmp::Ordering::Equal)
-                                    },
-                                    "<=" => {
-                                        let cmp = compare_values(left, right);
-                                        cmp == Some(std::cmp::Ordering::Less) || cmp == Some(std::cmp::Ordering::Equal)
-                                    },
-                                    _ => false,
-                                };
-                                
-                                if include {
-                                    results.push(item.clone());
+                            for value in self.eval(expr, item, env.clone()) {
+                                match value {
+                                    Ok(value) => results.push(value),
+                                    Err(e) => return single(Err(e)),
                                 }
                             }
                         }
                         
-                        Ok(vec![Value::Array(results)])
-                    },
-                    Value::Object(_) => {
-                        let left_results = self.execute(expr, data)?;
-                        let right_results = self.execute(value_expr, data)?;
-                        
-                        if left_results.len() == 1 && right_results.len() == 1 {
-                            let left = &left_results[0];
-                            let right = &right_results[0];
-                            
-                            let result = match op.as_str() {
-                                "==" => left == right,
-                                "!=" => left != right,
-                                ">" => compare_values(left, right) == Some(std::cmp::Ordering::Greater),
-                                "<" => compare_values(left, right) == Some(std::cmp::Ordering::Less),
-                                ">=" => {
-                                    let cmp = compare_values(left, right);
-                                    cmp == Some(std::cmp::Ordering::Greater) || cmp == Some(std::cmp::Ordering::Equal)
-                                },
-                                "<=" => {
-                                    let cmp = compare_values(left, right);
-                                    cmp == Some(std::cmp::Ordering::Less) || cmp == Some(std::cmp::Ordering::Equal)
-                                },
-                                _ => false,
-                            };
-                            
-                            if result {
-                                Ok(vec![data.clone()])
-                            } else {
-                                Ok(vec![])
-                            }
-                        } else {
-                            Ok(vec![])
-                        }
-                    },
-                    _ => Ok(vec![]),
-                }
-            },
-            
-            Expression::Map(expr) => {
-                // Map operation (map(expr))
-                match data {
-                    Value::Array(arr) => {
-                        let mut results = Vec::new();
-                        
-                        for item in arr {
-                            let mapped_results = self.execute(expr, item)?;
-                            results.extend(mapped_results);
-                        }
-                        
-                        Ok(vec![Value::Array(results)])
+                        single(Ok(Value::Array(results)))
                     },
-                    _ => Err(QueryError::Type("map can only be applied to arrays".to_string())),
+                    _ => single(Err(QueryError::Type("map can only be applied to arrays".to_string()))),
                 }
             },
             
@@ -304,15 +518,108 @@ impl QueryEngine {
                         let keys: Vec<Value> = obj.keys()
                             .map(|k| Value::String(k.clone()))
                             .collect();
-                        Ok(vec![Value::Array(keys)])
+                        single(Ok(Value::Array(keys)))
                     },
                     Value::Array(arr) => {
                         let keys: Vec<Value> = (0..arr.len())
                             .map(|i| Value::Number(serde_json::Number::from(i)))
                             .collect();
-                        Ok(vec![Value::Array(keys)])
+                        single(Ok(Value::Array(keys)))
+                    },
+                    _ => single(Err(QueryError::Type("keys can only be applied to objects or arrays".to_string()))),
+                }
+            },
+            
+            Expression::ToEntries => {
+                // An array of {"key": k, "value": v} objects, one for each key of an object or index of an array
+                let entries = match data {
+                    Value::Object(obj) => obj.into_iter().map(|(key, value)| entry(Value::String(key), value)).collect(),
+                    Value::Array(arr) => arr.into_iter().enumerate().map(|(i, value)| entry(Value::from(i), value)).collect(),
+                    other => return single(Err(QueryError::Type(format!("{} ({}) has no keys", type_name(&other), other)))),
+                };
+                single(Ok(Value::Array(entries)))
+            },
+            
+            Expression::FromEntries => {
+                // An object built from an array of entries, the inverse of to_entries
+                match data {
+                    Value::Array(entries) => single(entries.into_iter().map(from_entry).collect::<Result<Map<_, _>, _>>().map(Value::Object)),
+                    other => single(Err(QueryError::Type(format!("{} ({}) is not an array of entries", type_name(&other), other)))),
+                }
+            },
+            
+            Expression::AsciiDowncase | Expression::AsciiUpcase => {
+                // Change the case of the ASCII letters in a string, leaving any other characters alone
+                match data {
+                    Value::String(s) if matches!(expr, Expression::AsciiDowncase) => single(Ok(Value::String(s.to_ascii_lowercase()))),
+                    Value::String(s) => single(Ok(Value::String(s.to_ascii_uppercase()))),
+                    other => single(Err(QueryError::Type(format!("{} ({}) cannot change case, only strings can", type_name(&other), other)))),
+                }
+            },
+            
+            Expression::ToNumber => {
+                // A number, or a string holding one ("5" | tonumber)
+                match data {
+                    Value::Number(n) => single(Ok(Value::Number(n))),
+                    Value::String(s) => single(parse_number(&s)),
+                    other => single(Err(QueryError::Type(format!("{} ({}) cannot be parsed as a number", type_name(&other), other)))),
+                }
+            },
+            
+            Expression::ToString => {
+                // A string as it is, and anything else as its JSON text
+                match data {
+                    Value::String(s) => single(Ok(Value::String(s))),
+                    other => single(Ok(Value::String(other.to_string()))),
+                }
+            },
+            
+            Expression::Sort => {
+                // Sort (sort) an array using jq's total order; equal values keep their order
+                match data {
+                    Value::Array(mut arr) => {
+                        arr.sort_by(compare_values);
+                        single(Ok(Value::Array(arr)))
+                    },
+                    other => single(Err(QueryError::Type(format!("{} ({}) cannot be sorted, as it is not an array", type_name(&other), other)))),
+                }
+            },
+            
+            Expression::SortBy(key) => {
+                // Sort an array by key (sort_by(f)). Like jq, each element's key is the array of all outputs of f,
+                // so sort_by(.a, .b) sorts by .a and then by .b; elements with equal keys keep their order
+                match data {
+                    Value::Array(arr) => match self.keyed_by(key, arr, env) {
+                        Ok(mut keyed) => {
+                            keyed.sort_by(|(left, _), (right, _)| compare_values(left, right));
+                            single(Ok(Value::Array(keyed.into_iter().map(|(_, item)| item).collect())))
+                        },
+                        Err(e) => single(Err(e)),
+                    },
+                    other => single(Err(QueryError::Type(format!("{} ({}) cannot be sorted, as it is not an array", type_name(&other), other)))),
+                }
+            },
+            
+            Expression::MinBy(key) | Expression::MaxBy(key) => {
+                // The element with the smallest or largest key (min_by(f), max_by(f)), keyed like sort_by. On a tie
+                // min_by returns the first such element and max_by the last, as in jq; an empty array gives null
+                let is_min = matches!(expr, Expression::MinBy(_));
+                match data {
+                    Value::Array(arr) => match self.keyed_by(key, arr, env) {
+                        Ok(keyed) => {
+                            let compare = |(left, _): &(Value, Value), (right, _): &(Value, Value)| compare_values(left, right);
+                            let found = if is_min { keyed.into_iter().min_by(compare) } else { keyed.into_iter().max_by(compare) };
+                            single(Ok(found.map(|(_, item)| item).unwrap_or(Value::Null)))
+                        },
+                        Err(e) => single(Err(e)),
+                    },
+                    other => {
+                        let name = if is_min { "minimum" } else { "maximum" };
+                        single(Err(QueryError::Type(format!(
+                            "{} ({}) has no {}, as it is not an array (to compare the outputs of a generator, collect them with [...] first)",
+                            type_name(&other), other, name
+                        ))))
                     },
-                    _ => Err(QueryError::Type("keys can only be applied to objects or arrays".to_string())),
                 }
             },
             
@@ -320,145 +627,3140 @@ impl QueryEngine {
                 // Length operation (length)
                 match data {
                     Value::Array(arr) => {
-                        Ok(vec![Value::Number(serde_json::Number::from(arr.len()))])
+                        single(Ok(Value::Number(serde_json::Number::from(arr.len()))))
                     },
                     Value::Object(obj) => {
-                        Ok(vec![Value::Number(serde_json::Number::from(obj.len()))])
+                        single(Ok(Value::Number(serde_json::Number::from(obj.len()))))
                     },
                     Value::String(s) => {
-                        Ok(vec![Value::Number(serde_json::Number::from(s.len()))])
+                        // Unicode scalar values (code points), not bytes or user-perceived characters
+                        single(Ok(Value::Number(serde_json::Number::from(s.chars().count()))))
                     },
-                    _ => Err(QueryError::Type("length can only be applied to arrays, objects, or strings".to_string())),
+                    _ => single(Err(QueryError::Type("length can only be applied to arrays, objects, or strings".to_string()))),
                 }
             },
-        }
-    }
-    
-    /// Recursively collect all values in a JSON structure
-    fn collect_recursive(&self, value: &Value, results: &mut Vec<Value>) {
-        results.push(value.clone());
-        
-        match value {
-            Value::Object(obj) => {
-                for (_, v) in obj {
-                    self.collect_recursive(v, results);
+            
+            Expression::Utf8ByteLength => {
+                // The number of bytes in a string's UTF-8 encoding (utf8bytelength)
+                match data {
+                    Value::String(s) => single(Ok(Value::from(s.len()))),
+                    other => single(Err(QueryError::Type(format!("{} ({}) only strings have UTF-8 byte length", type_name(&other), other)))),
                 }
             },
-            Value::Array(arr) => {
-                for v in arr {
-                    self.collect_recursive(v, results);
+            
+            Expression::Abs => {
+                // Absolute value (abs), negating through arithmetic so integers stay integers
+                match data {
+                    Value::Number(n) if n.as_f64().is_some_and(|f| f < 0.0) => {
+                        single(arithmetic(&Value::from(0), "-", &Value::Number(n)))
+                    },
+                    Value::Number(n) => single(Ok(Value::Number(n))),
+                    other => single(Err(QueryError::Type(format!("{} ({}) has no absolute value", type_name(&other), other)))),
+                }
+            },
+            
+            Expression::GetPath(path) => {
+                // The value at each path (getpath(["a", 0])), null where the path leads nowhere
+                Box::new(self.eval(path, data.clone(), env.clone()).map(move |path| get_path(&data, &path_array(path?)?, true)))
+            },
+            
+            Expression::SetPath(path, value) => {
+                // The input with each path set to each value (setpath(["a", 0]; 1)), creating missing objects and
+                // arrays along the way
+                Box::new(self.eval(value, data.clone(), env.clone()).flat_map(move |value| match value {
+                    Ok(value) => {
+                        let data = data.clone();
+                        let stream: ValueStream<'a> = Box::new(self.eval(path, data.clone(), env.clone())
+                            .map(move |path| set_path(data.clone(), &path_array(path?)?, value.clone())));
+                        stream
+                    },
+                    Err(e) => single(Err(e)),
+                }))
+            },
+            
+            Expression::Delete(target) => {
+                // The input without every path the target refers to (del(.a, .items[].tmp))
+                match self.paths(target, data.clone(), env.clone()) {
+                    Ok(paths) => single(delete_paths(data, paths.into_iter().map(|(path, _)| path).collect())),
+                    Err(e) => single(Err(e)),
+                }
+            },
+            
+            Expression::Literal(value) => {
+                // Literal values ignore their input
+                single(Ok(value.clone()))
+            },
+            
+            Expression::Variable(name) => {
+                // Variable reference ($name), local bindings shadow globals
+                match env.variable(name).or_else(|| self.variables.get(name)) {
+                    Some(value) => single(Ok(value.clone())),
+                    None => single(Err(QueryError::UndefinedVariable(name.clone()))),
                 }
             },
-            _ => {},
-        }
-    }
-}
-
-/// Check if a JSON value is truthy
-fn is_truthy(value: &Value) -> bool {
-    match value {
-        Value::Null => false,
-        Value::Bool(b) => *b,
-        Value::Number(n) => !n.is_f64() || n.as_f64().unwrap() != 0.0,
-        Value::String(s) => !s.is_empty(),
-        Value::Array(arr) => !arr.is_empty(),
-        Value::Object(obj) => !obj.is_empty(),
-    }
-}
-
-/// Compare two JSON values for ordering
-fn compare_values(left: &Value, right: &Value) -> Option<std::cmp::Ordering> {
-    match (left, right) {
-        (Value::Number(l), Value::Number(r)) => {
-            if let (Some(lf), Some(rf)) = (l.as_f64(), r.as_f64()) {
-                lf.partial_cmp(&rf)
-            } else if let (Some(li), Some(ri)) = (l.as_i64(), r.as_i64()) {
-                Some(li.cmp(&ri))
-            } else if let (Some(lu), Some(ru)) = (l.as_u64(), r.as_u64()) {
-                Some(lu.cmp(&ru))
-            } else {
-                None
-            }
-        },
-        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
-        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
-        (Value::Array(l), Value::Array(r)) => {
-            if l.len() != r.len() {
-                return Some(l.len().cmp(&r.len()));
-            }
             
-            for (lv, rv) in l.iter().zip(r.iter()) {
-                if let Some(ord) = compare_values(lv, rv) {
-                    if ord != std::cmp::Ordering::Equal {
-                        return Some(ord);
+            Expression::StringInterp(parts) => {
+                // String interpolation ("a \(expr) b"): one string per combination of interpolated outputs
+                let mut strings = vec![String::new()];
+                
+                for part in parts {
+                    match part {
+                        StringPart::Literal(text) => strings.iter_mut().for_each(|s| s.push_str(text)),
+                        StringPart::Expr(expr) => {
+                            let outputs = match self.collect(expr, data.clone(), env.clone()) {
+                                Ok(outputs) => outputs,
+                                Err(e) => return single(Err(e)),
+                            };
+                            
+                            // Later interpolations vary slowest, like jq
+                            strings = outputs.iter()
+                                .flat_map(|value| {
+                                    let text = value_to_text(value);
+                                    strings.iter().map(move |s| format!("{}{}", s, text))
+                                })
+                                .collect();
+                        },
                     }
-                } else {
-                    return None;
                 }
-            }
+                
+                values(strings.into_iter().map(Value::String).collect())
+            },
             
-            Some(std::cmp::Ordering::Equal)
-        },
-        _ => None,
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
+            Expression::Format(name) => {
+                // Format string (@csv, @base64, ...) turning a value into text
+                single(apply_format(name, &data).map(Value::String))
+            },
+            
+            Expression::Join(separator) => {
+                // Join (join(sep)) the elements of an array into a string
+                Box::new(self.eval(separator, data.clone(), env.clone()).map(move |separator| match (separator?, &data) {
+                    (Value::String(separator), Value::Array(arr)) => {
+                        let parts = arr.iter()
+                            .map(|value| value_to_scalar_string(value)
+                                .ok_or_else(|| QueryError::Type(format!("cannot join with {}", type_name(value)))))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        Ok(Value::String(parts.join(&separator)))
+                    },
+                    (Value::String(_), other) => Err(QueryError::Type(format!("cannot join {} ({}), only an array", type_name(other), other))),
+                    (separator, _) => Err(QueryError::Type(format!("join separator must be a string, not {}", type_name(&separator)))),
+                }))
+            },
+            
+            Expression::Split(separator) => {
+                // Split (split(sep)) a string on a literal separator into an array of strings
+                Box::new(self.eval(separator, data.clone(), env.clone()).map(move |separator| match (&data, separator?) {
+                    (Value::String(s), Value::String(separator)) => Ok(split_string(s, &separator)),
+                    _ => Err(QueryError::Type("split input and separator must be strings".to_string())),
+                }))
+            },
+            
+            Expression::Arithmetic(left, op, right) => {
+                // Arithmetic (expr1 + expr2): every combination of outputs, right side outermost like jq
+                Box::new(self.eval(right, data.clone(), env.clone()).flat_map(move |r| match r {
+                    Ok(r) => {
+                        let stream: ValueStream<'a> = Box::new(self.eval(left, data.clone(), env.clone())
+                            .map(move |l| l.and_then(|l| arithmetic(&l, op, &r))));
+                        stream
+                    },
+                    Err(e) => single(Err(e)),
+                }))
+            },
+            
+            Expression::Comparison(left, op, right) => {
+                // Comparison (expr1 == expr2): every combination of outputs, right side outermost like jq
+                Box::new(self.eval(right, data.clone(), env.clone()).flat_map(move |r| match r {
+                    Ok(r) => {
+                        let stream: ValueStream<'a> = Box::new(self.eval(left, data.clone(), env.clone())
+                            .map(move |l| l.map(|l| Value::Bool(compare(&l, op, &r)))));
+                        stream
+                    },
+                    Err(e) => single(Err(e)),
+                }))
+            },
+            
+            Expression::Comma(left, right) => {
+                // Comma (expr1, expr2): every output of the left side, then every output of the right side
+                Box::new(self.eval(left, data.clone(), env.clone()).chain(self.eval(right, data, env.clone())))
+            },
+            
+            Expression::Alternative(left, right) => {
+                // Alternative (expr1 // expr2): the left side's outputs other than false and null, or the right
+                // side's outputs if there are none. Errors on the left side count as no output, like jq.
+                let truthy: Vec<Value> = self.eval(left, data.clone(), env.clone())
+                    .filter_map(Result::ok)
+                    .filter(is_truthy)
+                    .collect();
+                
+                if truthy.is_empty() {
+                    self.eval(right, data, env.clone())
+                } else {
+                    values(truthy)
+                }
+            },
+            
+            Expression::And(left, right) | Expression::Or(left, right) => {
+                // Boolean operators: for each output of the left side, `and` is false for a falsy value and `or`
+                // is true for a truthy one without evaluating the right side; otherwise the right side decides
+                let is_and = matches!(expr, Expression::And(..));
+                Box::new(self.eval(left, data.clone(), env.clone()).flat_map(move |l| match l {
+                    Ok(l) if is_truthy(&l) != is_and => single(Ok(Value::Bool(!is_and))),
+                    Ok(_) => {
+                        let stream: ValueStream<'a> = Box::new(self.eval(right, data.clone(), env.clone())
+                            .map(|r| r.map(|r| Value::Bool(is_truthy(&r)))));
+                        stream
+                    },
+                    Err(e) => single(Err(e)),
+                }))
+            },
+            
+            Expression::Negate(operand) => {
+                // Unary minus (-expr)
+                Box::new(self.eval(operand, data, env.clone()).map(|value| match value? {
+                    Value::Number(n) => arithmetic(&Value::from(0), "-", &Value::Number(n)),
+                    other => Err(QueryError::Type(format!("{} ({}) cannot be negated", type_name(&other), other))),
+                }))
+            },
+            
+            Expression::Assign(target, source) => {
+                // Assignment (path = value): for each output of the value, evaluated on the input, set every
+                // path the target refers to in the input
+                let paths = match self.paths(target, data.clone(), env.clone()) {
+                    Ok(paths) => paths,
+                    Err(e) => return single(Err(e)),
+                };
+                
+                Box::new(self.eval(source, data.clone(), env.clone()).map(move |value| {
+                    let value = value?;
+                    paths.iter().try_fold(data.clone(), |result, (path, _)| set_path(result, path, value.clone()))
+                }))
+            },
+            
+            Expression::Update(target, update) => {
+                // Update-assignment (path |= f): replace the value at every path the target refers to with the
+                // first output of f on it. Paths where f has no output are deleted, as in jq 1.7.
+                let paths = match self.paths(target, data.clone(), env.clone()) {
+                    Ok(paths) => paths,
+                    Err(e) => return single(Err(e)),
+                };
+                
+                let mut deleted = Vec::new();
+                let updated = paths.into_iter().try_fold(data, |result, (path, _)| {
+                    let current = get_path(&result, &path, false)?;
+                    match self.eval(update, current, env.clone()).next() {
+                        Some(value) => set_path(result, &path, value?),
+                        None => {
+                            deleted.push(path);
+                            Ok(result)
+                        },
+                    }
+                });
+                single(updated.and_then(|result| delete_paths(result, deleted)))
+            },
+            
+            Expression::While(cond, update) => {
+                // While loop (while(cond; update)): emit each value while the condition holds
+                let mut pending = vec![data];
+                let mut updated: Option<Value> = None;
+                let mut iterations = 0;
+                
+                Box::new(std::iter::from_fn(move || {
+                    // Only compute the next values once the previous one has been consumed
+                    if let Some(value) = updated.take() {
+                        match self.collect(update, value.clone(), env.clone()) {
+                            Ok(next) => pending.extend(next.into_iter().rev()),
+                            Err(e) => return Some(Err(e)),
+                        }
+                    }
+                    
+                    while let Some(value) = pending.pop() {
+                        iterations += 1;
+                        if iterations > MAX_LOOP_ITERATIONS {
+                            pending.clear();
+                            return Some(Err(QueryError::IterationLimit("while".to_string(), MAX_LOOP_ITERATIONS)));
+                        }
+                        
+                        match self.condition_holds(cond, &value, env.clone()) {
+                            Ok(true) => {
+                                updated = Some(value.clone());
+                                return Some(Ok(value));
+                            },
+                            Ok(false) => {},
+                            Err(e) => {
+                                pending.clear();
+                                return Some(Err(e));
+                            },
+                        }
+                    }
+                    
+                    None
+                }))
+            },
+            
+            Expression::Until(cond, update) => {
+                // Until loop (until(cond; update)): keep updating until the condition holds, then emit
+                let mut pending = vec![data];
+                let mut iterations = 0;
+                
+                Box::new(std::iter::from_fn(move || {
+                    while let Some(value) = pending.pop() {
+                        iterations += 1;
+                        if iterations > MAX_LOOP_ITERATIONS {
+                            pending.clear();
+                            return Some(Err(QueryError::IterationLimit("until".to_string(), MAX_LOOP_ITERATIONS)));
+                        }
+                        
+                        match self.condition_holds(cond, &value, env.clone()) {
+                            Ok(true) => return Some(Ok(value)),
+                            Ok(false) => match self.collect(update, value.clone(), env.clone()) {
+                                Ok(next) => pending.extend(next.into_iter().rev()),
+                                Err(e) => {
+                                    pending.clear();
+                                    return Some(Err(e));
+                                },
+                            },
+                            Err(e) => {
+                                pending.clear();
+                                return Some(Err(e));
+                            },
+                        }
+                    }
+                    
+                    None
+                }))
+            },
+            
+            Expression::Repeat(expr) => {
+                // Repeat (repeat(f)): emit the input, then repeat on each output of f, depth first.
+                // This never ends on its own, so values are only produced as they are consumed.
+                let mut first = Some(data);
+                let mut expand: Option<Value> = None;
+                let mut stack: Vec<ValueStream<'a>> = Vec::new();
+                
+                Box::new(std::iter::from_fn(move || {
+                    if let Some(value) = first.take() {
+                        expand = Some(value.clone());
+                        return Some(Ok(value));
+                    }
+                    
+                    if let Some(value) = expand.take() {
+                        stack.push(self.eval(expr, value, env.clone()));
+                    }
+                    
+                    while let Some(top) = stack.last_mut() {
+                        match top.next() {
+                            Some(Ok(value)) => {
+                                expand = Some(value.clone());
+                                return Some(Ok(value));
+                            },
+                            Some(Err(e)) => {
+                                stack.clear();
+                                return Some(Err(e));
+                            },
+                            None => {
+                                stack.pop();
+                            },
+                        }
+                    }
+                    
+                    None
+                }))
+            },
+            
+            Expression::Limit(count, expr) => {
+                // Limit (limit(n; f)): at most the first n outputs of f
+                Box::new(self.eval(count, data.clone(), env.clone()).flat_map(move |count| match count {
+                    Ok(Value::Number(n)) => {
+                        let n = n.as_f64().unwrap_or(0.0);
+                        if n < 0.0 {
+                            self.eval(expr, data.clone(), env.clone())
+                        } else {
+                            Box::new(self.eval(expr, data.clone(), env.clone()).take(n.ceil() as usize))
+                        }
+                    },
+                    Ok(other) => single(Err(QueryError::Type(format!("limit count must be a number, not {}", type_name(&other))))),
+                    Err(e) => single(Err(e)),
+                }))
+            },
+            
+            Expression::If(cond, then_branch, else_branch) => {
+                // Conditional (if cond then a else b end): a branch is chosen for each output of the condition
+                Box::new(self.eval(cond, data.clone(), env.clone()).flat_map(move |cond| match cond {
+                    Ok(cond) if is_truthy(&cond) => self.eval(then_branch, data.clone(), env.clone()),
+                    Ok(_) => self.eval(else_branch, data.clone(), env.clone()),
+                    Err(e) => single(Err(e)),
+                }))
+            },
+            
+            Expression::Binding(source, pattern, body) => {
+                // Variable binding (expr as $x | body): the body runs on the same input once per output of expr
+                Box::new(self.eval(source, data.clone(), env.clone()).flat_map(move |value| {
+                    match value.and_then(|value| bind_pattern(pattern, value, env.clone())) {
+                        Ok(scope) => self.eval(body, data.clone(), scope),
+                        Err(e) => single(Err(e)),
+                    }
+                }))
+            },
+            
+            Expression::Reduce(source, pattern, init, update) => {
+                // Reduction (reduce source as $x (init; update)): starting from each output of init, fold every output
+                // of source into the state with the last output of update (null if it has none). The source is
+                // consumed as it is produced, so `reduce inputs as $x (...)` holds one input at a time.
+                let input = data.clone();
+                Box::new(self.eval(init, data, env.clone()).map(move |state| {
+                    self.eval(source, input.clone(), env.clone()).try_fold(state?, |state, item| {
+                        let scope = bind_pattern(pattern, item?, env.clone())?;
+                        self.eval(update, state, scope).try_fold(Value::Null, |_, value| value)
+                    })
+                }))
+            },
+            
+            Expression::Foreach(source, pattern, init, update, extract) => {
+                // Like reduce, but emitting each intermediate state (foreach source as $x (init; update; extract)),
+                // or the outputs of extract on it. Every output of update is emitted, and the last becomes the state.
+                let input = data.clone();
+                Box::new(self.eval(init, data, env.clone()).flat_map(move |state| -> ValueStream<'a> {
+                    let mut state = match state {
+                        Ok(state) => state,
+                        Err(e) => return single(Err(e)),
+                    };
+                    let env = env.clone();
+                    let mut items = self.eval(source, input.clone(), env.clone());
+                    let mut pending = empty();
+                    
+                    Box::new(std::iter::from_fn(move || loop {
+                        if let Some(value) = pending.next() {
+                            return Some(value);
+                        }
+                        
+                        let scope = match items.next()?.and_then(|item| bind_pattern(pattern, item, env.clone())) {
+                            Ok(scope) => scope,
+                            Err(e) => return Some(Err(e)),
+                        };
+                        let updates = match self.collect(update, state.clone(), scope.clone()) {
+                            Ok(updates) => updates,
+                            Err(e) => return Some(Err(e)),
+                        };
+                        if let Some(last) = updates.last() {
+                            state = last.clone();
+                        }
+                        
+                        pending = match extract {
+                            Some(extract) => Box::new(updates.into_iter().flat_map(move |value| self.eval(extract, value, scope.clone()))),
+                            None => values(updates),
+                        };
+                    }))
+                }))
+            },
+            
+            Expression::BindingAlternatives(source, patterns, body) => {
+                // Destructuring alternatives (expr as {a: $x} ?// [$x] | body): each pattern is tried in turn, moving
+                // on when it doesn't fit the value or the body fails with it; an error with the last one stands.
+                // Variables of every pattern are bound, those the chosen pattern lacks to null.
+                let mut names = Vec::new();
+                for pattern in patterns {
+                    pattern_variables(pattern, &mut names);
+                }
+                
+                Box::new(self.eval(source, data.clone(), env.clone()).flat_map(move |value| -> ValueStream<'a> {
+                    let value = match value {
+                        Ok(value) => value,
+                        Err(e) => return single(Err(e)),
+                    };
+                    
+                    let mut outputs = Vec::new();
+                    for (i, pattern) in patterns.iter().enumerate() {
+                        let scope = names.iter().fold(env.clone(), |scope, name| Rc::new(Env::Variable(name, Value::Null, scope)));
+                        let result = bind_pattern(pattern, value.clone(), scope).and_then(|scope| {
+                            for output in self.eval(body, data.clone(), scope) {
+                                outputs.push(output?);
+                            }
+                            Ok(())
+                        });
+                        
+                        match result {
+                            Err(e) if i + 1 == patterns.len() => return Box::new(values(outputs).chain(std::iter::once(Err(e)))),
+                            Err(_) => continue,
+                            Ok(()) => break,
+                        }
+                    }
+                    values(outputs)
+                }))
+            },
+            
+            Expression::FunctionDef(def, rest) => {
+                // Function definition (def name(params): body; rest): visible to the rest and to its own body
+                self.eval(rest, data, Rc::new(Env::Function(def, env)))
+            },
+            
+            Expression::FunctionCall(name, args) => {
+                // Call of a user-defined function or of a filter parameter
+                match env.function(name, args.len()) {
+                    Some(Callable::Function(def, scope)) => self.call(def, args, 0, scope, data, env),
+                    Some(Callable::Closure(body, scope)) => self.eval(body, data, scope),
+                    None => single(Err(QueryError::UndefinedFunction(name.clone(), args.len()))),
+                }
+            },
+            
+            Expression::First(expr) => {
+                // First output of a generator (first(expr)); stops evaluating after it
+                match self.eval(expr, data, env.clone()).next() {
+                    Some(value) => single(value),
+                    None => empty(),
+                }
+            },
+            
+            Expression::Last(expr) => {
+                // Last output of a generator (last(expr))
+                let mut last = None;
+                for value in self.eval(expr, data, env.clone()) {
+                    match value {
+                        Ok(value) => last = Some(value),
+                        Err(e) => return single(Err(e)),
+                    }
+                }
+                
+                match last {
+                    Some(value) => single(Ok(value)),
+                    None => empty(),
+                }
+            },
+            
+            Expression::Range(from, upto) => {
+                // Range generator (range(upto) or range(from; upto)), produced one number at a time
+                Box::new(self.eval(from, data.clone(), env.clone()).flat_map(move |from| {
+                    let from = match from {
+                        Ok(from) => from,
+                        Err(e) => return single(Err(e)),
+                    };
+                    
+                    Box::new(self.eval(upto, data.clone(), env.clone()).flat_map(move |upto| {
+                        match (&from, upto) {
+                            (Value::Number(from), Ok(Value::Number(upto))) => {
+                                let upto = upto.as_f64().unwrap_or(0.0);
+                                let mut current = from.as_f64().unwrap_or(0.0);
+                                
+                                Box::new(std::iter::from_fn(move || {
+                                    if current < upto {
+                                        let value = number_to_value(current);
+                                        current += 1.0;
+                                        Some(Ok(value))
+                                    } else {
+                                        None
+                                    }
+                                })) as ValueStream<'a>
+                            },
+                            (_, Err(e)) => single(Err(e)),
+                            _ => single(Err(QueryError::Type("range bounds must be numeric".to_string()))),
+                        }
+                    })) as ValueStream<'a>
+                }))
+            },
+        }
+    }
+    
+    /// Bind the parameters of a function from `index` onwards, then evaluate its body.
+    /// Filter parameters are passed by name; `$name` parameters are bound to each output of their argument.
+    fn call<'a>(&'a self, def: &'a FunctionDef, args: &'a [Expression], index: usize, scope: Rc<Env<'a>>, data: Value, caller: Rc<Env<'a>>) -> ValueStream<'a> {
+        let (param, arg) = match (def.params.get(index), args.get(index)) {
+            (Some(param), Some(arg)) => (param, arg),
+            _ => return self.eval(&def.body, data, scope),
+        };
+        
+        let name = param.strip_prefix('$').unwrap_or(param);
+        let scope = Rc::new(Env::Closure(name, arg, caller.clone(), scope));
+        
+        if param.starts_with('$') {
+            Box::new(self.eval(arg, data.clone(), caller.clone()).flat_map(move |value| match value {
+                Ok(value) => {
+                    let scope = Rc::new(Env::Variable(name, value, scope.clone()));
+                    self.call(def, args, index + 1, scope, data.clone(), caller.clone())
+                },
+                Err(e) => single(Err(e)),
+            }))
+        } else {
+            self.call(def, args, index + 1, scope, data, caller)
+        }
+    }
+    
+    /// Evaluate an expression and collect all of its outputs
+    fn collect<'a>(&'a self, expr: &'a Expression, data: Value, env: Rc<Env<'a>>) -> QueryResult {
+        self.eval(expr, data, env).collect()
+    }
+    
+    /// The paths (arrays of object keys and array indices) that a path expression such as `.a[0]`,
+    /// `.a, .b` or `.[] | select(.x)` refers to in `data`, each with the value found there
+    fn paths<'a>(&'a self, expr: &'a Expression, data: Value, env: Rc<Env<'a>>) -> Result<Vec<(Vec<Value>, Value)>, QueryError> {
+        let paths = match expr {
+            Expression::Identity => vec![(Vec::new(), data)],
+            Expression::Empty => Vec::new(),
+            // Paths may lead through missing values, which are created when the path is set
+            Expression::Property(name) if data.is_null() => vec![(vec![Value::String(name.clone())], Value::Null)],
+            Expression::Index(index) if data.is_null() => vec![(vec![Value::from(*index)], Value::Null)],
+            Expression::Property(name) => {
+                let value = self.collect(expr, data, env)?.pop().unwrap_or(Value::Null);
+                vec![(vec![Value::String(name.clone())], value)]
+            },
+            Expression::Index(index) => {
+                let value = self.collect(expr, data, env)?.pop().unwrap_or(Value::Null);
+                vec![(vec![Value::from(*index)], value)]
+            },
+            Expression::ArrayIteration => match data {
+                Value::Array(arr) => arr.into_iter().enumerate().map(|(i, v)| (vec![Value::from(i)], v)).collect(),
+                Value::Object(obj) => obj.into_iter().map(|(k, v)| (vec![Value::String(k)], v)).collect(),
+                _ => {
+                    self.collect(expr, data, env)?;
+                    Vec::new()
+                },
+            },
+            Expression::RecursiveDescent => {
+                // Parents before their children, like the values of `..`
+                let mut paths = Vec::new();
+                let mut stack = vec![(Vec::new(), data)];
+                
+                while let Some((path, value)) = stack.pop() {
+                    if path.len() > self.max_depth {
+                        return Err(QueryError::DepthLimit(self.max_depth));
+                    }
+                    
+                    let children: Vec<(Value, Value)> = match &value {
+                        Value::Object(obj) => obj.iter().map(|(k, v)| (Value::String(k.clone()), v.clone())).collect(),
+                        Value::Array(arr) => arr.iter().enumerate().map(|(i, v)| (Value::from(i), v.clone())).collect(),
+                        _ => Vec::new(),
+                    };
+                    for (key, child) in children.into_iter().rev() {
+                        let mut child_path = path.clone();
+                        child_path.push(key);
+                        stack.push((child_path, child));
+                    }
+                    paths.push((path, value));
+                }
+                paths
+            },
+            Expression::Pipe(left, right) => {
+                let mut paths = Vec::new();
+                for (prefix, value) in self.paths(left, data, env.clone())? {
+                    for (suffix, value) in self.paths(right, value, env.clone())? {
+                        let mut path = prefix.clone();
+                        path.extend(suffix);
+                        paths.push((path, value));
+                    }
+                }
+                paths
+            },
+            Expression::Comma(left, right) => {
+                let mut paths = self.paths(left, data.clone(), env.clone())?;
+                paths.extend(self.paths(right, data, env)?);
+                paths
+            },
+            Expression::If(cond, then_branch, else_branch) => {
+                let mut paths = Vec::new();
+                for cond in self.collect(cond, data.clone(), env.clone())? {
+                    let branch = if is_truthy(&cond) { then_branch } else { else_branch };
+                    paths.extend(self.paths(branch, data.clone(), env.clone())?);
+                }
+                paths
+            },
+            Expression::Alternative(left, right) => {
+                let truthy: Vec<(Vec<Value>, Value)> = self.paths(left, data.clone(), env.clone())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|(_, value)| is_truthy(value))
+                    .collect();
+                
+                if truthy.is_empty() {
+                    self.paths(right, data, env)?
+                } else {
+                    truthy
+                }
+            },
+            Expression::GetPath(path) => {
+                let mut paths = Vec::new();
+                for path in self.collect(path, data.clone(), env)? {
+                    let path = path_array(path)?;
+                    let value = get_path(&data, &path, true)?;
+                    paths.push((path, value));
+                }
+                paths
+            },
+            // jq accepts these as paths, but they are not implemented in path expressions yet
+            Expression::Slice(..) => return Err(QueryError::Unsupported("a slice as a path".to_string())),
+            Expression::FunctionCall(name, args) => {
+                return Err(QueryError::Unsupported(format!("calling {}/{} in a path", name, args.len())));
+            },
+            Expression::FunctionDef(..) => return Err(QueryError::Unsupported("a function definition in a path".to_string())),
+            Expression::Binding(..) => return Err(QueryError::Unsupported("a variable binding in a path".to_string())),
+            Expression::First(_) | Expression::Last(_) | Expression::Limit(..) | Expression::Repeat(_) => {
+                return Err(QueryError::Unsupported("a generator such as first, last, limit or recurse in a path".to_string()));
+            },
+            _ => return Err(QueryError::Path(format!("{:?} is not a path expression", expr))),
+        };
+        
+        Ok(paths)
+    }
+    
+    /// Map each element of an array on rayon's thread pool, keeping the outputs in order. When elements fail,
+    /// the error is always the one from the lowest failing index, whichever thread finishes first: elements
+    /// after a known failure are skipped, but earlier ones still run in case one of them fails too.
+    #[cfg(feature = "parallel")]
+    fn par_map(&self, expr: &Expression, arr: Vec<Value>) -> Result<Vec<Value>, QueryError> {
+        use rayon::prelude::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        
+        let first_failure = AtomicUsize::new(usize::MAX);
+        let outputs: Vec<Option<QueryResult>> = arr
+            .into_par_iter()
+            .enumerate()
+            .map(|(index, item)| {
+                if index > first_failure.load(Ordering::Relaxed) {
+                    return None;
+                }
+                let outputs = self.collect(expr, item, Rc::new(Env::Root));
+                if outputs.is_err() {
+                    first_failure.fetch_min(index, Ordering::Relaxed);
+                }
+                Some(outputs)
+            })
+            .collect();
+        
+        // Skipped elements all come after the first failure, so its error is reached before any of them
+        let mut results = Vec::with_capacity(outputs.len());
+        for element in outputs.into_iter().flatten() {
+            results.extend(element?);
+        }
+        Ok(results)
+    }
+    
+    /// Pair each element of an array with its sort key, the array of all outputs of `key` on it. Each key is
+    /// evaluated exactly once, rather than again in every comparison.
+    fn keyed_by<'a>(&'a self, key: &'a Expression, arr: Vec<Value>, env: Rc<Env<'a>>) -> Result<Vec<(Value, Value)>, QueryError> {
+        arr.into_iter()
+            .map(|item| Ok((Value::Array(self.collect(key, item.clone(), env.clone())?), item)))
+            .collect()
+    }
+    
+    /// Replace the matches of a regex in a string with the outputs of `replacement`, one string for each
+    /// combination of replacements when it has several, like jq's `sub`
+    fn substitute<'a>(&'a self, regex: &Regex, flags: &RegexFlags, input: &str, replacement: &'a Expression, env: Rc<Env<'a>>) -> QueryResult {
+        let mut results = vec![String::new()];
+        let mut end = 0;
+        
+        let matches = regex.captures_iter(input)
+            .filter(|captures| !(flags.skip_empty && captures[0].is_empty()))
+            .take(if flags.global { usize::MAX } else { 1 });
+        for captures in matches {
+            let whole = captures.get(0).expect("a match always has the whole match as group 0");
+            let named: Map<String, Value> = regex.capture_names().flatten()
+                .map(|name| (name.to_string(), captures.name(name).map_or(Value::Null, |group| Value::from(group.as_str()))))
+                .collect();
+            
+            let replacements = self.collect(replacement, Value::Object(named), env.clone())?
+                .into_iter()
+                .map(|replacement| match replacement {
+                    Value::String(replacement) => Ok(replacement),
+                    other => Err(QueryError::Type(format!("{} ({}) cannot be a replacement, as it is not a string", type_name(&other), other))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            
+            let before = &input[end..whole.start()];
+            results = results.iter()
+                .flat_map(|result| replacements.iter().map(move |replacement| format!("{}{}{}", result, before, replacement)))
+                .collect();
+            end = whole.end();
+        }
+        
+        Ok(results.into_iter().map(|result| Value::String(result + &input[end..])).collect())
+    }
+    
+    /// The outputs of `walk(f)` on a value: arrays and objects have `walk(f)` applied to their elements first,
+    /// with an array collecting every output of each element and an object keeping the first of each value
+    fn walk<'a>(&'a self, f: &'a Expression, data: Value, env: Rc<Env<'a>>, depth: usize) -> QueryResult {
+        if depth > self.max_depth {
+            return Err(QueryError::DepthLimit(self.max_depth));
+        }
+        
+        let data = match data {
+            Value::Array(arr) => {
+                let mut walked = Vec::with_capacity(arr.len());
+                for item in arr {
+                    walked.extend(self.walk(f, item, env.clone(), depth + 1)?);
+                }
+                Value::Array(walked)
+            },
+            Value::Object(obj) => {
+                let mut walked = Map::new();
+                for (key, value) in obj {
+                    if let Some(value) = self.walk(f, value, env.clone(), depth + 1)?.into_iter().next() {
+                        walked.insert(key, value);
+                    }
+                }
+                Value::Object(walked)
+            },
+            other => other,
+        };
+        self.collect(f, data, env)
+    }
+    
+    /// Check whether any output of a condition is truthy
+    fn condition_holds<'a>(&'a self, cond: &'a Expression, value: &Value, env: Rc<Env<'a>>) -> Result<bool, QueryError> {
+        for result in self.eval(cond, value.clone(), env) {
+            if is_truthy(&result?) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+    
+    /// Every value in a JSON structure, parents before their children, produced as they are consumed so
+    /// `first(..)` does not walk the whole structure. This uses an explicit stack rather than recursion so
+    /// deeply nested input cannot overflow the call stack. With a `limit`, values more than that many levels
+    /// below the top are left out.
+    fn recurse<'a>(&self, value: Value, limit: Option<usize>) -> ValueStream<'a> {
+        let max_depth = self.max_depth;
+        let mut stack = vec![(value, 0)];
+        
+        Box::new(std::iter::from_fn(move || {
+            let (value, depth) = stack.pop()?;
+            if depth > max_depth {
+                stack.clear();
+                return Some(Err(QueryError::DepthLimit(max_depth)));
+            }
+            
+            // Children are pushed in reverse so they are visited in document order
+            match &value {
+                _ if limit.is_some_and(|limit| depth >= limit) => {},
+                Value::Object(obj) => stack.extend(obj.values().rev().map(|v| (v.clone(), depth + 1))),
+                Value::Array(arr) => stack.extend(arr.iter().rev().map(|v| (v.clone(), depth + 1))),
+                _ => {},
+            }
+            Some(Ok(value))
+        }))
+    }
+}
+
+/// Bind the variables of a destructuring pattern to the matching parts of a value.
+/// Missing elements and keys (and null in place of a structure) bind null, like jq.
+fn bind_pattern<'a>(pattern: &'a Pattern, value: Value, env: Rc<Env<'a>>) -> Result<Rc<Env<'a>>, QueryError> {
+    match pattern {
+        Pattern::Variable(name) => Ok(Rc::new(Env::Variable(name, value, env))),
+        Pattern::Array(elements) => {
+            let mut items = match value {
+                Value::Array(arr) => arr,
+                Value::Null => Vec::new(),
+                other => return Err(QueryError::Type(format!("cannot index {} with number", type_name(&other)))),
+            };
+            items.resize(elements.len().max(items.len()), Value::Null);
+            
+            elements.iter()
+                .zip(items)
+                .try_fold(env, |env, (element, item)| bind_pattern(element, item, env))
+        },
+        Pattern::Object(entries) => {
+            let obj = match value {
+                Value::Object(obj) => obj,
+                Value::Null => Map::new(),
+                other => return Err(QueryError::Type(format!("cannot index {} with string", type_name(&other)))),
+            };
+            
+            entries.iter().try_fold(env, |env, (key, entry)| {
+                bind_pattern(entry, obj.get(key).cloned().unwrap_or(Value::Null), env)
+            })
+        },
+    }
+}
+
+/// Collect the names of the variables a pattern binds, without duplicates
+fn pattern_variables<'a>(pattern: &'a Pattern, names: &mut Vec<&'a str>) {
+    match pattern {
+        Pattern::Variable(name) => {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        },
+        Pattern::Array(elements) => elements.iter().for_each(|element| pattern_variables(element, names)),
+        Pattern::Object(entries) => entries.iter().for_each(|(_, entry)| pattern_variables(entry, names)),
+    }
+}
+
+/// A stream containing a single result
+fn single<'a>(value: Result<Value, QueryError>) -> ValueStream<'a> {
+    Box::new(std::iter::once(value))
+}
+
+/// A stream containing no results
+fn empty<'a>() -> ValueStream<'a> {
+    Box::new(std::iter::empty())
+}
+
+/// A stream over already computed values
+fn values<'a>(values: Vec<Value>) -> ValueStream<'a> {
+    Box::new(values.into_iter().map(Ok))
+}
+
+/// Resolve optional, possibly negative slice bounds against a length, clamped so that `from <= to <= len`
+fn slice_bounds(len: usize, start: Option<i64>, end: Option<i64>) -> (usize, usize) {
+    let resolve = |index: i64| {
+        if index < 0 {
+            len.saturating_sub(index.unsigned_abs() as usize)
+        } else {
+            (index as usize).min(len)
+        }
+    };
+    
+    let to = end.map_or(len, resolve);
+    let from = start.map_or(0, resolve).min(to);
+    (from, to)
+}
+
+/// How a field access is written in a query: `.name`, or `."name"` when the name is not an identifier
+fn field_syntax(name: &str) -> String {
+    let is_identifier = name.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    
+    if is_identifier {
+        format!(".{}", name)
+    } else {
+        format!(".{}", Value::String(name.to_string()))
+    }
+}
+
+/// Set the value at a path (an array of object keys and array indices) in a JSON value, creating
+/// objects and arrays for missing parts of the path and padding arrays with nulls
+fn set_path(root: Value, path: &[Value], value: Value) -> Result<Value, QueryError> {
+    let Some((key, rest)) = path.split_first() else {
+        return Ok(value);
+    };
+    
+    match (root, key) {
+        (Value::Object(mut obj), Value::String(key)) => {
+            let child = obj.remove(key).unwrap_or(Value::Null);
+            obj.insert(key.clone(), set_path(child, rest, value)?);
+            Ok(Value::Object(obj))
+        },
+        (Value::Null, Value::String(_)) => set_path(Value::Object(Map::new()), path, value),
+        (Value::Array(mut arr), Value::Number(n)) => {
+            let index = n.as_f64().unwrap_or(0.0) as i64;
+            let index = if index < 0 { index + arr.len() as i64 } else { index };
+            if index < 0 {
+                return Err(QueryError::Index("out of bounds negative array index".to_string()));
+            }
+            
+            let index = index as usize;
+            if index >= arr.len() {
+                arr.resize(index + 1, Value::Null);
+            }
+            let child = std::mem::take(&mut arr[index]);
+            arr[index] = set_path(child, rest, value)?;
+            Ok(Value::Array(arr))
+        },
+        (Value::Null, Value::Number(_)) => set_path(Value::Array(Vec::new()), path, value),
+        (root, key) => Err(QueryError::Type(format!("cannot index {} with {}", type_name(&root), key))),
+    }
+}
+
+/// Delete the values at several paths. Later paths are deleted first, so removing an array element doesn't
+/// shift the indices of those still to go.
+fn delete_paths(root: Value, paths: Vec<Vec<Value>>) -> Result<Value, QueryError> {
+    let mut paths: Vec<Value> = paths.into_iter().map(Value::Array).collect();
+    paths.sort_by(compare_values);
+    paths.dedup();
+    
+    paths.iter().rev().try_fold(root, |result, path| match path {
+        Value::Array(path) => delete_path(result, path),
+        _ => unreachable!("paths are arrays"),
+    })
+}
+
+/// Parse a string as a JSON number for tonumber, keeping integers as integers
+fn parse_number(s: &str) -> Result<Value, QueryError> {
+    match serde_json::from_str::<serde_json::Number>(s) {
+        Ok(n) => Ok(Value::Number(n)),
+        Err(_) => Err(QueryError::Type(format!("string ({}) cannot be parsed as a number", Value::String(s.to_string())))),
+    }
+}
+
+/// An entry of to_entries: `{"key": key, "value": value}`
+fn entry(key: Value, value: Value) -> Value {
+    let mut entry = Map::new();
+    entry.insert("key".to_string(), key);
+    entry.insert("value".to_string(), value);
+    Value::Object(entry)
+}
+
+/// The key and value of an entry for from_entries. Like jq, this falls back to `k`, `name`, `Name`, `K` or
+/// `Key` (whichever is first set to something other than null or false) for a null `key`, and to `v` for
+/// a missing `value`. Keys that aren't strings are written as JSON.
+fn from_entry(entry: Value) -> Result<(String, Value), QueryError> {
+    let Value::Object(mut entry) = entry else {
+        return Err(QueryError::Type(format!("{} ({}) is not an entry object", type_name(&entry), entry)));
+    };
+    
+    let key = match entry.get("key") {
+        Some(key) if !key.is_null() => key.clone(),
+        _ => ["k", "name", "Name", "K", "Key"].iter()
+            .filter_map(|name| entry.get(*name))
+            .find(|key| !matches!(key, Value::Null | Value::Bool(false)))
+            .cloned()
+            .unwrap_or(Value::Null),
+    };
+    let key = match key {
+        Value::String(key) => key,
+        Value::Object(_) | Value::Array(_) => {
+            return Err(QueryError::Type(format!("{} ({}) cannot be used as an object key", type_name(&key), key)));
+        },
+        other => other.to_string(),
+    };
+    
+    let value = entry.remove("value").or_else(|| entry.remove("v")).unwrap_or(Value::Null);
+    Ok((key, value))
+}
+
+/// Delete the value at a path of object keys and array indices. Deleting something that isn't there
+/// leaves the value as it is, and deleting the empty path (the value itself) gives null.
+fn delete_path(root: Value, path: &[Value]) -> Result<Value, QueryError> {
+    let Some((key, rest)) = path.split_first() else {
+        return Ok(Value::Null);
+    };
+    
+    match (root, key) {
+        (Value::Null, Value::String(_) | Value::Number(_)) => Ok(Value::Null),
+        (Value::Object(mut obj), Value::String(key)) => {
+            if let Some(child) = obj.remove(key) {
+                if !rest.is_empty() {
+                    obj.insert(key.clone(), delete_path(child, rest)?);
+                }
+            }
+            Ok(Value::Object(obj))
+        },
+        (Value::Array(mut arr), Value::Number(n)) => {
+            let index = n.as_f64().unwrap_or(0.0) as i64;
+            let index = if index < 0 { index + arr.len() as i64 } else { index };
+            if let Some(index) = usize::try_from(index).ok().filter(|&index| index < arr.len()) {
+                if rest.is_empty() {
+                    arr.remove(index);
+                } else {
+                    let child = std::mem::take(&mut arr[index]);
+                    arr[index] = delete_path(child, rest)?;
+                }
+            }
+            Ok(Value::Array(arr))
+        },
+        (root, key) => Err(QueryError::Type(format!("cannot delete field at {} of {}", key, type_name(&root)))),
+    }
+}
+
+/// Get the value at a path of object keys and array indices. A missing key, an index out of range or a
+/// null along the way gives null; indexing a value of the wrong type (such as a string with a number) is
+/// an error when `strict` is set, and null otherwise.
+fn get_path(root: &Value, path: &[Value], strict: bool) -> Result<Value, QueryError> {
+    let Some((key, rest)) = path.split_first() else {
+        return Ok(root.clone());
+    };
+    
+    let child = match (root, key) {
+        (Value::Null, Value::String(_) | Value::Number(_)) => return Ok(Value::Null),
+        (Value::Object(obj), Value::String(key)) => obj.get(key),
+        (Value::Array(arr), Value::Number(n)) => {
+            let index = n.as_f64().unwrap_or(0.0) as i64;
+            let index = if index < 0 { index + arr.len() as i64 } else { index };
+            usize::try_from(index).ok().and_then(|index| arr.get(index))
+        },
+        (root, key) if strict => return Err(QueryError::Type(format!("cannot index {} with {}", type_name(root), key))),
+        _ => return Ok(Value::Null),
+    };
+    
+    match child {
+        Some(child) => get_path(child, rest, strict),
+        None => Ok(Value::Null),
+    }
+}
+
+/// Unwrap the array of keys and indices given as a path to getpath or setpath
+fn path_array(path: Value) -> Result<Vec<Value>, QueryError> {
+    match path {
+        Value::Array(path) => Ok(path),
+        other => Err(QueryError::Path(format!("a path must be an array, not {} ({})", type_name(&other), other))),
+    }
+}
+
+/// Append the streaming events for a value at `path`, children first: each scalar or empty array or object
+/// is a `[path, value]` event, and a non-empty array or object ends with `[path of its last child]`
+fn stream_events(value: Value, path: &mut Vec<Value>, events: &mut Vec<Value>, max_depth: usize) -> Result<(), QueryError> {
+    if path.len() > max_depth {
+        return Err(QueryError::DepthLimit(max_depth));
+    }
+    
+    let children: Vec<(Value, Value)> = match value {
+        Value::Array(arr) if !arr.is_empty() => arr.into_iter().enumerate().map(|(i, v)| (Value::from(i), v)).collect(),
+        Value::Object(obj) if !obj.is_empty() => obj.into_iter().map(|(k, v)| (Value::String(k), v)).collect(),
+        leaf => {
+            events.push(Value::Array(vec![Value::Array(path.clone()), leaf]));
+            return Ok(());
+        },
+    };
+    
+    let mut last_key = None;
+    for (key, child) in children {
+        path.push(key);
+        stream_events(child, path, events, max_depth)?;
+        last_key = path.pop();
+    }
+    
+    let mut closing = path.clone();
+    closing.extend(last_key);
+    events.push(Value::Array(vec![Value::Array(closing)]));
+    Ok(())
+}
+
+/// The flags of a regex that change how it is applied rather than what it matches
+struct RegexFlags {
+    /// `g`: every match rather than only the first
+    global: bool,
+    /// `n`: leave out empty matches
+    skip_empty: bool,
+}
+
+/// Compile a regex with jq's flags: `i` (case-insensitive), `x` (extended, ignoring whitespace and
+/// comments), `s` (`.` matches newlines), `g` and `n`. Null flags are the same as none.
+fn compile_regex(regex: &Value, flags: &Value) -> Result<(Regex, RegexFlags), QueryError> {
+    let pattern = match regex {
+        Value::String(pattern) => pattern,
+        other => return Err(QueryError::Type(format!("{} ({}) cannot be used as a regex, as it is not a string", type_name(other), other))),
+    };
+    let flags = match flags {
+        Value::String(flags) => flags.as_str(),
+        Value::Null => "",
+        other => return Err(QueryError::Type(format!("regex flags must be a string, not {} ({})", type_name(other), other))),
+    };
+    
+    let mut builder = RegexBuilder::new(pattern);
+    let mut options = RegexFlags { global: false, skip_empty: false };
+    for flag in flags.chars() {
+        match flag {
+            'i' => {
+                builder.case_insensitive(true);
+            },
+            'x' => {
+                builder.ignore_whitespace(true);
+            },
+            's' => {
+                builder.dot_matches_new_line(true);
+            },
+            'g' => options.global = true,
+            'n' => options.skip_empty = true,
+            other => return Err(QueryError::Type(format!("{} is not a valid regex flag", other))),
+        }
+    }
+    
+    let regex = builder.build().map_err(|e| QueryError::Type(format!("{} is not a valid regex: {}", regex, e)))?;
+    Ok((regex, options))
+}
+
+/// The match objects for a regex on a string, like jq's `match`: each has the `offset`, `length` and `string`
+/// of the match and its `captures`, with offsets and lengths counted in characters
+fn regex_matches(regex: &Regex, flags: &RegexFlags, input: &str) -> Vec<Value> {
+    let chars = |text: &str| Value::from(text.chars().count());
+    let part = |start: usize, text: &str| {
+        let mut part = Map::new();
+        part.insert("offset".to_string(), chars(&input[..start]));
+        part.insert("length".to_string(), chars(text));
+        part.insert("string".to_string(), Value::String(text.to_string()));
+        part
+    };
+    
+    regex.captures_iter(input)
+        .filter(|captures| !(flags.skip_empty && captures[0].is_empty()))
+        .take(if flags.global { usize::MAX } else { 1 })
+        .map(|captures| {
+            let whole = captures.get(0).expect("a match always has the whole match as group 0");
+            let groups = regex.capture_names().enumerate().skip(1).map(|(i, name)| {
+                let mut group = match captures.get(i) {
+                    Some(group) => part(group.start(), group.as_str()),
+                    None => {
+                        let mut group = Map::new();
+                        group.insert("offset".to_string(), Value::from(-1));
+                        group.insert("length".to_string(), Value::from(0));
+                        group.insert("string".to_string(), Value::Null);
+                        group
+                    },
+                };
+                group.insert("name".to_string(), name.map_or(Value::Null, Value::from));
+                Value::Object(group)
+            });
+            
+            let mut matched = part(whole.start(), whole.as_str());
+            matched.insert("captures".to_string(), Value::Array(groups.collect()));
+            Value::Object(matched)
+        })
+        .collect()
+}
+
+/// Check if a JSON value is truthy
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => !n.is_f64() || n.as_f64().unwrap() != 0.0,
+        Value::String(s) => !s.is_empty(),
+        Value::Array(arr) => !arr.is_empty(),
+        Value::Object(obj) => !obj.is_empty(),
+    }
+}
+
+/// Get the jq type name of a JSON value
+pub(crate) fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Render a scalar as text for @csv, @tsv and join: strings unquoted, integral numbers
+/// without a trailing `.0`, null as an empty string. Arrays and objects have no scalar form.
+fn value_to_scalar_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => Some(String::new()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if !n.is_i64() && !n.is_u64() && f.fract() == 0.0 && f.abs() < 1e17 => Some(format!("{}", f as i64)),
+            _ => Some(n.to_string()),
+        },
+        Value::String(s) => Some(s.clone()),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+/// Convert a value to text like jq's `tostring`: strings as they are, anything else as JSON
+fn value_to_text(value: &Value) -> String {
+    match value {
+        Value::Null | Value::Array(_) | Value::Object(_) => value.to_string(),
+        scalar => value_to_scalar_string(scalar).unwrap_or_default(),
+    }
+}
+
+/// Apply an `@name` format to a value
+fn apply_format(name: &str, value: &Value) -> Result<String, QueryError> {
+    match name {
+        "csv" => format_csv(value),
+        "tsv" => format_tsv(value),
+        "json" => Ok(value.to_string()),
+        "base64" => Ok(encode_base64(value_to_text(value).as_bytes())),
+        "uri" => Ok(encode_uri(&value_to_text(value))),
+        "html" => Ok(escape_html(&value_to_text(value))),
+        _ => Ok(value_to_text(value)),
+    }
+}
+
+/// Encode bytes as standard, padded base64
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &b)| group | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    
+    encoded
+}
+
+/// Percent-encode every byte of a string outside the unreserved set `A-Za-z0-9-_.~`
+fn encode_uri(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Escape the characters with a meaning in HTML as entities, writing `'` as `&#39;` like jq
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\'' => escaped.push_str("&#39;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Format an array as a CSV row, quoting strings (which also covers embedded commas, quotes and newlines)
+fn format_csv(value: &Value) -> Result<String, QueryError> {
+    let arr = row(value, "csv")?;
+    
+    let fields = arr.iter()
+        .map(|value| match value {
+            Value::String(s) => Ok(format!("\"{}\"", s.replace('"', "\"\""))),
+            other => value_to_scalar_string(other)
+                .ok_or_else(|| QueryError::Type(format!("{} ({}) is not valid in a csv row", type_name(other), other))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    
+    Ok(fields.join(","))
+}
+
+/// The fields of a row for @csv or @tsv, which must be an array. Objects are a common mistake, so the
+/// error for one shows how to format its values instead.
+fn row<'v>(value: &'v Value, format: &str) -> Result<&'v Vec<Value>, QueryError> {
+    match value {
+        Value::Array(arr) => Ok(arr),
+        Value::Object(_) => Err(QueryError::Type(format!(
+            "object ({}) cannot be {}-formatted, only an array can be; use [.[]] | @{} to format its values", value, format, format
+        ))),
+        other => Err(QueryError::Type(format!("{} ({}) cannot be {}-formatted, only an array can be", type_name(other), other, format))),
+    }
+}
+
+/// Format an array as a TSV row, escaping tabs, newlines and backslashes in strings
+fn format_tsv(value: &Value) -> Result<String, QueryError> {
+    let arr = row(value, "tsv")?;
+    
+    let fields = arr.iter()
+        .map(|value| match value {
+            Value::String(s) => Ok(s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")),
+            other => value_to_scalar_string(other)
+                .ok_or_else(|| QueryError::Type(format!("{} ({}) is not valid in a tsv row", type_name(other), other))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    
+    Ok(fields.join("\t"))
+}
+
+/// Apply a binary arithmetic operator to two JSON values
+fn arithmetic(left: &Value, op: &str, right: &Value) -> Result<Value, QueryError> {
+    match (op, left, right) {
+        ("+", Value::Null, r) => Ok(r.clone()),
+        ("+", l, Value::Null) => Ok(l.clone()),
+        ("+", Value::String(l), Value::String(r)) => Ok(Value::String(format!("{}{}", l, r))),
+        ("+", Value::Array(l), Value::Array(r)) => {
+            let mut result = l.clone();
+            result.extend(r.iter().cloned());
+            Ok(Value::Array(result))
+        },
+        ("+", Value::Object(l), Value::Object(r)) => {
+            // A shallow merge: keys of the right object replace those of the left
+            let mut result = l.clone();
+            result.extend(r.iter().map(|(k, v)| (k.clone(), v.clone())));
+            Ok(Value::Object(result))
+        },
+        ("-", Value::Array(l), Value::Array(r)) => {
+            // Remove every occurrence of each element of the right array
+            let result = l.iter()
+                .filter(|item| !r.iter().any(|other| compare_values(item, other) == std::cmp::Ordering::Equal))
+                .cloned()
+                .collect();
+            Ok(Value::Array(result))
+        },
+        ("/", Value::String(l), Value::String(r)) => Ok(split_string(l, r)),
+        ("*", Value::String(s), Value::Number(n)) | ("*", Value::Number(n), Value::String(s)) => {
+            // jq repeats the string; a count of zero or less yields null
+            let count = n.as_f64().unwrap_or(0.0);
+            if count <= 0.0 {
+                return Ok(Value::Null);
+            }
+            
+            let count = (count as usize).max(1);
+            match s.len().checked_mul(count) {
+                Some(length) if length <= MAX_REPEAT_LENGTH => Ok(Value::String(s.repeat(count))),
+                _ => Err(QueryError::Type(format!("{} because the result would be too long", arithmetic_message(left, op, right)))),
+            }
+        },
+        (_, Value::Number(l), Value::Number(r)) => {
+            if let Some(result) = integer_arithmetic(l.as_i64(), op, r.as_i64()) {
+                return Ok(Value::from(result));
+            }
+            
+            let l = l.as_f64().unwrap_or(0.0);
+            let r = r.as_f64().unwrap_or(0.0);
+            
+            // The remainder truncates its operands to integers, so a divisor such as 0.5 is zero too
+            let divisor_is_zero = match op {
+                "/" => r == 0.0,
+                "%" => r as i64 == 0,
+                _ => false,
+            };
+            if divisor_is_zero {
+                return Err(QueryError::Type(format!("{} because the divisor is zero", arithmetic_message(left, op, right))));
+            }
+            
+            let result = match op {
+                "+" => l + r,
+                "-" => l - r,
+                "*" => l * r,
+                "/" => l / r,
+                // The divisor is not zero, so this only wraps for an operand saturated to i64::MIN with -1
+                "%" => (l as i64).wrapping_rem(r as i64) as f64,
+                _ => return Err(arithmetic_error(left, op, right)),
+            };
+            Ok(float_to_value(result))
+        },
+        _ => Err(arithmetic_error(left, op, right)),
+    }
+}
+
+/// Apply an arithmetic operator to two integers, giving `None` when either operand is not an integer or
+/// the result is not one (it overflows, or is a fractional quotient) so the caller falls back to floats
+fn integer_arithmetic(left: Option<i64>, op: &str, right: Option<i64>) -> Option<i64> {
+    let (l, r) = (left?, right?);
+    match op {
+        "+" => l.checked_add(r),
+        "-" => l.checked_sub(r),
+        "*" => l.checked_mul(r),
+        "/" if l.checked_rem(r) == Some(0) => l.checked_div(r),
+        // Only i64::MIN % -1 wraps, and its remainder is 0
+        "%" if r != 0 => Some(l.wrapping_rem(r)),
+        _ => None,
+    }
+}
+
+/// Convert the result of arithmetic to a JSON value. JSON cannot represent infinity, so like jq an
+/// overflow clamps to the largest finite float of its sign and stays a number; NaN becomes null.
+fn float_to_value(n: f64) -> Value {
+    if n.is_nan() {
+        Value::Null
+    } else {
+        Value::from(n.clamp(f64::MIN, f64::MAX))
+    }
+}
+
+/// Split a string on a separator into an array of strings (jq `split`)
+fn split_string(s: &str, separator: &str) -> Value {
+    if s.is_empty() {
+        return Value::Array(vec![]);
+    }
+    
+    let parts: Vec<Value> = if separator.is_empty() {
+        s.chars().map(|c| Value::String(c.to_string())).collect()
+    } else {
+        s.split(separator).map(|part| Value::String(part.to_string())).collect()
+    };
+    
+    Value::Array(parts)
+}
+
+/// Build the jq-style error for operands an arithmetic operator does not accept
+fn arithmetic_error(left: &Value, op: &str, right: &Value) -> QueryError {
+    QueryError::Type(arithmetic_message(left, op, right))
+}
+
+/// Describe operands an arithmetic operator cannot combine, such as `number (1) and string ("a") cannot be added`
+fn arithmetic_message(left: &Value, op: &str, right: &Value) -> String {
+    let verb = match op {
+        "+" => "added",
+        "-" => "subtracted",
+        "*" => "multiplied",
+        "/" => "divided",
+        _ => "divided (remainder)",
+    };
+    format!("{} ({}) and {} ({}) cannot be {}", type_name(left), left, type_name(right), right, verb)
+}
+
+/// Apply a comparison operator to two JSON values
+fn compare(left: &Value, op: &str, right: &Value) -> bool {
+    let cmp = compare_values(left, right);
+    match op {
+        "==" => cmp == std::cmp::Ordering::Equal,
+        "!=" => cmp != std::cmp::Ordering::Equal,
+        ">" => cmp == std::cmp::Ordering::Greater,
+        "<" => cmp == std::cmp::Ordering::Less,
+        ">=" => cmp != std::cmp::Ordering::Less,
+        "<=" => cmp != std::cmp::Ordering::Greater,
+        _ => false,
+    }
+}
+
+/// Rank of a value's type in jq's ordering: null < false < true < numbers < strings < arrays < objects
+fn type_order(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(false) => 1,
+        Value::Bool(true) => 2,
+        Value::Number(_) => 3,
+        Value::String(_) => 4,
+        Value::Array(_) => 5,
+        Value::Object(_) => 6,
+    }
+}
+
+/// Compare two JSON values using jq's total order, so every pair of values is ordered
+fn compare_values(left: &Value, right: &Value) -> std::cmp::Ordering {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => {
+            if let (Some(li), Some(ri)) = (l.as_i64(), r.as_i64()) {
+                li.cmp(&ri)
+            } else if let (Some(lu), Some(ru)) = (l.as_u64(), r.as_u64()) {
+                lu.cmp(&ru)
+            } else {
+                // Unlike total_cmp, this makes -0 equal to 0 as in jq. NaN sorts below every number.
+                let (lf, rf) = (l.as_f64().unwrap_or_default(), r.as_f64().unwrap_or_default());
+                lf.partial_cmp(&rf).unwrap_or_else(|| rf.is_nan().cmp(&lf.is_nan()))
+            }
+        },
+        (Value::String(l), Value::String(r)) => l.cmp(r),
+        (Value::Array(l), Value::Array(r)) => {
+            // Element by element, then a shorter prefix sorts first
+            l.iter()
+                .zip(r.iter())
+                .map(|(lv, rv)| compare_values(lv, rv))
+                .find(|ord| *ord != std::cmp::Ordering::Equal)
+                .unwrap_or_else(|| l.len().cmp(&r.len()))
+        },
+        (Value::Object(l), Value::Object(r)) => compare_objects(l, r),
+        _ => type_order(left).cmp(&type_order(right)),
+    }
+}
+
+/// Compare two objects like jq: first their sorted key sets, then their values key by key
+fn compare_objects(left: &Map<String, Value>, right: &Map<String, Value>) -> std::cmp::Ordering {
+    let mut left_keys: Vec<&String> = left.keys().collect();
+    let mut right_keys: Vec<&String> = right.keys().collect();
+    left_keys.sort();
+    right_keys.sort();
+    
+    left_keys.cmp(&right_keys).then_with(|| {
+        left_keys.iter()
+            .map(|key| compare_values(&left[key.as_str()], &right[key.as_str()]))
+            .find(|ord| *ord != std::cmp::Ordering::Equal)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_query;
     use serde_json::json;
     
     #[test]
-    fn test_identity() {
+    fn test_identity() {
+        let engine = QueryEngine::new();
+        let data = json!({"name": "John", "age": 30});
+        let expr = Expression::Identity;
+        
+        let result = engine.execute(&expr, &data).unwrap();
+        assert_eq!(result, vec![data]);
+    }
+    
+    #[test]
+    fn test_property_access() {
+        let engine = QueryEngine::new();
+        let data = json!({"name": "John", "age": 30});
+        let expr = Expression::Property("name".to_string());
+        
+        let result = engine.execute(&expr, &data).unwrap();
+        assert_eq!(result, vec![json!("John")]);
+    }
+    
+    #[test]
+    fn test_array_index() {
+        let engine = QueryEngine::new();
+        let data = json!([1, 2, 3, 4, 5]);
+        let expr = Expression::Index(2);
+        
+        let result = engine.execute(&expr, &data).unwrap();
+        assert_eq!(result, vec![json!(3)]);
+    }
+    
+    #[test]
+    fn test_array_slice() {
+        let engine = QueryEngine::new();
+        let data = json!([1, 2, 3, 4, 5]);
+        let expr = Expression::Slice(Some(1), Some(4));
+        
+        let result = engine.execute(&expr, &data).unwrap();
+        assert_eq!(result, vec![json!([2, 3, 4])]);
+    }
+    
+    #[test]
+    fn test_pipe() {
+        let engine = QueryEngine::new();
+        let data = json!({"user": {"name": "John", "age": 30}});
+        
+        let expr = Expression::Pipe(
+            Box::new(Expression::Property("user".to_string())),
+            Box::new(Expression::Property("name".to_string()))
+        );
+        
+        let result = engine.execute(&expr, &data).unwrap();
+        assert_eq!(result, vec![json!("John")]);
+    }
+    
+    #[test]
+    fn test_variable() {
+        let mut engine = QueryEngine::new();
+        engine.set_variable("limit", json!(10));
+        let expr = parse_query("[$limit, .]").unwrap();
+        
+        let result = engine.execute(&expr, &json!("x")).unwrap();
+        assert_eq!(result, vec![json!([10, "x"])]);
+        
+        let expr = parse_query("$missing").unwrap();
+        assert!(matches!(engine.execute(&expr, &Value::Null), Err(QueryError::UndefinedVariable(_))));
+    }
+    
+    #[test]
+    fn test_string_repetition() {
+        let engine = QueryEngine::new();
+        let expr = Expression::Arithmetic(
+            Box::new(Expression::Literal(json!("ab"))),
+            "*".to_string(),
+            Box::new(Expression::Literal(json!(3)))
+        );
+        
+        let result = engine.execute(&expr, &Value::Null).unwrap();
+        assert_eq!(result, vec![json!("ababab")]);
+    }
+    
+    #[test]
+    fn test_string_repetition_too_long() {
+        let engine = QueryEngine::new();
+        
+        for count in [json!(1e18), json!(u64::MAX), json!(MAX_REPEAT_LENGTH)] {
+            let expr = Expression::Arithmetic(
+                Box::new(Expression::Literal(json!("ab"))),
+                "*".to_string(),
+                Box::new(Expression::Literal(count.clone()))
+            );
+            
+            let error = engine.execute(&expr, &Value::Null).unwrap_err().to_string();
+            assert!(error.contains("the result would be too long"), "{}: {}", count, error);
+        }
+    }
+    
+    #[test]
+    fn test_string_repetition_zero() {
+        let engine = QueryEngine::new();
+        
+        for count in [json!(0), json!(-2)] {
+            let expr = Expression::Arithmetic(
+                Box::new(Expression::Literal(json!("x"))),
+                "*".to_string(),
+                Box::new(Expression::Literal(count))
+            );
+            
+            let result = engine.execute(&expr, &Value::Null).unwrap();
+            assert_eq!(result, vec![Value::Null]);
+        }
+    }
+    
+    #[test]
+    fn test_string_division_splits() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("\"a-b\" / \"-\"").unwrap();
+        
+        let result = engine.execute(&expr, &Value::Null).unwrap();
+        assert_eq!(result, vec![json!(["a", "b"])]);
+    }
+    
+    #[test]
+    fn test_string_division_on_input() {
+        let engine = QueryEngine::new();
+        let data = json!({"csv": "a,b,c"});
+        let expr = parse_query(".csv / \",\"").unwrap();
+        
+        let result = engine.execute(&expr, &data).unwrap();
+        assert_eq!(result, vec![json!(["a", "b", "c"])]);
+    }
+    
+    #[test]
+    fn test_array_subtraction() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("[1, 2, 3, 2] - [2]").unwrap();
+        
+        let result = engine.execute(&expr, &Value::Null).unwrap();
+        assert_eq!(result, vec![json!([1, 3])]);
+    }
+    
+    #[test]
+    fn test_array_subtraction_multiple_elements() {
+        let engine = QueryEngine::new();
+        let data = json!({"all": [1, "a", 2, 3, "b", 4, "a"], "drop": ["a", 2, 4]});
+        let expr = parse_query(".all - .drop").unwrap();
+        
+        let result = engine.execute(&expr, &data).unwrap();
+        assert_eq!(result, vec![json!([1, 3, "b"])]);
+    }
+    
+    #[test]
+    fn test_object_addition_merges() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("{x: 1} + {y: 2}").unwrap();
+        
+        let result = engine.execute(&expr, &Value::Null).unwrap();
+        assert_eq!(result, vec![json!({"x": 1, "y": 2})]);
+        
+        // The merge is shallow and the right-hand object wins
+        let expr = parse_query("{a: 1, b: {c: 2}} + {b: {d: 3}}").unwrap();
+        let result = engine.execute(&expr, &Value::Null).unwrap();
+        assert_eq!(result, vec![json!({"a": 1, "b": {"d": 3}})]);
+    }
+    
+    #[test]
+    fn test_first_generator_short_circuits() {
+        let engine = QueryEngine::new();
+        
+        // An eager implementation would have to produce every number in the range first
+        for query in ["first(range(0; 1000000))", "first(range(0; 1000000000000000))"] {
+            let expr = parse_query(query).unwrap();
+            let result = engine.execute(&expr, &Value::Null).unwrap();
+            assert_eq!(result, vec![json!(0)]);
+        }
+    }
+    
+    #[test]
+    fn test_first_and_last_generator() {
+        let engine = QueryEngine::new();
+        let data = json!({"items": [{"id": 1, "active": false}, {"id": 2, "active": true}, {"id": 3, "active": true}]});
+        
+        let expr = parse_query("first(.items[] | .id)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(1)]);
+        
+        let expr = parse_query("last(.items[] | .id)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(3)]);
+        
+        // Elements after the first are never evaluated, so the division by zero on 2 is not reached
+        let expr = parse_query("first(.items[] | .id / (.id - 2))").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(-1)]);
+        
+        // No output from the generator means no output at all
+        let expr = parse_query("[first(.items | .[5:] | .[])]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([])]);
+    }
+    
+    #[test]
+    fn test_range() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("[range(3)]").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!([0, 1, 2])]);
+        
+        let expr = parse_query("[range(2; 5)]").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!([2, 3, 4])]);
+    }
+    
+    /// Run a query and return its numeric results as floats
+    fn numbers(engine: &QueryEngine, query: &str, data: &Value) -> Vec<f64> {
+        let expr = parse_query(query).unwrap();
+        engine.execute(&expr, data).unwrap().iter().map(|v| v.as_f64().unwrap()).collect()
+    }
+    
+    #[test]
+    fn test_while_countdown() {
+        let engine = QueryEngine::new();
+        
+        let result = numbers(&engine, "while(. > 0; . - 1)", &json!(5));
+        assert_eq!(result, vec![5.0, 4.0, 3.0, 2.0, 1.0]);
+        
+        let result = numbers(&engine, "while(. > 10; . - 1)", &json!(5));
+        assert!(result.is_empty());
+    }
+    
+    #[test]
+    fn test_until() {
+        let engine = QueryEngine::new();
+        
+        let result = numbers(&engine, "until(. >= 100; . * 2)", &json!(3));
+        assert_eq!(result, vec![192.0]);
+    }
+    
+    #[test]
+    fn test_loop_iteration_limit() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("until(. < 0; . + 1)").unwrap();
+        let result = engine.execute(&expr, &json!(0));
+        assert!(matches!(result, Err(QueryError::IterationLimit(_, MAX_LOOP_ITERATIONS))));
+        
+        // An endless loop is fine as long as only part of it is consumed
+        let result = numbers(&engine, "first(while(true; . + 1))", &json!(0));
+        assert_eq!(result, vec![0.0]);
+    }
+    
+    #[test]
+    fn test_limit_repeat() {
+        let engine = QueryEngine::new();
+        
+        // Like jq, repeat(f) emits its input before the outputs of f, so on null input the first value is null
+        let expr = parse_query("[limit(3; repeat(1))]").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!([null, 1, 1])]);
+        assert_eq!(engine.execute(&expr, &json!(1)).unwrap(), vec![json!([1, 1, 1])]);
+        
+        let result = numbers(&engine, "limit(4; repeat(. * 2))", &json!(1));
+        assert_eq!(result, vec![1.0, 2.0, 4.0, 8.0]);
+    }
+    
+    #[test]
+    fn test_limit() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("[limit(2; .[])]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([5, 6, 7])).unwrap(), vec![json!([5, 6])]);
+        
+        let expr = parse_query("[limit(0; .[])]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([5, 6, 7])).unwrap(), vec![json!([])]);
+    }
+    
+    #[test]
+    fn test_user_function_without_arguments() {
+        let engine = QueryEngine::new();
+        let result = numbers(&engine, "def inc: . + 1; .a | inc", &json!({"a": 41}));
+        assert_eq!(result, vec![42.0]);
+    }
+    
+    #[test]
+    fn test_user_function_with_arguments() {
+        let engine = QueryEngine::new();
+        
+        // Filter parameters are evaluated against the input inside the function
+        let result = numbers(&engine, "def twice(f): f | f; 3 | twice(. * 2)", &Value::Null);
+        assert_eq!(result, vec![12.0]);
+        
+        // Value parameters are bound in the caller's context
+        let result = numbers(&engine, "def add($n): map(. + $n); add(.[0]) | .[]", &json!([1, 2]));
+        assert_eq!(result, vec![2.0, 3.0]);
+    }
+    
+    #[test]
+    fn test_user_function_recursion() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("def countdown: if . > 0 then . - 1 | countdown else \"done\" end; countdown").unwrap();
+        assert_eq!(engine.execute(&expr, &json!(5)).unwrap(), vec![json!("done")]);
+        
+        let result = numbers(&engine, "def final: if length > 1 then .[1:] | final else .[0] end; final", &json!([1, 2, 3]));
+        assert_eq!(result, vec![3.0]);
+    }
+    
+    #[test]
+    fn test_if_elif_else() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("map(if . < 0 then \"neg\" elif . == 0 then \"zero\" else \"pos\" end)").unwrap();
+        let result = engine.execute(&expr, &json!([-1, 0, 1])).unwrap();
+        assert_eq!(result, vec![json!(["neg", "zero", "pos"])]);
+    }
+    
+    #[test]
+    fn test_value_to_scalar_string() {
+        assert_eq!(value_to_scalar_string(&json!(1)).unwrap(), "1");
+        assert_eq!(value_to_scalar_string(&json!(2.0)).unwrap(), "2");
+        assert_eq!(value_to_scalar_string(&json!(1.5)).unwrap(), "1.5");
+        assert_eq!(value_to_scalar_string(&json!(true)).unwrap(), "true");
+        assert_eq!(value_to_scalar_string(&Value::Null).unwrap(), "");
+        assert!(value_to_scalar_string(&json!([1])).is_none());
+    }
+    
+    #[test]
+    fn test_format_csv() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("@csv").unwrap();
+        
+        let result = engine.execute(&expr, &json!([1, 2.0, 1.5, true, false, null, "a,b", "say \"hi\"", "two\nlines", "cr\r"])).unwrap();
+        assert_eq!(result, vec![json!("1,2,1.5,true,false,,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",\"cr\r\"")]);
+        
+        assert!(engine.execute(&expr, &json!([{"a": 1}])).is_err());
+        assert!(engine.execute(&expr, &json!("not an array")).is_err());
+    }
+    
+    #[test]
+    fn test_format_csv_object_hint() {
+        let engine = QueryEngine::new();
+        let data = json!({"name": "Ada", "age": 36});
+        
+        // jq only formats arrays, so an object is an error that suggests taking its values
+        let expr = parse_query("@csv").unwrap();
+        let error = engine.execute(&expr, &data).unwrap_err().to_string();
+        assert!(error.contains("use [.[]] | @csv"), "{}", error);
+        
+        let expr = parse_query("[.[]] | @csv").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("36,\"Ada\"")]);
+    }
+    
+    #[test]
+    fn test_format_tsv() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("@tsv").unwrap();
+        
+        let result = engine.execute(&expr, &json!(["a\tb", "c\nd", "e\\f", 3, 0.5, null, true])).unwrap();
+        assert_eq!(result, vec![json!("a\\tb\tc\\nd\te\\\\f\t3\t0.5\t\ttrue")]);
+    }
+    
+    #[test]
+    fn test_join() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("join(\", \")").unwrap();
+        
+        let result = engine.execute(&expr, &json!(["a", 1, 2.0, null, false])).unwrap();
+        assert_eq!(result, vec![json!("a, 1, 2, , false")]);
+        
+        assert!(engine.execute(&expr, &json!([[1]])).is_err());
+    }
+    
+    #[test]
+    fn test_split() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("split(\", \")").unwrap();
+        assert_eq!(engine.execute(&expr, &json!("a, b, c")).unwrap(), vec![json!(["a", "b", "c"])]);
+        assert_eq!(engine.execute(&expr, &json!("")).unwrap(), vec![json!([])]);
+        
+        // Unlike `/`, split never divides numbers or falls through to other types
+        for (query, input) in [("split(2)", json!(10)), ("split(\",\")", json!({})), ("split(\",\")", json!(["a,b"])), ("split(1)", json!("a1b"))] {
+            match engine.execute(&parse_query(query).unwrap(), &input) {
+                Err(QueryError::Type(message)) => assert_eq!(message, "split input and separator must be strings"),
+                other => panic!("{} on {}: {:?}", query, input, other),
+            }
+        }
+    }
+    
+    #[test]
+    fn test_total_order_across_types() {
+        use std::cmp::Ordering;
+        
+        // null < false < true < numbers < strings < arrays < objects
+        let ordered = [json!(null), json!(false), json!(true), json!(-1), json!(2.5), json!(""), json!("a"), json!([]), json!([0]), json!({}), json!({"a": 1})];
+        for (i, left) in ordered.iter().enumerate() {
+            for (j, right) in ordered.iter().enumerate() {
+                assert_eq!(compare_values(left, right), i.cmp(&j), "comparing {} and {}", left, right);
+            }
+        }
+        
+        assert_eq!(compare_values(&json!(1), &json!(1.0)), Ordering::Equal);
+        assert_eq!(compare_values(&json!([1, 2]), &json!([1, 2, 0])), Ordering::Less);
+        assert_eq!(compare_values(&json!([2]), &json!([1, 5])), Ordering::Greater);
+    }
+    
+    #[test]
+    fn test_comparison_operators_across_types() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("[.[] < \"a\"]").unwrap();
+        let result = engine.execute(&expr, &json!([null, true, 10, "b", [], {}])).unwrap();
+        assert_eq!(result, vec![json!([true, true, true, false, false, false])]);
+        
+        let expr = parse_query(".a == .b").unwrap();
+        assert_eq!(engine.execute(&expr, &json!({"a": 1, "b": 1.0})).unwrap(), vec![json!(true)]);
+    }
+    
+    #[test]
+    fn test_walk_gsub_collapses_whitespace() {
+        let engine = QueryEngine::new();
+        let expr = parse_query(r#"walk(if type == "string" then gsub("\\s+"; " ") else . end)"#).unwrap();
+        let data = json!({
+            "title": "a   spaced\t\ttitle",
+            "tags": ["one  two", "three"],
+            "nested": {"deeper": [{"note": "x \n  y"}], "count": 3},
+        });
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({
+            "title": "a spaced title",
+            "tags": ["one two", "three"],
+            "nested": {"deeper": [{"note": "x y"}], "count": 3},
+        })]);
+    }
+    
+    #[test]
+    fn test_sub_and_gsub() {
+        let engine = QueryEngine::new();
+        let run = |query: &str, data: Value| engine.execute(&parse_query(query).unwrap(), &data).unwrap();
+        
+        assert_eq!(run(r#"sub("a"; "b")"#, json!("aaa")), vec![json!("baa")]);
+        assert_eq!(run(r#"gsub("a"; "b")"#, json!("aaa")), vec![json!("bbb")]);
+        assert_eq!(run(r#"gsub("A"; "b"; "i")"#, json!("aAa")), vec![json!("bbb")]);
+        
+        // The replacement sees the named captures
+        assert_eq!(run(r#"gsub("(?<d>\\d)"; "<\(.d)>")"#, json!("a1b2")), vec![json!("a<1>b<2>")]);
+        assert_eq!(run(r#"[sub("b"; "1", "2")]"#, json!("abc")), vec![json!(["a1c", "a2c"])]);
+        
+        assert_eq!(run("[1, \"a\", [], {}, null, true] | map(type)", Value::Null), vec![json!(["number", "string", "array", "object", "null", "boolean"])]);
+    }
+    
+    #[test]
+    fn test_normalize_email() {
+        let engine = QueryEngine::new();
+        let expr = parse_query(r#"ascii_downcase | gsub("\\+.*@"; "@")"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!("John+news@Example.COM")).unwrap(), vec![json!("john@example.com")]);
+        assert_eq!(engine.execute(&expr, &json!("jane@example.com")).unwrap(), vec![json!("jane@example.com")]);
+    }
+    
+    #[test]
+    fn test_env_filters_records() {
+        std::env::set_var("RJX_TEST_REGION", "eu-west-1");
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("[.[] | select(.region == env.RJX_TEST_REGION) | .id]").unwrap();
+        let data = json!([
+            {"id": 1, "region": "us-east-1"},
+            {"id": 2, "region": "eu-west-1"},
+            {"id": 3, "region": "eu-west-1"},
+        ]);
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([2, 3])]);
+        
+        let expr = parse_query("env | type, .RJX_TEST_UNSET_VARIABLE").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!("object"), Value::Null]);
+    }
+    
+    #[test]
+    fn test_computed_object_keys() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("{(.name | ascii_downcase): .value}").unwrap();
+        let data = json!({"name": "Color", "value": "red"});
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"color": "red"})]);
+        
+        // Like values, keys with several outputs build one object for each
+        let expr = parse_query("{(\"a\", \"b\"): 1, c: 2}").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![
+            json!({"a": 1, "c": 2}),
+            json!({"b": 1, "c": 2}),
+        ]);
+        
+        let expr = parse_query("{(.value): 1}").unwrap();
+        let error = engine.execute(&expr, &json!({"value": 3})).unwrap_err().to_string();
+        assert!(error.contains("Object keys must be strings"), "{}", error);
+    }
+    
+    #[test]
+    fn test_min_max() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("[range(0; 5)] | max, min").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(4), json!(0)]);
+        
+        let expr = parse_query("[[], max]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([])).unwrap(), vec![json!([[], null])]);
+        
+        // Each output of a generator on its own is not an array, so the error suggests collecting them
+        let expr = parse_query("range(0; 5) | max").unwrap();
+        let error = engine.execute(&expr, &Value::Null).unwrap_err().to_string();
+        assert!(error.contains("collect them with [...]"), "{}", error);
+    }
+    
+    #[test]
+    fn test_map_comparison() {
+        let engine = QueryEngine::new();
+        let data = json!([{"a": 1, "b": 1}, {"a": 1, "b": 2}, {"a": "x", "b": "x"}, {"a": [1]}]);
+        
+        // A comparison is an expression like any other, so map collects its booleans
+        let expr = parse_query("map(.a == .b)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([true, false, true, false])]);
+        
+        let expr = parse_query("map(.a < .b)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([false, true, false, false])]);
+    }
+    
+    #[test]
+    fn test_object_ordering() {
+        use std::cmp::Ordering;
+        
+        // Key sets are compared before any values
+        assert_eq!(compare_values(&json!({"a": 9}), &json!({"b": 0})), Ordering::Less);
+        assert_eq!(compare_values(&json!({"a": 1}), &json!({"a": 1, "b": 0})), Ordering::Less);
+        assert_eq!(compare_values(&json!({"a": 1, "b": 2}), &json!({"a": 1, "b": 1})), Ordering::Greater);
+        assert_eq!(compare_values(&json!({"a": [1], "b": null}), &json!({"b": null, "a": [1]})), Ordering::Equal);
+    }
+    
+    #[test]
+    fn test_sort_objects() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("sort").unwrap();
+        
+        let data = json!([{"b": 1}, {"a": 2}, {"a": 1, "b": 0}, {"a": 1}, 3, null]);
+        let result = engine.execute(&expr, &data).unwrap();
+        assert_eq!(result, vec![json!([null, 3, {"a": 1}, {"a": 2}, {"a": 1, "b": 0}, {"b": 1}])]);
+    }
+    
+    /// Build an array nested `depth` levels deep around a number
+    fn nested_array(depth: usize) -> Value {
+        let mut value = json!(0);
+        for _ in 0..depth {
+            value = Value::Array(vec![value]);
+        }
+        value
+    }
+    
+    #[test]
+    fn test_recursive_descent_order() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("..").unwrap();
+        let result = engine.execute(&expr, &json!({"a": [1, {"b": 2}], "c": 3})).unwrap();
+        assert_eq!(result, vec![
+            json!({"a": [1, {"b": 2}], "c": 3}),
+            json!([1, {"b": 2}]),
+            json!(1),
+            json!({"b": 2}),
+            json!(2),
+            json!(3),
+        ]);
+    }
+    
+    #[test]
+    fn test_recursive_descent_deeply_nested() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("..").unwrap();
+        let data = nested_array(2_000);
+        
+        let count = engine.stream(&expr, data.clone()).count();
+        assert_eq!(count, 2_001);
+        
+        let mut engine = QueryEngine::new();
+        engine.set_max_depth(100);
+        let result = engine.execute(&expr, &data);
+        assert!(matches!(result, Err(QueryError::DepthLimit(100))));
+    }
+    
+    #[test]
+    fn test_string_slice() {
+        let engine = QueryEngine::new();
+        let expr = parse_query(".[1:3]").unwrap();
+        
+        assert_eq!(engine.execute(&expr, &json!("hello")).unwrap(), vec![json!("el")]);
+        assert_eq!(engine.execute(&expr, &json!("héllo wörld")).unwrap(), vec![json!("él")]);
+        assert_eq!(engine.execute(&expr, &json!("日本語テキスト")).unwrap(), vec![json!("本語")]);
+        
+        let expr = parse_query(".[-3:-1]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!("añadir")).unwrap(), vec![json!("di")]);
+    }
+    
+    #[test]
+    fn test_slice_bounds() {
+        assert_eq!(slice_bounds(5, Some(1), Some(3)), (1, 3));
+        assert_eq!(slice_bounds(5, Some(-2), None), (3, 5));
+        assert_eq!(slice_bounds(3, None, Some(-10)), (0, 0));
+        assert_eq!(slice_bounds(3, Some(4), Some(10)), (3, 3));
+        assert_eq!(slice_bounds(3, Some(2), Some(1)), (1, 1));
+    }
+    
+    #[test]
+    fn test_string_slice_open_ended() {
+        let engine = QueryEngine::new();
+        let data = json!("héllo");
+        
+        let expr = parse_query(".[:2]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("hé")]);
+        
+        let expr = parse_query(".[2:]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("llo")]);
+        
+        let expr = parse_query(".[-1:]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("o")]);
+    }
+    
+    #[test]
+    fn test_string_index_suggests_slice() {
+        let engine = QueryEngine::new();
+        let expr = parse_query(".[1]").unwrap();
+        let err = engine.execute(&expr, &json!("hello")).unwrap_err();
+        assert!(err.to_string().contains(".[1:2]"));
+    }
+    
+    #[test]
+    fn test_string_interpolation() {
+        let engine = QueryEngine::new();
+        let data = json!({"name": "Ada", "age": 36, "tags": ["x"], "ratio": 2.0, "missing": null});
+        
+        let expr = parse_query(r#""Hello \(.name), you are \(.age)""#).unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("Hello Ada, you are 36")]);
+        
+        let expr = parse_query(r#""\(.tags) \(.ratio) \(.missing) \("nested \(.age + 1)")""#).unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(r#"["x"] 2 null nested 37"#)]);
+    }
+    
+    #[test]
+    fn test_string_interpolation_generators() {
+        let engine = QueryEngine::new();
+        let expr = parse_query(r#""\(.[])-\(range(2))""#).unwrap();
+        let result = engine.execute(&expr, &json!(["a", "b"])).unwrap();
+        assert_eq!(result, vec![json!("a-0"), json!("b-0"), json!("a-1"), json!("b-1")]);
+    }
+    
+    #[test]
+    fn test_base64() {
+        assert_eq!(encode_base64(b""), "");
+        assert_eq!(encode_base64(b"f"), "Zg==");
+        assert_eq!(encode_base64(b"fo"), "Zm8=");
+        assert_eq!(encode_base64(b"foo"), "Zm9v");
+        assert_eq!(encode_base64(b"foobar"), "Zm9vYmFy");
+    }
+    
+    #[test]
+    fn test_format_templates() {
+        let engine = QueryEngine::new();
+        let data = json!({"query": "a b&c/é", "user": "ada:pw", "n": 1});
+        
+        // Only the interpolated values are encoded, not the literal text around them
+        let expr = parse_query(r#"@uri "https://x.io/?q=\(.query)&n=\(.n)""#).unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("https://x.io/?q=a%20b%26c%2F%C3%A9&n=1")]);
+        
+        let expr = parse_query(r#"@base64 "Basic \(.user)""#).unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("Basic YWRhOnB3")]);
+        
+        let expr = parse_query("@json").unwrap();
+        assert_eq!(engine.execute(&expr, &json!({"a": [1, "x"]})).unwrap(), vec![json!(r#"{"a":[1,"x"]}"#)]);
+    }
+    
+    #[test]
+    fn test_format_uri_reserved_characters() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("@uri").unwrap();
+        
+        // Only the unreserved characters of RFC 3986 are kept; everything else, including `/`, is encoded
+        let result = engine.execute(&expr, &json!("a b/c~d-e_f.g!*'()?=+")).unwrap();
+        assert_eq!(result, vec![json!("a%20b%2Fc~d-e_f.g%21%2A%27%28%29%3F%3D%2B")]);
+        
+        let result = engine.execute(&expr, &json!("AZaz09-_.~")).unwrap();
+        assert_eq!(result, vec![json!("AZaz09-_.~")]);
+    }
+    
+    #[test]
+    fn test_format_html() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("@html").unwrap();
+        
+        let result = engine.execute(&expr, &json!(r#"<a href="x">Tom & Jerry's</a>"#)).unwrap();
+        assert_eq!(result, vec![json!("&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;")]);
+        
+        // Other values are escaped in their JSON form
+        assert_eq!(engine.execute(&expr, &json!(["<b>"])).unwrap(), vec![json!("[&quot;&lt;b&gt;&quot;]")]);
+        
+        let expr = parse_query(r#"@html "<p>\(.)</p>""#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!("1 < 2")).unwrap(), vec![json!("<p>1 &lt; 2</p>")]);
+    }
+    
+    #[test]
+    fn test_variable_binding() {
+        let engine = QueryEngine::new();
+        
+        // The body sees the original input, once per output of the bound expression
+        let result = numbers(&engine, ".[] as $x | $x * .[0]", &json!([2, 3]));
+        assert_eq!(result, vec![4.0, 6.0]);
+    }
+    
+    #[test]
+    fn test_destructuring() {
+        let engine = QueryEngine::new();
+        
+        let result = numbers(&engine, ". as [$a, $b] | $a - $b", &json!([10, 4]));
+        assert_eq!(result, vec![6.0]);
+        
+        let expr = parse_query(r#". as {name: $n, "the age": $age, $id} | "\($n) \($age) \($id)""#).unwrap();
+        let result = engine.execute(&expr, &json!({"name": "Ada", "the age": 36, "id": 7})).unwrap();
+        assert_eq!(result, vec![json!("Ada 36 7")]);
+        
+        let expr = parse_query(". as [$a, {b: [$c]}, $missing] | [$a, $c, $missing]").unwrap();
+        let result = engine.execute(&expr, &json!([1, {"b": [2]}])).unwrap();
+        assert_eq!(result, vec![json!([1, 2, null])]);
+        
+        // Variables bound with {$name: pattern} hold the whole value as well
+        let expr = parse_query(". as {$user: {name: $n}} | [$user, $n]").unwrap();
+        let result = engine.execute(&expr, &json!({"user": {"name": "Ada"}})).unwrap();
+        assert_eq!(result, vec![json!([{"name": "Ada"}, "Ada"])]);
+    }
+    
+    #[test]
+    fn test_destructuring_shape_mismatch() {
+        let engine = QueryEngine::new();
+        let expr = parse_query(". as [$a] | $a").unwrap();
+        assert!(engine.execute(&expr, &json!({"a": 1})).is_err());
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![Value::Null]);
+    }
+    
+    #[test]
+    fn test_destructuring_alternatives() {
+        let engine = QueryEngine::new();
+        
+        // The same variable comes from whichever pattern fits the input
+        let expr = parse_query(". as {a: $x} ?// [$x] | $x").unwrap();
+        assert_eq!(engine.execute(&expr, &json!({"a": 1})).unwrap(), vec![json!(1)]);
+        assert_eq!(engine.execute(&expr, &json!([2, 3])).unwrap(), vec![json!(2)]);
+        
+        // Variables only the other patterns bind are null
+        let expr = parse_query("[.[] as [$a] ?// $b | [$a, $b]]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([[1], "x"])).unwrap(), vec![json!([[1, null], [null, "x"]])]);
+        
+        // An error in the body moves on to the next pattern, except with the last one
+        let expr = parse_query(". as [$a] ?// $a | $a[0]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!(["x"])).unwrap(), vec![json!("x")]);
+        let expr = parse_query(". as {a: $x} ?// [$x] | $x").unwrap();
+        assert!(matches!(engine.execute(&expr, &json!("s")), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_grouping() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": 1, "b": 2, "c": {"d": 5}});
+        
+        assert_eq!(numbers(&engine, "2 * (.a + .b)", &data), vec![6.0]);
+        assert_eq!(numbers(&engine, "(.a + .b) * 2", &data), vec![6.0]);
+        assert_eq!(numbers(&engine, "(.c | .d) - 1", &data), vec![4.0]);
+        assert_eq!(numbers(&engine, ".c | (.d | . * .)", &data), vec![25.0]);
+    }
+    
+    #[test]
+    fn test_operator_precedence() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": 1, "b": 2, "c": 3});
+        
+        assert_eq!(numbers(&engine, "1 + 2 * 3", &Value::Null), vec![7.0]);
+        assert_eq!(numbers(&engine, ".a + .b * .c - 4 / 2", &data), vec![5.0]);
+        assert_eq!(numbers(&engine, ".a + .b * 2 | . * 10", &data), vec![50.0]);
+        assert_eq!(numbers(&engine, "-.c + 1", &data), vec![-2.0]);
+        
+        let expr = parse_query(".a + 1 > .b and .c == 3").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(false)]);
+    }
+    
+    #[test]
+    fn test_comma() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": 1, "b": 2});
+        
+        assert_eq!(numbers(&engine, ".a, .b | . * 10", &data), vec![10.0, 20.0]);
+        
+        let expr = parse_query("[.a, .b, range(2)]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([1, 2, 0, 1])]);
+        
+        // Object values end at the comma between entries
+        let expr = parse_query("{x: .a | . * 2, y: .b}").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"x": 2, "y": 2})]);
+    }
+    
+    #[test]
+    fn test_alternative_and_boolean_operators() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": 1, "f": false});
+        
+        let expr = parse_query("[.missing // .a, .f // \"default\", (null, 2, false) // 3, .a.b // 4]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([1, "default", 2, 4])]);
+        
+        let expr = parse_query("[(true, false) and (true, false)]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([true, false, false])]);
+        
+        let expr = parse_query("[(true, false) or (true, false)]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([true, true, false])]);
+        
+        // The right side is not evaluated once the left side decides the result
+        let expr = parse_query(".f and .a.b").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(false)]);
+    }
+    
+    #[test]
+    fn test_array_collects_generators() {
+        let engine = QueryEngine::new();
+        let data = json!({"items": [{"id": 1}, {"id": 2}, {"id": 3}], "none": []});
+        
+        let expr = parse_query("[.items[].id]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([1, 2, 3])]);
+        
+        let expr = parse_query("[.items[] | .id * 10]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([10, 20, 30])]);
+        
+        // Generators with no outputs still build an (empty) array
+        for query in ["[]", "[empty]", "[.none[]]", "[range(0)]"] {
+            let expr = parse_query(query).unwrap();
+            assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([])], "{}", query);
+        }
+        
+        let expr = parse_query("{}").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({})]);
+        
+        let expr = parse_query("empty").unwrap();
+        assert!(engine.execute(&expr, &data).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_property_on_array_hint() {
+        let engine = QueryEngine::new();
+        let data = json!({"users": [{"name": "Ada"}]});
+        
+        let expr = parse_query(".users.name").unwrap();
+        let error = engine.execute(&expr, &data).unwrap_err().to_string();
+        assert!(error.contains(".[].name"), "{}", error);
+        assert!(error.contains("map(.name)"), "{}", error);
+        
+        let expr = parse_query(r#".users."full name""#).unwrap();
+        let error = engine.execute(&expr, &data).unwrap_err().to_string();
+        assert!(error.contains(r#"map(."full name")"#), "{}", error);
+    }
+    
+    #[test]
+    fn test_select() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("[.[] | select(. > 1)]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([1, 2, 3])).unwrap(), vec![json!([2, 3])]);
+        
+        let expr = parse_query(r#".[] | select(.type == "a" and .n > 1) | .n"#).unwrap();
+        let data = json!([{"type": "a", "n": 1}, {"type": "b", "n": 2}, {"type": "a", "n": 3}]);
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(3)]);
+    }
+    
+    #[test]
+    fn test_select_tests_the_whole_input() {
+        let engine = QueryEngine::new();
+        let data = json!([1, 2]);
+        
+        // Filtering elements takes an explicit .[]
+        let expr = parse_query(".[] | select(. > 1)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(2)]);
+        
+        // Otherwise select tests its input as a whole, whether an array, an object or a scalar
+        let expr = parse_query("select(length > 0)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([1, 2])]);
+        assert_eq!(engine.execute(&expr, &json!([])).unwrap(), Vec::<Value>::new());
+        
+        let expr = parse_query(r#". | select(.type == "a")"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!({"type": "a"})).unwrap(), vec![json!({"type": "a"})]);
+        
+        let expr = parse_query("1 | select(. > 0)").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(1)]);
+    }
+    
+    #[test]
+    fn test_first_select_stops_early() {
+        let engine = QueryEngine::new();
+        
+        // Elements after the match are never looked at, so the string that .id would fail on is not reached
+        let expr = parse_query("first(.items[] | select(.id == 42))").unwrap();
+        let data = json!({"items": [{"id": 1}, {"id": 42, "name": "found"}, "not an object"]});
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"id": 42, "name": "found"})]);
+        
+        // An endless generator finishes as soon as a value is selected
+        let result = numbers(&engine, "first(repeat(. + 1) | select(. > 1000))", &json!(0));
+        assert_eq!(result, vec![1001.0]);
+    }
+    
+    #[test]
+    fn test_overflow_clamps_to_largest_float() {
+        let engine = QueryEngine::new();
+        let input = json!({"big": 1e308, "tiny": 1e-10});
+        
+        for (query, expected) in [(".big * 10", json!(f64::MAX)), (".big * -10", json!(f64::MIN)), (".big + .big", json!(f64::MAX)),
+                                  ("(.big * 10) | type", json!("number")), ("-(.big * 10)", json!(f64::MIN)), (".big / .tiny", json!(f64::MAX))] {
+            let expr = parse_query(query).unwrap();
+            assert_eq!(engine.execute(&expr, &input).unwrap(), vec![expected], "{}", query);
+        }
+    }
+    
+    #[test]
+    fn test_division_by_zero() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": 7, "f": 2.5, "zero": 0, "fzero": 0.0});
+        
+        for query in [".a / 0", ".a / .zero", ".f / .fzero", ".a % .zero", ".a % 0.5"] {
+            let expr = parse_query(query).unwrap();
+            let error = engine.execute(&expr, &data).unwrap_err().to_string();
+            assert!(error.contains("because the divisor is zero"), "{}: {}", query, error);
+        }
+        
+        let expr = parse_query(".a / .zero").unwrap();
+        let error = engine.execute(&expr, &data).unwrap_err().to_string();
+        assert!(error.contains("number (7) and number (0) cannot be divided"), "{}", error);
+        
+        assert_eq!(numbers(&engine, ".a / 2, .a % 2", &data), vec![3.5, 1.0]);
+    }
+    
+    #[test]
+    fn test_negative_zero_equals_zero() {
+        let engine = QueryEngine::new();
+        let data = json!([-0.0, 0, 0.0]);
+        
+        // Subtracting [0] removes every zero, negative or not
+        let expr = parse_query(".[0] == .[1], .[0] == .[2], .[0] < .[2], . - [0]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(true), json!(true), json!(false), json!([])]);
+        
+        assert_eq!(compare_values(&json!(-0.0), &json!(0.0)), std::cmp::Ordering::Equal);
+        assert_eq!(compare_values(&json!(-0.5), &json!(0.0)), std::cmp::Ordering::Less);
+    }
+    
+    #[test]
+    fn test_integer_arithmetic() {
+        let engine = QueryEngine::new();
+        
+        let cases = [
+            ("2 + 2", "4"),
+            ("7 - 10", "-3"),
+            ("6 * 7", "42"),
+            ("8 / 2", "4"),
+            ("7 / 2", "3.5"),
+            ("-7 % 3", "-1"),
+            ("-(3)", "-3"),
+            ("9223372036854775807 + 1", "9.223372036854776e+18"),
+        ];
+        for (query, expected) in cases {
+            let expr = parse_query(query).unwrap();
+            let result = engine.execute(&expr, &Value::Null).unwrap();
+            assert_eq!(serde_json::to_string(&result[0]).unwrap(), expected, "{}", query);
+        }
+        
+        // i64::MIN / -1 overflows, so it falls back to a float rather than panicking, and its remainder is 0
+        let min = json!(i64::MIN);
+        for (query, expected) in [(". / -1", "9.223372036854776e+18"), (". % -1", "0")] {
+            let expr = parse_query(query).unwrap();
+            let result = engine.execute(&expr, &min).unwrap();
+            assert_eq!(serde_json::to_string(&result[0]).unwrap(), expected, "{}", query);
+        }
+        
+        // A float operand gives a float result
+        for (query, expected) in [(". + 2", "4.0"), (". * 2", "4.0"), (". + 0.5", "2.5")] {
+            let expr = parse_query(query).unwrap();
+            let result = engine.execute(&expr, &json!(2.0)).unwrap();
+            assert_eq!(serde_json::to_string(&result[0]).unwrap(), expected, "{}", query);
+        }
+    }
+    
+    #[test]
+    fn test_assignment() {
         let engine = QueryEngine::new();
-        let data = json!({"name": "John", "age": 30});
-        let expr = Expression::Identity;
+        let data = json!({"a": 1, "b": {"c": [1, 2]}});
         
-        let result = engine.execute(&expr, &data).unwrap();
-        assert_eq!(result, vec![data]);
+        let expr = parse_query(".b.c[1] = 5").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": 1, "b": {"c": [1, 5]}})]);
+        
+        // The value is evaluated on the input, and missing paths are created
+        let expr = parse_query(".x.y = .a").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": 1, "b": {"c": [1, 2]}, "x": {"y": 1}})]);
+        
+        let expr = parse_query(".list[2] = true").unwrap();
+        assert_eq!(engine.execute(&expr, &json!({})).unwrap(), vec![json!({"list": [null, null, true]})]);
+        
+        // Each output of the value gives a separate result
+        let expr = parse_query(".a = (1, 2) | .a").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(1), json!(2)]);
+        
+        let expr = parse_query("length = 1").unwrap();
+        assert!(matches!(engine.execute(&expr, &data), Err(QueryError::Path(_))));
     }
     
     #[test]
-    fn test_property_access() {
+    fn test_reduce() {
         let engine = QueryEngine::new();
-        let data = json!({"name": "John", "age": 30});
-        let expr = Expression::Property("name".to_string());
         
-        let result = engine.execute(&expr, &data).unwrap();
-        assert_eq!(result, vec![json!("John")]);
+        let expr = parse_query("reduce .[] as $n (0; . + $n)").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([1, 2, 3, 4])).unwrap(), vec![json!(10)]);
+        assert_eq!(engine.execute(&expr, &json!([])).unwrap(), vec![json!(0)]);
+        
+        // Patterns destructure each item, and the source and init both run on the input
+        let expr = parse_query("reduce .pairs[] as [$k, $v] (.base; setpath([$k]; $v))").unwrap();
+        let data = json!({"base": {"a": 0}, "pairs": [["b", 1], ["c", 2]]});
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": 0, "b": 1, "c": 2})]);
+        
+        // The last output of the update is kept
+        let expr = parse_query("reduce range(0; 3) as $i (0; ., . + 10)").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(30)]);
     }
     
     #[test]
-    fn test_array_index() {
+    fn test_foreach() {
         let engine = QueryEngine::new();
-        let data = json!([1, 2, 3, 4, 5]);
-        let expr = Expression::Index(2);
         
-        let result = engine.execute(&expr, &data).unwrap();
-        assert_eq!(result, vec![json!(3)]);
+        let expr = parse_query("[foreach .[] as $n (0; . + $n)]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([1, 2, 3])).unwrap(), vec![json!([1, 3, 6])]);
+        
+        let expr = parse_query("[foreach .[] as $n (0; . + $n; [$n, .])]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([1, 2])).unwrap(), vec![json!([[1, 1], [2, 3]])]);
+        
+        // Results stream out, so the source needn't end
+        let expr = parse_query("first(foreach range(1; 1000000000000) as $n (0; . + $n; select(. > 10)))").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(15)]);
     }
     
     #[test]
-    fn test_array_slice() {
+    fn test_input_stream_is_read_lazily() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = produced.clone();
+        let mut engine = QueryEngine::new();
+        engine.set_input_stream((1..=1000).map(move |n| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(json!(n))
+        }));
+        
+        // Only the inputs the query reads are taken from the stream
+        let expr = parse_query("[limit(3; inputs)]").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!([1, 2, 3])]);
+        assert_eq!(produced.load(Ordering::SeqCst), 3);
+        
+        let expr = parse_query("reduce inputs as $n (0; . + $n)").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(500494)]);
+        
+        // Errors reading an input surface from input and inputs
+        engine.set_input_stream(vec![Ok(json!(1)), Err(QueryError::Unsupported("test".to_string()))].into_iter());
+        let expr = parse_query("[inputs]").unwrap();
+        assert!(matches!(engine.execute(&expr, &Value::Null), Err(QueryError::Unsupported(_))));
+    }
+    
+    #[test]
+    fn test_update_assignment() {
         let engine = QueryEngine::new();
-        let data = json!([1, 2, 3, 4, 5]);
-        let expr = Expression::Slice(Some(1), Some(4));
+        let data = json!({"a": 1, "b": {"c": [1, 2]}});
         
-        let result = engine.execute(&expr, &data).unwrap();
-        assert_eq!(result, vec![json!([2, 3, 4])]);
+        // The update runs on the value at the path, not on the input
+        let expr = parse_query(".a |= . + 1").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": 2, "b": {"c": [1, 2]}})]);
+        
+        let expr = parse_query(".b.c[] |= . * 10").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": 1, "b": {"c": [10, 20]}})]);
+        
+        // Only the first output is used, and no output deletes the path
+        let expr = parse_query(".a |= (5, 6)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": 5, "b": {"c": [1, 2]}})]);
+        let expr = parse_query(".b.c[] |= select(. > 1)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": 1, "b": {"c": [2]}})]);
     }
     
     #[test]
-    fn test_pipe() {
+    fn test_entries() {
         let engine = QueryEngine::new();
-        let data = json!({"user": {"name": "John", "age": 30}});
         
-        let expr = Expression::Pipe(
-            Box::new(Expression::Property("user".to_string())),
-            Box::new(Expression::Property("name".to_string()))
-        );
+        let expr = parse_query("to_entries").unwrap();
+        assert_eq!(engine.execute(&expr, &json!({"a": 1, "b": [2]})).unwrap(), vec![json!([{"key": "a", "value": 1}, {"key": "b", "value": [2]}])]);
         
-        let result = engine.execute(&expr, &data).unwrap();
-        assert_eq!(result, vec![json!("John")]);
+        // Other names for the key and value are accepted, and keys that aren't strings are written as JSON
+        let expr = parse_query(r#"[{"name": "x", "v": 1}, {"key": 2, "value": true}, {"k": "y"}] | from_entries"#).unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!({"x": 1, "2": true, "y": null})]);
+        
+        let expr = parse_query("with_entries(select(.value > 1))").unwrap();
+        assert_eq!(engine.execute(&expr, &json!({"a": 1, "b": 2, "c": 3})).unwrap(), vec![json!({"b": 2, "c": 3})]);
+        
+        let expr = parse_query("from_entries").unwrap();
+        assert!(matches!(engine.execute(&expr, &json!([1])), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_tonumber_and_tostring() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query(r#"["5", "-2.5", "1e3", 7] | map(tonumber)"#).unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!([5, -2.5, 1000.0, 7])]);
+        
+        for input in [json!("five"), json!("5 apples"), json!(null)] {
+            let expr = parse_query("tonumber").unwrap();
+            assert!(matches!(engine.execute(&expr, &input), Err(QueryError::Type(_))), "{}", input);
+        }
+        
+        let expr = parse_query(r#"[1, "a", [true], null] | map(tostring)"#).unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(["1", "a", "[true]", "null"])]);
+    }
+    
+    #[test]
+    fn test_ascii_case() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("ascii_downcase, ascii_upcase").unwrap();
+        assert_eq!(engine.execute(&expr, &json!("MiXed Çase 1")).unwrap(), vec![json!("mixed Çase 1"), json!("MIXED ÇASE 1")]);
+        
+        let expr = parse_query("ascii_downcase").unwrap();
+        assert!(matches!(engine.execute(&expr, &json!(1)), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_multi_path_assignment() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": 1, "b": 2, "c": 3});
+        
+        let expr = parse_query("(.a, .b) = 0").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": 0, "b": 0, "c": 3})]);
+        
+        let expr = parse_query(".[] = null").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": null, "b": null, "c": null})]);
+        
+        let expr = parse_query("(.items[] | select(.done)).done = \"yes\"").unwrap();
+        let data = json!({"items": [{"done": true}, {"done": false}, {"done": true}]});
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"items": [{"done": "yes"}, {"done": false}, {"done": "yes"}]})]);
+    }
+    
+    #[test]
+    fn test_recurse_is_lazy() {
+        let mut engine = QueryEngine::new();
+        engine.set_max_depth(2);
+        let data = json!({"a": {"b": {"c": {"d": 1}}}});
+        
+        // Walking everything goes past the depth limit, but the first values are found before it
+        let expr = parse_query("[..]").unwrap();
+        assert!(matches!(engine.execute(&expr, &data), Err(QueryError::DepthLimit(2))));
+        
+        let expr = parse_query("first(..)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![data.clone()]);
+        
+        let expr = parse_query("[limit(2; recurse)] | length").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(2)]);
+    }
+    
+    #[test]
+    fn test_recurse_with_filter() {
+        let engine = QueryEngine::new();
+        let data = json!({"name": "root", "children": [{"name": "a", "children": [{"name": "b"}]}, {"name": "c"}]});
+        
+        let expr = parse_query("[recurse(.children // [] | .[]) | .name]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(["root", "a", "b", "c"])]);
+    }
+    
+    #[test]
+    fn test_engine_shared_across_threads() {
+        let mut engine = QueryEngine::default();
+        engine.set_variable("offset", json!(100));
+        let expr = parse_query(".n + $offset").unwrap();
+        
+        let results: Vec<f64> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|n| {
+                    let (engine, expr) = (&engine, &expr);
+                    scope.spawn(move || engine.execute(expr, &json!({"n": n})).unwrap()[0].as_f64().unwrap())
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        
+        assert_eq!(results, vec![100.0, 101.0, 102.0, 103.0]);
+    }
+    
+    #[test]
+    fn test_unsupported_path_expressions() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": [1, 2, 3]});
+        
+        for query in [".a[1:2] = [0]", "def f: .a; f = 1", "first(.a[]) = 0", "(. as $x | .a) = 1"] {
+            let expr = parse_query(query).unwrap();
+            let error = engine.execute(&expr, &data).unwrap_err();
+            assert!(matches!(error, QueryError::Unsupported(_)), "{}: {:?}", query, error);
+            assert!(error.to_string().ends_with("is not supported yet"), "{}", error);
+        }
+        
+        // Expressions that can never be paths are still path errors
+        let expr = parse_query("(.a | length) = 1").unwrap();
+        assert!(matches!(engine.execute(&expr, &data), Err(QueryError::Path(_))));
+    }
+    
+    #[test]
+    fn test_abs() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("abs").unwrap();
+        
+        let result = engine.execute(&expr, &json!(-5)).unwrap();
+        assert_eq!(result, vec![json!(5)]);
+        assert!(result[0].is_i64());
+        
+        assert_eq!(engine.execute(&expr, &json!(-2.5)).unwrap(), vec![json!(2.5)]);
+        assert_eq!(engine.execute(&expr, &json!(7)).unwrap(), vec![json!(7)]);
+        assert_eq!(engine.execute(&expr, &json!(1.25)).unwrap(), vec![json!(1.25)]);
+        
+        let error = engine.execute(&expr, &json!("-1")).unwrap_err();
+        assert!(matches!(error, QueryError::Type(_)));
+        assert!(error.to_string().contains("has no absolute value"), "{}", error);
+    }
+    
+    #[test]
+    fn test_getpath() {
+        let engine = QueryEngine::new();
+        let data = json!({"users": [{"name": "Ada"}, {"name": "Grace"}]});
+        
+        let expr = parse_query(r#"getpath(["users", 0, "name"]), getpath(["users", -1, "name"])"#).unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("Ada"), json!("Grace")]);
+        
+        // Misses are null, whether the key, the index or anything after them is missing
+        let expr = parse_query(r#"[getpath(["users", 5, "name"]), getpath(["groups", 0]), getpath(["users", 0, "email"]), getpath([])]"#).unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([null, null, null, data.clone()])]);
+        
+        // Indexing a value of the wrong type is an error
+        let expr = parse_query(r#"getpath(["users", "name"])"#).unwrap();
+        assert!(matches!(engine.execute(&expr, &data), Err(QueryError::Type(_))));
+        let expr = parse_query(r#"getpath("users")"#).unwrap();
+        assert!(matches!(engine.execute(&expr, &data), Err(QueryError::Path(_))));
+        
+        // getpath is also a path expression
+        let expr = parse_query(r#"getpath(["users", 1, "name"]) = "Hopper""#).unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap()[0]["users"][1]["name"], json!("Hopper"));
+    }
+    
+    #[test]
+    fn test_get_path_lenient() {
+        let data = json!({"a": [1, {"b": true}]});
+        assert_eq!(get_path(&data, &[json!("a"), json!(1), json!("b")], false).unwrap(), json!(true));
+        assert_eq!(get_path(&data, &[json!("a"), json!("b")], false).unwrap(), Value::Null);
+        assert!(get_path(&data, &[json!("a"), json!("b")], true).is_err());
+    }
+    
+    #[test]
+    fn test_string_length_counts_code_points() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("length").unwrap();
+        
+        assert_eq!(engine.execute(&expr, &json!("héllo")).unwrap(), vec![json!(5)]);
+        // An astral character such as an emoji is one code point, however many bytes or UTF-16 units it takes
+        assert_eq!(engine.execute(&expr, &json!("\u{1F600}")).unwrap(), vec![json!(1)]);
+        // A combining accent is a code point of its own, so "e" followed by U+0301 counts as two
+        assert_eq!(engine.execute(&expr, &json!("e\u{301}")).unwrap(), vec![json!(2)]);
+        
+        let expr = parse_query(r#""\ud83d\ude00" | length"#).unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(1)]);
+    }
+    
+    #[test]
+    fn test_utf8bytelength() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query("utf8bytelength, length").unwrap();
+        assert_eq!(engine.execute(&expr, &json!("héllo")).unwrap(), vec![json!(6), json!(5)]);
+        assert_eq!(engine.execute(&expr, &json!("\u{1F600}")).unwrap(), vec![json!(4), json!(1)]);
+        
+        let expr = parse_query("utf8bytelength").unwrap();
+        assert!(matches!(engine.execute(&expr, &json!([1])), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_del_nested_key() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": {"b": 1, "c": 2}, "d": 3});
+        
+        let expr = parse_query("del(.a.b)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": {"c": 2}, "d": 3})]);
+        
+        // Several paths at once, and missing keys are left alone
+        let expr = parse_query("del(.a.c, .d, .x.y)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"a": {"b": 1}})]);
+        
+        let expr = parse_query("del(.)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![Value::Null]);
+        
+        let expr = parse_query("del(.d.e)").unwrap();
+        assert!(matches!(engine.execute(&expr, &data), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_del_from_each_element() {
+        let engine = QueryEngine::new();
+        let data = json!({"items": [{"id": 1, "tmp": "x"}, {"id": 2}, {"id": 3, "tmp": "z"}]});
+        
+        let expr = parse_query("del(.items[].tmp)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"items": [{"id": 1}, {"id": 2}, {"id": 3}]})]);
+        
+        // Removing elements keeps the indices of the others in place, whatever order the paths come in
+        let expr = parse_query("del(.[0], .[2], .[-1])").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([0, 1, 2, 3, 4])).unwrap(), vec![json!([1, 3])]);
+        
+        let expr = parse_query("del(.[] | select(. > 2))").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([3, 1, 4, 1, 5])).unwrap(), vec![json!([1, 1])]);
+    }
+    
+    #[test]
+    fn test_setpath_creates_intermediates() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query(r#"setpath(["a", "b"]; 1)"#).unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!({"a": {"b": 1}})]);
+        assert_eq!(engine.execute(&expr, &json!({})).unwrap(), vec![json!({"a": {"b": 1}})]);
+        
+        // An index past the end extends the array with nulls
+        let expr = parse_query(r#"setpath([3]; "x")"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!([1])).unwrap(), vec![json!([1, null, null, "x"])]);
+        let expr = parse_query(r#"setpath(["list", 2, "id"]; .n)"#).unwrap();
+        let data = json!({"n": 7, "list": [0]});
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"n": 7, "list": [0, null, {"id": 7}]})]);
+        
+        // getpath on null is null however deep the path goes
+        let expr = parse_query(r#"getpath(["a", 0, "b"])"#).unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![Value::Null]);
+        
+        let expr = parse_query(r#"setpath(["a", "b"]; 1)"#).unwrap();
+        assert!(matches!(engine.execute(&expr, &json!({"a": [1]})), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_sort_by_multiple_keys() {
+        let engine = QueryEngine::new();
+        let data = json!([
+            {"name": "c", "age": 30},
+            {"name": "a", "age": 40},
+            {"name": "b", "age": 30},
+            {"name": "a", "age": 20},
+        ]);
+        
+        let expr = parse_query("sort_by(.age) | map(.name)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(["a", "c", "b", "a"])]);
+        
+        // Ties on the first key are broken by the second
+        let expr = parse_query("sort_by(.age, .name) | map(.name)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(["a", "b", "c", "a"])]);
+        let expr = parse_query("sort_by(.name, -.age) | map(.age)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([40, 20, 30, 30])]);
+        
+        // Keys can be any expression, such as a comparison (false sorts before true)
+        let expr = parse_query("sort_by(.age >= 30) | map(.name)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(["a", "c", "a", "b"])]);
+        
+        let expr = parse_query("sort_by(.a)").unwrap();
+        assert!(matches!(engine.execute(&expr, &json!({"a": 1})), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_min_by_max_by() {
+        let engine = QueryEngine::new();
+        let data = json!([
+            {"name": "a", "x": 2},
+            {"name": "b", "x": 1},
+            {"name": "c", "x": 3},
+            {"name": "d", "x": 1},
+            {"name": "e", "x": 3},
+        ]);
+        
+        // The whole element is returned, the first of equal minimums and the last of equal maximums
+        let expr = parse_query("min_by(.x), max_by(.x)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"name": "b", "x": 1}), json!({"name": "e", "x": 3})]);
+        
+        let expr = parse_query("max_by(.x, .name) | .name").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!("e")]);
+        
+        let expr = parse_query("[min_by(.x), max_by(.x)]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([])).unwrap(), vec![json!([null, null])]);
+        
+        let expr = parse_query("max_by(.x)").unwrap();
+        assert!(matches!(engine.execute(&expr, &json!("text")), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_large_map_keeps_order() {
+        let engine = QueryEngine::new();
+        let data = Value::Array((0..PARALLEL_MAP_THRESHOLD as i64 * 2).map(Value::from).collect());
+        
+        let expr = parse_query("map(. * 2, empty) | .[0], .[-1], length").unwrap();
+        let last = PARALLEL_MAP_THRESHOLD as i64 * 4 - 2;
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(0), json!(last), json!(PARALLEL_MAP_THRESHOLD * 2)]);
+    }
+    
+    #[test]
+    fn test_large_map_reports_first_error() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("map(. + 1)").unwrap();
+        
+        // Whichever element fails first in time, the error is always that of the lowest failing index
+        let mut items: Vec<Value> = (0..PARALLEL_MAP_THRESHOLD * 4).map(Value::from).collect();
+        items[PARALLEL_MAP_THRESHOLD * 3] = json!("third");
+        items[PARALLEL_MAP_THRESHOLD + 7] = json!("first");
+        items[PARALLEL_MAP_THRESHOLD * 2] = json!("second");
+        let data = Value::Array(items);
+        
+        for _ in 0..20 {
+            let error = engine.execute(&expr, &data).unwrap_err();
+            assert!(error.to_string().contains("\"first\""), "{}", error);
+        }
+    }
+    
+    #[test]
+    fn test_mixed_type_comparisons() {
+        let engine = QueryEngine::new();
+        
+        // Values of different types compare by jq's type order: null, false, true, numbers, strings,
+        // arrays, objects
+        for (query, expected) in [
+            (r#"1 < "a""#, true),
+            (r#""a" < 1"#, false),
+            (r#"1 > "a""#, false),
+            (r#""a" >= 1"#, true),
+            ("null < false", true),
+            ("true < 0", true),
+            (r#""z" < []"#, true),
+            ("[] < {}", true),
+        ] {
+            let expr = parse_query(query).unwrap();
+            assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(expected)], "{}", query);
+        }
+        
+        // select keeps mixed values on the right side of the order instead of silently dropping them
+        let expr = parse_query(r#"[.[] | select(. < "b")]"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!([1, "a", "c", null, [0]])).unwrap(), vec![json!([1, "a", null])]);
+    }
+    
+    #[test]
+    fn test_recurse_depth() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": {"b": {"c": {"d": 1}}}, "x": [1, [2]]});
+        
+        // Two levels: the input, its children and their children, but nothing deeper
+        let expr = parse_query("[recurse_depth(2)] | length").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(6)]);
+        let expr = parse_query("[recurse_depth(2)]").unwrap();
+        let expected = json!([data.clone(), {"b": {"c": {"d": 1}}}, {"c": {"d": 1}}, [1, [2]], 1, [2]]);
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![expected]);
+        
+        let expr = parse_query("[recurse_depth(0)], [recurse_depth(100)] == [..]").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!([data.clone()]), json!(true)]);
+        
+        let expr = parse_query("recurse_depth(-1)").unwrap();
+        assert!(matches!(engine.execute(&expr, &data), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_tostream() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("[tostream]").unwrap();
+        
+        let events = engine.execute(&expr, &json!({"a": [1, {"b": null}], "c": {}})).unwrap();
+        assert_eq!(events, vec![json!([
+            [["a", 0], 1],
+            [["a", 1, "b"], null],
+            [["a", 1, "b"]],
+            [["a", 1]],
+            [["c"], {}],
+            [["c"]],
+        ])]);
+        
+        assert_eq!(engine.execute(&expr, &json!(3)).unwrap(), vec![json!([[[], 3]])]);
+        assert_eq!(engine.execute(&expr, &json!([])).unwrap(), vec![json!([[[], []]])]);
+    }
+    
+    #[test]
+    fn test_fromstream_round_trip() {
+        let engine = QueryEngine::new();
+        let data = json!({"users": [{"name": "Ada", "tags": ["x", "y"]}, {"name": "Grace", "tags": []}], "n": 2.5, "empty": {}});
+        
+        let expr = parse_query("fromstream(tostream)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![data.clone()]);
+        
+        // Each top-level value is produced as soon as it is complete
+        let expr = parse_query("[fromstream(1, [1, {\"a\": 2}], \"s\" | tostream)]").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!([1, [1, {"a": 2}], "s"])]);
+        
+        // Events can be transformed on the way through
+        let expr = parse_query("fromstream(tostream | if length == 2 and (.[1] | . == \"x\") then [.[0], \"z\"] else . end)").unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap()[0]["users"][0]["tags"], json!(["z", "y"]));
+        
+        let expr = parse_query("fromstream(1)").unwrap();
+        assert!(matches!(engine.execute(&expr, &Value::Null), Err(QueryError::Type(_))));
+    }
+    
+    #[test]
+    fn test_regex_case_insensitive() {
+        let engine = QueryEngine::new();
+        
+        let expr = parse_query(r#"test("HELLO"), test("HELLO"; "i"), test("h.llo"; null)"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!("hello")).unwrap(), vec![json!(false), json!(true), json!(true)]);
+        
+        let expr = parse_query(r#"[.[] | select(test("^ada"; "i"))]"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!(["Ada", "ADA L", "Grace"])).unwrap(), vec![json!(["Ada", "ADA L"])]);
+        
+        let expr = parse_query(r#"[match("L+"; "gi") | .string]"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!("Hello, world")).unwrap(), vec![json!(["ll", "l"])]);
+    }
+    
+    #[test]
+    fn test_match_objects() {
+        let engine = QueryEngine::new();
+        
+        // Offsets are counted in characters, and a group that did not take part has an offset of -1
+        let expr = parse_query(r#"match("(?<word>[a-z]+)(\\d)?")"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!("é abc")).unwrap(), vec![json!({
+            "offset": 2, "length": 3, "string": "abc",
+            "captures": [
+                {"offset": 2, "length": 3, "string": "abc", "name": "word"},
+                {"offset": -1, "length": 0, "string": null, "name": null},
+            ],
+        })]);
+        
+        let expr = parse_query(r#"[match("x*"; "g") | .offset], [match("x*"; "gn") | .offset]"#).unwrap();
+        assert_eq!(engine.execute(&expr, &json!("axx")).unwrap(), vec![json!([0, 1]), json!([1])]);
+        
+        for query in [r#"test("(")"#, r#"test("a"; "q")"#, r#"1 | test("a")"#] {
+            let expr = parse_query(query).unwrap();
+            assert!(matches!(engine.execute(&expr, &json!("a")), Err(QueryError::Type(_))), "{}", query);
+        }
+    }
+    
+    #[test]
+    fn test_quoted_key_with_dot() {
+        let engine = QueryEngine::new();
+        let data = json!({"a.b": {"c": 1}, "a": {"b": 2}, "x": {"a.b": 3}});
+        
+        // A quoted key is one whole key, even with a dot in it
+        assert!(matches!(parse_query(r#"."a.b""#).unwrap(), Expression::Property(ref key) if key == "a.b"));
+        for (query, expected) in [(r#"."a.b""#, json!({"c": 1})), (r#"."a.b".c"#, json!(1)), (r#".x."a.b""#, json!(3)), (r#".["a.b"].c"#, json!(1))] {
+            let expr = parse_query(query).unwrap();
+            assert_eq!(engine.execute(&expr, &data).unwrap(), vec![expected], "{}", query);
+        }
+        
+        let expr = parse_query(r#"."a.b" = 0 | ."a.b", .a.b"#).unwrap();
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!(0), json!(2)]);
+    }
+    
+    #[test]
+    fn test_builtins() {
+        let engine = QueryEngine::new();
+        let expr = parse_query("builtins").unwrap();
+        let result = engine.execute(&expr, &Value::Null).unwrap();
+        
+        let names = result[0].as_array().unwrap();
+        assert!(!names.is_empty());
+        for name in ["keys/0", "map/1", "range/2", "builtins/0"] {
+            assert!(names.contains(&json!(name)), "{}", name);
+        }
+        
+        // Every listed builtin can be called
+        for (name, arity, _) in BUILTINS {
+            let args = vec!["."; *arity].join("; ");
+            let query = if *arity == 0 { name.to_string() } else { format!("{}({})", name, args) };
+            assert!(parse_query(&query).is_ok(), "{}", query);
+        }
+    }
+    
+    #[test]
+    fn test_object_construction_empty_and_product() {
+        let engine = QueryEngine::new();
+        
+        // A value with no outputs drops the whole object rather than leaving the key out or setting it to null
+        let expr = parse_query("[{a: 1, b: empty}], [{a: .missing}]").unwrap();
+        assert_eq!(engine.execute(&expr, &json!({})).unwrap(), vec![json!([]), json!([{"a": null}])]);
+        
+        // A value with several outputs makes one object for each
+        let expr = parse_query("{name, tag: .tags[]}").unwrap();
+        let data = json!({"name": "rjx", "tags": ["json", "cli"]});
+        assert_eq!(engine.execute(&expr, &data).unwrap(), vec![json!({"name": "rjx", "tag": "json"}), json!({"name": "rjx", "tag": "cli"})]);
+    }
+    
+    #[test]
+    fn test_object_construction_cartesian_order() {
+        let engine = QueryEngine::new();
+        
+        // Like jq, the last key's values vary fastest
+        let expr = parse_query("{a: (1, 2), b: (3, 4)}").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![
+            json!({"a": 1, "b": 3}),
+            json!({"a": 1, "b": 4}),
+            json!({"a": 2, "b": 3}),
+            json!({"a": 2, "b": 4}),
+        ]);
+        
+        let expr = parse_query("[{x: .[], y: (.[] | . * 10)}] | length").unwrap();
+        assert_eq!(engine.execute(&expr, &json!([1, 2, 3])).unwrap(), vec![json!(9)]);
+    }
+    
+    #[test]
+    fn test_array_construction_interleaves_outputs() {
+        let engine = QueryEngine::new();
+        let data = json!({"a": [{"b": 1, "c": 2}, {"b": 3, "c": 4}], "d": 5});
+        
+        // The comma binds tighter than the pipe, so both fields of each element come before the next element
+        for (query, expected) in [
+            ("[.a[] | .b, .c]", json!([1, 2, 3, 4])),
+            ("[.a[] | (.b, .c) | (., . * 10)]", json!([1, 10, 2, 20, 3, 30, 4, 40])),
+            ("[(.a[] | .b), .d]", json!([1, 3, 5])),
+            ("[.a[] | [.c, .b]]", json!([[2, 1], [4, 3]])),
+        ] {
+            let expr = parse_query(query).unwrap();
+            assert_eq!(engine.execute(&expr, &data).unwrap(), vec![expected], "{}", query);
+        }
     }
 }
@@ -0,0 +1,76 @@
+//! Peak memory checks. These install a counting global allocator, so they live in their own test target
+//! without the standard harness: nothing else runs in the process to perturb the measurements.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rjx::parser::parse_query;
+use rjx::query::{QueryEngine, QueryError};
+use serde_json::{json, Value};
+
+/// Counts the bytes currently allocated and the most allocated at once
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+    
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The most memory allocated at any point while running `f`, beyond what was already
+fn peak_allocation(f: impl FnOnce()) -> usize {
+    let start = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(start, Ordering::Relaxed);
+    f();
+    PEAK.load(Ordering::Relaxed) - start
+}
+
+/// Folding a stream of documents read lazily with `inputs` holds one at a time, where slurping them
+/// holds them all
+fn lazy_input_sum_uses_less_memory_than_slurp() {
+    let input: String = (1..=100_000).map(|n| format!("{}\n", n)).collect();
+    let bytes = input.clone().into_bytes();
+    
+    let slurp_peak = peak_allocation(|| {
+        let documents = serde_json::Deserializer::from_str(&input)
+            .into_iter::<Value>()
+            .collect::<Result<Vec<Value>, _>>()
+            .unwrap();
+        let expr = parse_query("reduce .[] as $n (0; . + $n)").unwrap();
+        let result = QueryEngine::new().execute(&expr, &Value::Array(documents)).unwrap();
+        assert_eq!(result, vec![json!(5_000_050_000i64)]);
+    });
+    
+    let lazy_peak = peak_allocation(|| {
+        let mut engine = QueryEngine::new();
+        engine.set_input_stream(serde_json::Deserializer::from_reader(io::Cursor::new(bytes))
+            .into_iter::<Value>()
+            .map(|document| document.map_err(QueryError::from)));
+        let expr = parse_query("reduce inputs as $n (0; . + $n)").unwrap();
+        assert_eq!(engine.execute(&expr, &Value::Null).unwrap(), vec![json!(5_000_050_000i64)]);
+    });
+    
+    assert!(lazy_peak * 10 < slurp_peak, "lazy peak {} bytes, slurp peak {} bytes", lazy_peak, slurp_peak);
+}
+
+fn main() {
+    lazy_input_sum_uses_less_memory_than_slurp();
+    println!("test lazy_input_sum_uses_less_memory_than_slurp ... ok");
+}